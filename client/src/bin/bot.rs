@@ -0,0 +1,577 @@
+// Headless clients for stress testing. Each bot speaks the real protocol over its
+// own socket, queues for a match, plays cards with a simple strategy and queues
+// again once the match is over. Bandwidth and snapshot timing are reported every
+// few seconds and in full at the end.
+//
+// bot [server] [--bots N] [--seconds S] [--strategy random|rush|saver|mixed]
+//     [--rating N] [--server-pid PID] [--conditions SPEC]
+
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use client::clock::ClockSync;
+use client::network::Connection;
+use client::render::{VIRTUAL_HEIGHT, VIRTUAL_WIDTH};
+use shared::delta::Baselines;
+use shared::net::{Conditions, ConnectionState, DisconnectReason, EndpointStats};
+use shared::protocol::{ClientMessage, SERVER_PORT, ServerMessage};
+use shared::rng::Rng;
+use shared::tick::{TICK_DT, TICK_RATE};
+use shared::{Card, GameState, MAX_ELIXIR, cards};
+
+const BASELINE_CAPACITY: usize = 64;
+// How often sockets are drained. Much finer than a tick so snapshot arrival
+// times are worth measuring.
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+const REPORT_INTERVAL: f64 = 5.0;
+// Random bots play a card about this often when they can afford one
+const RANDOM_PLAYS_PER_SECOND: f64 = 0.5;
+// Wait before queueing again after a match, so the server sees churn but not a
+// reconnect storm
+const REQUEUE_DELAY: f64 = 1.0;
+// Linux reports process CPU time in these, fixed at 100 on every common setup
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+// Snapshot intervals are counted in millisecond buckets up to this, the last
+// bucket takes anything slower
+const GAP_BUCKETS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Strategy {
+    // Any affordable card, now and then
+    Random,
+    // The cheapest card as soon as it is affordable
+    Rush,
+    // Sits on full elixir, then drops the most expensive card
+    Saver,
+}
+
+impl Strategy {
+    const ALL: [Strategy; 3] = [Strategy::Random, Strategy::Rush, Strategy::Saver];
+
+    // The card to play now, if any
+    fn pick(self, hand: &[Card], elixir: u32, rng: &mut Rng) -> Option<u32> {
+        let affordable = hand.iter().filter(|c| c.cost <= elixir);
+        match self {
+            Strategy::Random => {
+                let chance = RANDOM_PLAYS_PER_SECOND / TICK_RATE as f64;
+                if (rng.next_f32() as f64) >= chance {
+                    return None;
+                }
+                let affordable: Vec<&Card> = affordable.collect();
+                let index = rng.range(0, affordable.len() as u32) as usize;
+                affordable.get(index).map(|c| c.id)
+            }
+            Strategy::Rush => affordable.min_by_key(|c| c.cost).map(|c| c.id),
+            Strategy::Saver if elixir >= MAX_ELIXIR => {
+                affordable.max_by_key(|c| c.cost).map(|c| c.id)
+            }
+            Strategy::Saver => None,
+        }
+    }
+}
+
+// Time between consecutive snapshots, in constant memory however long the run
+#[derive(Debug, Clone)]
+struct Gaps {
+    counts: Vec<u64>,
+    sum: f64,
+    sum_squares: f64,
+    max: f64,
+}
+
+impl Default for Gaps {
+    fn default() -> Self {
+        Gaps {
+            counts: vec![0; GAP_BUCKETS],
+            sum: 0.0,
+            sum_squares: 0.0,
+            max: 0.0,
+        }
+    }
+}
+
+impl Gaps {
+    fn push(&mut self, gap: f64) {
+        let bucket = ((gap * 1000.0) as usize).min(GAP_BUCKETS - 1);
+        self.counts[bucket] += 1;
+        self.sum += gap;
+        self.sum_squares += gap * gap;
+        self.max = self.max.max(gap);
+    }
+
+    fn merge(&mut self, other: &Gaps) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.sum += other.sum;
+        self.sum_squares += other.sum_squares;
+        self.max = self.max.max(other.max);
+    }
+
+    fn len(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    fn mean(&self) -> f64 {
+        self.sum / self.len().max(1) as f64
+    }
+
+    // Standard deviation
+    fn jitter(&self) -> f64 {
+        let mean = self.mean();
+        (self.sum_squares / self.len().max(1) as f64 - mean * mean)
+            .max(0.0)
+            .sqrt()
+    }
+
+    // Upper edge of the bucket holding the `p` quantile
+    fn percentile(&self, p: f64) -> f64 {
+        let rank = (self.len() as f64 * p).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank.max(1) {
+                return (bucket + 1) as f64 / 1000.0;
+            }
+        }
+        0.0
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    snapshots: u64,
+    decode_failures: u64,
+    // Ticks the server simulated that no snapshot arrived for
+    skipped_ticks: u64,
+    cards_played: u64,
+    matches: u64,
+    denied: u64,
+    timeouts: u64,
+    // Since the last report
+    gaps: Gaps,
+    // Totals of connections that were already closed
+    closed: EndpointStats,
+}
+
+struct Bot {
+    strategy: Strategy,
+    conditions: Conditions,
+    rating: Option<u32>,
+    rng: Rng,
+    connection: Option<Connection>,
+    // When to queue again while not connected
+    requeue_at: f64,
+    clock: ClockSync,
+    baselines: Baselines,
+    player_id: Option<u32>,
+    hand: Vec<Card>,
+    view: Option<GameState>,
+    next_sequence: u32,
+    // Last placement sent, no more until the server applied it so the elixir
+    // seen in snapshots is never spent twice
+    awaiting_ack: Option<u32>,
+    last_snapshot: Option<(u64, f64)>,
+    stats: Stats,
+}
+
+impl Bot {
+    fn new(strategy: Strategy, conditions: Conditions, rating: Option<u32>, seed: u64) -> Self {
+        Bot {
+            strategy,
+            conditions,
+            rating,
+            rng: Rng::new(seed),
+            connection: None,
+            requeue_at: 0.0,
+            clock: ClockSync::new(),
+            baselines: Baselines::new(BASELINE_CAPACITY),
+            player_id: None,
+            hand: Vec::new(),
+            view: None,
+            next_sequence: 1,
+            awaiting_ack: None,
+            last_snapshot: None,
+            stats: Stats::default(),
+        }
+    }
+
+    fn queue(&mut self, server: SocketAddr) -> io::Result<()> {
+        let lobby = ClientMessage::FindMatch {
+            rating: self.rating,
+        };
+        let connection = Connection::connect(server, &lobby)?;
+        self.connection = Some(connection.with_conditions(self.conditions));
+        self.clock = ClockSync::new();
+        self.baselines = Baselines::new(BASELINE_CAPACITY);
+        self.player_id = None;
+        self.view = None;
+        self.next_sequence = 1;
+        self.awaiting_ack = None;
+        self.last_snapshot = None;
+        Ok(())
+    }
+
+    fn close(&mut self, now: f64) {
+        if let Some(connection) = self.connection.take() {
+            self.stats.closed = add_stats(self.stats.closed, connection.stats());
+        }
+        self.requeue_at = now + REQUEUE_DELAY;
+    }
+
+    fn stats(&self) -> EndpointStats {
+        let open = self.connection.as_ref().map(|c| c.stats());
+        add_stats(self.stats.closed, open.unwrap_or_default())
+    }
+
+    // Drains the socket and handles everything that arrived
+    fn poll(&mut self, now: f64) {
+        let Some(connection) = &mut self.connection else {
+            return;
+        };
+        for message in connection.poll() {
+            match message {
+                ServerMessage::Welcome {
+                    player_id, hand, ..
+                } => {
+                    let catalog = cards::catalog();
+                    self.hand = catalog
+                        .into_iter()
+                        .filter(|c| hand.contains(&c.id))
+                        .collect();
+                    self.player_id = Some(player_id);
+                    // Pongs from the lobby carried tick 0
+                    self.clock = ClockSync::new();
+                    self.stats.matches += 1;
+                }
+                ServerMessage::Snapshot {
+                    tick,
+                    checksum,
+                    ack,
+                    payload,
+                    ..
+                } => {
+                    let Some(state) = self.baselines.decode(payload, checksum) else {
+                        self.stats.decode_failures += 1;
+                        continue;
+                    };
+                    connection.send(&ClientMessage::AckSnapshot { tick });
+                    self.stats.snapshots += 1;
+                    if let Some((last_tick, last_time)) = self.last_snapshot
+                        && tick > last_tick
+                    {
+                        self.stats.gaps.push(now - last_time);
+                        self.stats.skipped_ticks += tick - last_tick - 1;
+                    }
+                    self.last_snapshot = Some((tick, now));
+                    if self.awaiting_ack.is_some_and(|sequence| ack >= sequence) {
+                        self.awaiting_ack = None;
+                    }
+                    self.view = Some(state);
+                }
+                ServerMessage::Pong {
+                    client_time,
+                    server_time,
+                    server_tick,
+                } => self.clock.pong(client_time, server_time, server_tick, now),
+                ServerMessage::PlacementRejected { sequence } => {
+                    if self.awaiting_ack == Some(sequence) {
+                        self.awaiting_ack = None;
+                    }
+                }
+                ServerMessage::Queued
+                | ServerMessage::RoomCreated { .. }
+                | ServerMessage::RoomUnavailable
+                | ServerMessage::RejoinFailed
+                | ServerMessage::InputHealth { .. } => {}
+            }
+        }
+
+        if connection.is_connected()
+            && let Some(ping) = self.clock.ping(now)
+        {
+            connection.send(&ping);
+            let _ = connection.flush();
+        }
+    }
+
+    // Once per tick: plays a card if the strategy wants to and flushes
+    fn tick(&mut self, server: SocketAddr, now: f64) {
+        let Some(connection) = &mut self.connection else {
+            if now >= self.requeue_at
+                && let Err(err) = self.queue(server)
+            {
+                eprintln!("Bot could not connect: {}", err);
+                self.requeue_at = now + REQUEUE_DELAY;
+            }
+            return;
+        };
+
+        match connection.state() {
+            ConnectionState::Disconnected(reason) => {
+                match reason {
+                    DisconnectReason::Denied(_) => self.stats.denied += 1,
+                    DisconnectReason::TimedOut => self.stats.timeouts += 1,
+                    _ => {}
+                }
+                self.close(now);
+                return;
+            }
+            _ if self.view.as_ref().is_some_and(|view| view.ended) => {
+                let _ = connection.disconnect();
+                self.close(now);
+                return;
+            }
+            _ => {}
+        }
+
+        if let (Some(player_id), Some(view), Some(target)) =
+            (self.player_id, &self.view, self.clock.target_tick(now))
+            && self.awaiting_ack.is_none()
+        {
+            let elixir = view
+                .players
+                .iter()
+                .find(|p| p.id == player_id)
+                .map_or(0, |p| p.elixir);
+            if let Some(card_id) = self.strategy.pick(&self.hand, elixir, &mut self.rng) {
+                // Own half of the arena, players take turns at the bottom
+                let (low, high) = if player_id % 2 == 1 {
+                    (VIRTUAL_HEIGHT * 0.55, VIRTUAL_HEIGHT * 0.8)
+                } else {
+                    (VIRTUAL_HEIGHT * 0.2, VIRTUAL_HEIGHT * 0.45)
+                };
+                let x = self.rng.range_f32(VIRTUAL_WIDTH * 0.1, VIRTUAL_WIDTH * 0.9);
+                let y = self.rng.range_f32(low, high);
+                connection.send(&ClientMessage::PlaceCard {
+                    sequence: self.next_sequence,
+                    tick: target as u64,
+                    card_id,
+                    x,
+                    y,
+                });
+                self.awaiting_ack = Some(self.next_sequence);
+                self.next_sequence += 1;
+                self.stats.cards_played += 1;
+            }
+        }
+        let _ = connection.flush();
+    }
+}
+
+fn add_stats(a: EndpointStats, b: EndpointStats) -> EndpointStats {
+    EndpointStats {
+        rtt: a.rtt.max(b.rtt),
+        packets_sent: a.packets_sent + b.packets_sent,
+        packets_received: a.packets_received + b.packets_received,
+        packets_acked: a.packets_acked + b.packets_acked,
+        packets_lost: a.packets_lost + b.packets_lost,
+        bytes_sent: a.bytes_sent + b.bytes_sent,
+        bytes_received: a.bytes_received + b.bytes_received,
+        messages_resent: a.messages_resent + b.messages_resent,
+        fragment_groups_expired: a.fragment_groups_expired + b.fragment_groups_expired,
+        messages_superseded: a.messages_superseded + b.messages_superseded,
+    }
+}
+
+// User plus system CPU seconds the process has used, Linux only
+fn cpu_seconds(pid: u32) -> Option<f64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces, the fields after it do not
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let utime: f64 = fields.get(11)?.parse().ok()?;
+    let stime: f64 = fields.get(12)?.parse().ok()?;
+    Some((utime + stime) / CLOCK_TICKS_PER_SECOND)
+}
+
+// Collects every bot's snapshot intervals since the last call
+fn take_gaps(bots: &mut [Bot]) -> Gaps {
+    let mut gaps = Gaps::default();
+    for bot in bots {
+        gaps.merge(&std::mem::take(&mut bot.stats.gaps));
+    }
+    gaps
+}
+
+struct Report {
+    started: f64,
+    bytes: (u64, u64),
+    cpu: Option<f64>,
+}
+
+impl Report {
+    fn print(
+        &self,
+        bots: &[Bot],
+        gaps: &Gaps,
+        server_pid: Option<u32>,
+        now: f64,
+        label: &str,
+    ) -> Report {
+        let elapsed = (now - self.started).max(f64::EPSILON);
+        let total = bots
+            .iter()
+            .map(|b| b.stats())
+            .fold(EndpointStats::default(), add_stats);
+        let down = (total.bytes_received - self.bytes.0) as f64 / elapsed;
+        let up = (total.bytes_sent - self.bytes.1) as f64 / elapsed;
+        let connected = bots
+            .iter()
+            .filter(|b| b.connection.as_ref().is_some_and(|c| c.is_connected()))
+            .count();
+        let in_match = bots.iter().filter(|b| b.view.is_some()).count();
+
+        let sum = |f: fn(&Stats) -> u64| bots.iter().map(|b| f(&b.stats)).sum::<u64>();
+
+        println!(
+            "[{}] {:.0}s  bots {}/{} connected, {} in a match, {} matches",
+            label,
+            now,
+            connected,
+            bots.len(),
+            in_match,
+            sum(|s| s.matches),
+        );
+        println!(
+            "  bandwidth  down {:.1} KB/s ({:.2} KB/s per bot)  up {:.1} KB/s",
+            down / 1024.0,
+            down / 1024.0 / bots.len().max(1) as f64,
+            up / 1024.0,
+        );
+        println!(
+            "  snapshots  {} received, {} undecodable, {} ticks skipped, {} packets lost",
+            sum(|s| s.snapshots),
+            sum(|s| s.decode_failures),
+            sum(|s| s.skipped_ticks),
+            total.packets_lost,
+        );
+        println!(
+            "  interval   mean {:.1} ms (tick {:.1} ms)  jitter {:.1} ms  p99 {:.1} ms  max {:.1} ms",
+            gaps.mean() * 1000.0,
+            TICK_DT * 1000.0,
+            gaps.jitter() * 1000.0,
+            gaps.percentile(0.99) * 1000.0,
+            gaps.max * 1000.0,
+        );
+        println!(
+            "  cards {}  denied {}  timed out {}",
+            sum(|s| s.cards_played),
+            sum(|s| s.denied),
+            sum(|s| s.timeouts),
+        );
+
+        let cpu = server_pid.and_then(cpu_seconds);
+        if let (Some(before), Some(after)) = (self.cpu, cpu) {
+            println!(
+                "  server cpu {:.1}% of a core",
+                (after - before) / elapsed * 100.0
+            );
+        }
+        Report {
+            started: now,
+            bytes: (total.bytes_received, total.bytes_sent),
+            cpu,
+        }
+    }
+}
+
+fn main() {
+    let mut server = SocketAddr::from(([127, 0, 0, 1], SERVER_PORT));
+    let mut count = 8;
+    let mut seconds = 60.0;
+    let mut strategy = None;
+    let mut rating = None;
+    let mut server_pid = None;
+    let mut conditions = Conditions::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_default();
+        match arg.as_str() {
+            "--bots" => count = value().parse().unwrap_or(count),
+            "--seconds" => seconds = value().parse().unwrap_or(seconds),
+            "--strategy" => {
+                strategy = match value().as_str() {
+                    "random" => Some(Strategy::Random),
+                    "rush" => Some(Strategy::Rush),
+                    "saver" => Some(Strategy::Saver),
+                    _ => None,
+                }
+            }
+            "--rating" => rating = value().parse().ok(),
+            "--server-pid" => server_pid = value().parse().ok(),
+            "--conditions" => {
+                conditions = Conditions::parse(&value()).unwrap_or_else(|| {
+                    eprintln!("Ignoring malformed network conditions");
+                    conditions
+                })
+            }
+            _ => {
+                if let Ok(addr) = arg.parse() {
+                    server = addr;
+                }
+            }
+        }
+    }
+
+    // Mixed unless one strategy was asked for
+    let mut bots: Vec<Bot> = (0..count)
+        .map(|i| {
+            let strategy = strategy.unwrap_or(Strategy::ALL[i % Strategy::ALL.len()]);
+            Bot::new(strategy, conditions, rating, i as u64 + 1)
+        })
+        .collect();
+    println!("Running {} bots against {} for {}s", count, server, seconds);
+
+    let started = Instant::now();
+    let tick = TICK_DT as f64;
+    let mut next_tick = 0.0;
+    let mut next_report = REPORT_INTERVAL;
+    let mut interval = Report {
+        started: 0.0,
+        bytes: (0, 0),
+        cpu: server_pid.and_then(cpu_seconds),
+    };
+    let overall_cpu = interval.cpu;
+    let mut total_gaps = Gaps::default();
+
+    loop {
+        let now = started.elapsed().as_secs_f64();
+        if now >= seconds {
+            break;
+        }
+        for bot in &mut bots {
+            bot.poll(now);
+        }
+        if now >= next_tick {
+            for bot in &mut bots {
+                bot.tick(server, now);
+            }
+            // Skips ticks rather than bursting when this process falls behind
+            next_tick = (next_tick + tick).max(now);
+        }
+        if now >= next_report {
+            let gaps = take_gaps(&mut bots);
+            total_gaps.merge(&gaps);
+            interval = interval.print(&bots, &gaps, server_pid, now, "interval");
+            next_report += REPORT_INTERVAL;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    let now = started.elapsed().as_secs_f64();
+    total_gaps.merge(&take_gaps(&mut bots));
+    Report {
+        started: 0.0,
+        bytes: (0, 0),
+        cpu: overall_cpu,
+    }
+    .print(&bots, &total_gaps, server_pid, now, "total");
+    for bot in &mut bots {
+        if let Some(connection) = &mut bot.connection {
+            let _ = connection.disconnect();
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::VecDeque;
+
+use shared::protocol::ClientMessage;
+use shared::tick::TICK_RATE;
+
+// Seconds between pings once synchronized
+pub const PING_INTERVAL: f64 = 0.5;
+// Ticks the client stays ahead of the server on top of the one way latency, so
+// inputs land before the tick they target even with some jitter
+pub const INPUT_MARGIN_TICKS: f64 = 2.0;
+// Recent ping samples kept, the one with the lowest RTT gives the offset since
+// it spent the least time queued anywhere
+const SAMPLE_WINDOW: usize = 16;
+// Speed change per tick of error, and the most the clock is ever bent. A few
+// percent is invisible to the player.
+const ADJUST_GAIN: f64 = 0.02;
+const MAX_ADJUST: f64 = 0.05;
+// Further than this from the target, bending would take too long. The clock
+// stops or rushes instead.
+pub const SNAP_TICKS: f64 = 10.0;
+// Speed used to catch up after a snap backwards, capped by MAX_STEPS_PER_FRAME
+const CATCH_UP_SCALE: f32 = 4.0;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    rtt: f64,
+    // Server clock minus local clock
+    offset: f64,
+}
+
+// What the overlay shows, all in seconds or ticks
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClockStats {
+    pub rtt: f64,
+    pub offset: f64,
+    pub server_tick: f64,
+    pub target_tick: f64,
+    // Positive when the client runs ahead of where it should be
+    pub error: f64,
+    pub time_scale: f32,
+}
+
+// NTP-style estimate of the server clock from ping/pong round trips, and the
+// tick the client should be simulating to stay just ahead of the server
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    samples: VecDeque<Sample>,
+    // Server tick and server time carried by the newest pong
+    reference: Option<(u64, f64)>,
+    last_ping: Option<f64>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A ping to send if one is due, pinging faster until the first answer
+    pub fn ping(&mut self, now: f64) -> Option<ClientMessage> {
+        let interval = if self.is_synced() {
+            PING_INTERVAL
+        } else {
+            PING_INTERVAL / 5.0
+        };
+        if self.last_ping.is_some_and(|sent| now - sent < interval) {
+            return None;
+        }
+        self.last_ping = Some(now);
+        Some(ClientMessage::Ping { client_time: now })
+    }
+
+    pub fn pong(&mut self, client_time: f64, server_time: f64, server_tick: u64, now: f64) {
+        let rtt = now - client_time;
+        if rtt < 0.0 {
+            return;
+        }
+        // Assumes the trip took as long each way
+        let offset = server_time - (client_time + now) / 2.0;
+        if self.samples.len() == SAMPLE_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample { rtt, offset });
+
+        // Pongs can arrive out of order, keep the newest server reading
+        if self.reference.is_none_or(|(_, time)| server_time >= time) {
+            self.reference = Some((server_tick, server_time));
+        }
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.reference.is_some()
+    }
+
+    pub fn rtt(&self) -> Option<f64> {
+        let total: f64 = self.samples.iter().map(|s| s.rtt).sum();
+        (!self.samples.is_empty()).then(|| total / self.samples.len() as f64)
+    }
+
+    pub fn offset(&self) -> Option<f64> {
+        self.samples
+            .iter()
+            .min_by(|a, b| a.rtt.total_cmp(&b.rtt))
+            .map(|s| s.offset)
+    }
+
+    // Tick the server is simulating right now, with its fraction
+    pub fn server_tick(&self, now: f64) -> Option<f64> {
+        let (tick, time) = self.reference?;
+        let elapsed = now + self.offset()? - time;
+        Some(tick as f64 + elapsed * TICK_RATE as f64)
+    }
+
+    // Tick the client should be at: what the server will be simulating when an
+    // input sent now arrives, plus the safety margin
+    pub fn target_tick(&self, now: f64) -> Option<f64> {
+        let one_way = self.rtt()? / 2.0 * TICK_RATE as f64;
+        Some(self.server_tick(now)? + one_way + INPUT_MARGIN_TICKS)
+    }
+
+    // Factor to scale frame time by before it reaches the fixed timestep. Bends
+    // the clock a little when close to the target, stops it when far ahead and
+    // rushes when far behind.
+    pub fn time_scale(&self, client_tick: f64, now: f64) -> f32 {
+        let Some(target) = self.target_tick(now) else {
+            return 1.0;
+        };
+        let error = client_tick - target;
+        if error > SNAP_TICKS {
+            0.0
+        } else if error < -SNAP_TICKS {
+            CATCH_UP_SCALE
+        } else {
+            (1.0 - (error * ADJUST_GAIN).clamp(-MAX_ADJUST, MAX_ADJUST)) as f32
+        }
+    }
+
+    pub fn stats(&self, client_tick: f64, now: f64) -> ClockStats {
+        let target_tick = self.target_tick(now).unwrap_or(client_tick);
+        ClockStats {
+            rtt: self.rtt().unwrap_or(0.0),
+            offset: self.offset().unwrap_or(0.0),
+            server_tick: self.server_tick(now).unwrap_or(0.0),
+            target_tick,
+            error: client_tick - target_tick,
+            time_scale: self.time_scale(client_tick, now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A clock whose server runs 10 s ahead of ours, on tick 300 at server time 10
+    fn synced(rtt: f64) -> ClockSync {
+        let mut clock = ClockSync::new();
+        clock.pong(0.0, 10.0 + rtt / 2.0, 300, rtt);
+        clock
+    }
+
+    #[test]
+    fn offset_comes_from_the_quickest_round_trip() {
+        let mut clock = ClockSync::new();
+        assert!(!clock.is_synced());
+        // Held up somewhere on the way, its offset is off by the wait
+        clock.pong(0.0, 10.05, 300, 0.3);
+        clock.pong(1.0, 11.05, 330, 1.1);
+        assert!((clock.offset().unwrap() - 10.0).abs() < 1e-9);
+        assert!((clock.rtt().unwrap() - 0.2).abs() < 1e-9);
+
+        // Answers from before we asked are nonsense
+        clock.pong(5.0, 15.0, 450, 4.0);
+        assert!((clock.rtt().unwrap() - 0.2).abs() < 1e-9);
+
+        // Only the last SAMPLE_WINDOW samples count
+        for i in 0..SAMPLE_WINDOW {
+            let sent = 2.0 + i as f64;
+            clock.pong(sent, sent + 10.02, 360 + i as u64 * 30, sent + 0.04);
+        }
+        assert!((clock.rtt().unwrap() - 0.04).abs() < 1e-9);
+        assert!((clock.offset().unwrap() - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn late_pong_keeps_the_newer_reference() {
+        let mut clock = ClockSync::new();
+        clock.pong(1.0, 11.05, 330, 1.1);
+        clock.pong(0.0, 10.05, 300, 1.2);
+        // Tick 330 at server time 11.05, read at local time 2 which is server 12
+        let tick = clock.server_tick(2.0).unwrap();
+        assert!((tick - (330.0 + 0.95 * TICK_RATE as f64)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn target_leads_the_server_by_half_a_round_trip_and_the_margin() {
+        let clock = synced(0.2);
+        let server = clock.server_tick(0.2).unwrap();
+        let target = clock.target_tick(0.2).unwrap();
+        let lead = 0.1 * TICK_RATE as f64 + INPUT_MARGIN_TICKS;
+        assert!((target - server - lead).abs() < 1e-6);
+    }
+
+    #[test]
+    fn clock_bends_near_the_target_and_snaps_far_from_it() {
+        let clock = synced(0.2);
+        let target = clock.target_tick(0.2).unwrap();
+        let scale = |error: f64| clock.time_scale(target + error, 0.2);
+
+        assert_eq!(ClockSync::new().time_scale(100.0, 0.0), 1.0);
+        assert!((scale(0.0) - 1.0).abs() < 1e-6);
+        // Ahead slows down, behind speeds up, never by more than MAX_ADJUST
+        assert!((scale(1.0) - (1.0 - ADJUST_GAIN) as f32).abs() < 1e-6);
+        assert!((scale(-1.0) - (1.0 + ADJUST_GAIN) as f32).abs() < 1e-6);
+        assert!((scale(SNAP_TICKS) - (1.0 - MAX_ADJUST) as f32).abs() < 1e-6);
+        assert!((scale(-SNAP_TICKS) - (1.0 + MAX_ADJUST) as f32).abs() < 1e-6);
+        // Too far to bend: wait for the server, or rush to catch up
+        assert_eq!(scale(SNAP_TICKS + 1.0), 0.0);
+        assert_eq!(scale(-SNAP_TICKS - 1.0), CATCH_UP_SCALE);
+    }
+
+    #[test]
+    fn pings_faster_until_synced() {
+        let mut clock = ClockSync::new();
+        assert!(clock.ping(0.0).is_some());
+        assert!(clock.ping(PING_INTERVAL / 10.0).is_none());
+        assert!(clock.ping(PING_INTERVAL / 5.0).is_some());
+
+        clock.pong(0.1, 10.0, 300, 0.2);
+        assert!(clock.ping(0.3).is_none());
+        assert!(clock.ping(0.1 + PING_INTERVAL).is_some());
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+
+use shared::checksum::checksum_of;
+use shared::protocol::Outdated;
+use shared::{GameState, Unit};
+
+// How many locally simulated ticks are kept for comparison
+const HISTORY_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Desync {
+    pub tick: u64,
+    pub local_checksum: u64,
+    pub server_checksum: u64,
+}
+
+// What is kept of a predicted tick. Units are kept one by one, so those a
+// snapshot left outdated can be skipped, the rest only as a checksum.
+struct Prediction {
+    tick: u64,
+    // Last placement sequence applied
+    sequence: u32,
+    rest: u64,
+    units: Vec<UnitPrint>,
+}
+
+struct UnitPrint {
+    owner: u32,
+    id: u32,
+    x: f32,
+    y: f32,
+    checksum: u64,
+}
+
+impl UnitPrint {
+    fn of(unit: &Unit) -> Self {
+        UnitPrint {
+            owner: unit.owner,
+            id: unit.id,
+            x: unit.x,
+            y: unit.y,
+            checksum: checksum_of(unit),
+        }
+    }
+}
+
+// Compares the client's own simulation with the checksums the server sends in
+// snapshots and dumps both states when they diverge
+pub struct DesyncDetector {
+    // Predicted ticks, oldest first
+    history: VecDeque<Prediction>,
+    dump_dir: PathBuf,
+    last_reported: Option<u64>,
+}
+
+impl DesyncDetector {
+    pub fn new(dump_dir: impl Into<PathBuf>) -> Self {
+        DesyncDetector {
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            dump_dir: dump_dir.into(),
+            last_reported: None,
+        }
+    }
+
+    // Remember the local state at its tick so a later snapshot can be checked.
+    // A re-simulated tick replaces what was predicted for it and after it.
+    pub fn record(&mut self, state: &GameState, sequence: u32) {
+        while self.history.back().is_some_and(|p| p.tick >= state.tick) {
+            self.history.pop_back();
+        }
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(Prediction {
+            tick: state.tick,
+            sequence,
+            rest: state.checksum_without_units(),
+            units: state.units.iter().map(UnitPrint::of).collect(),
+        });
+    }
+
+    // Checks the snapshot for `tick` against what was predicted for it. A
+    // prediction that ran ahead of the server's `ack`, or that could not know
+    // about units the server shows, is a misprediction and not compared. Units
+    // the snapshot left `outdated` are left out on both sides.
+    pub fn check(
+        &mut self,
+        tick: u64,
+        ack: u32,
+        server_state: &GameState,
+        outdated: &Outdated,
+    ) -> Option<Desync> {
+        let local = self.history.iter().find(|p| p.tick == tick)?;
+        // Ids are handed out by whoever spawns first, a predicted unit can hold
+        // an id the server gave to the opponent's
+        if local.sequence > ack
+            || server_state.units.iter().any(|u| {
+                !local
+                    .units
+                    .iter()
+                    .any(|l| (l.owner, l.id) == (u.owner, u.id))
+            })
+        {
+            return None;
+        }
+        let server_units: Vec<UnitPrint> = server_state.units.iter().map(UnitPrint::of).collect();
+        let local_checksum = comparable(local.rest, &local.units, outdated);
+        let server_checksum = comparable(
+            server_state.checksum_without_units(),
+            &server_units,
+            outdated,
+        );
+        if local_checksum == server_checksum {
+            return None;
+        }
+
+        let desync = Desync {
+            tick,
+            local_checksum,
+            server_checksum,
+        };
+        // Only dump the first tick of a divergence, later ones are just noise
+        if self.last_reported.is_none_or(|last| last + 1 != tick) {
+            self.report(&desync, local, server_state);
+        }
+        self.last_reported = Some(tick);
+        Some(desync)
+    }
+
+    fn report(&self, desync: &Desync, local: &Prediction, server: &GameState) {
+        let path = self.dump_dir.join(format!("desync-{}.log", desync.tick));
+        // Only unit positions are kept of the prediction, the server's state is whole
+        let mut units = String::new();
+        for unit in &local.units {
+            units += &format!(
+                "unit {} of {} at ({:.2}, {:.2}) checksum {:016x}\n",
+                unit.id, unit.owner, unit.x, unit.y, unit.checksum
+            );
+        }
+        let dump = format!(
+            "tick {}\nlocal checksum {:016x}\nserver checksum {:016x}\n\n--- local ---\nchecksum without units {:016x}\n{}\n--- server ---\n{:#?}\n",
+            desync.tick, desync.local_checksum, desync.server_checksum, local.rest, units, server
+        );
+        match fs::write(&path, dump) {
+            Ok(()) => eprintln!(
+                "Desync at tick {}: local {:016x} != server {:016x}, state dumped to {}",
+                desync.tick,
+                desync.local_checksum,
+                desync.server_checksum,
+                path.display()
+            ),
+            Err(err) => eprintln!(
+                "Desync at tick {}: local {:016x} != server {:016x}, failed to write dump: {}",
+                desync.tick, desync.local_checksum, desync.server_checksum, err
+            ),
+        }
+    }
+}
+
+// Checksum of the state without the units a snapshot did not bring up to date
+fn comparable(rest: u64, units: &[UnitPrint], outdated: &Outdated) -> u64 {
+    let units: Vec<u64> = units
+        .iter()
+        .filter(|u| !outdated.stale.contains(&u.id) && !outdated.fogged.contains(&u.id))
+        .map(|u| u.checksum)
+        .collect();
+    checksum_of(&(rest, units))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prediction::Predictor;
+
+    fn arena() -> GameState {
+        let mut state = GameState::for_match(3);
+        state.add_player(1);
+        state.add_player(2);
+        state.spawn_unit(1, 3, 240.0, 600.0);
+        state
+    }
+
+    fn detector() -> DesyncDetector {
+        DesyncDetector::new(std::env::temp_dir())
+    }
+
+    #[test]
+    fn matching_prediction_passes() {
+        let mut detector = detector();
+        let mut state = arena();
+        state.update(0.1);
+        detector.record(&state, 1);
+        assert_eq!(
+            detector.check(state.tick, 1, &state, &Outdated::default()),
+            None
+        );
+        // Nothing predicted for that tick, nothing to compare
+        assert_eq!(
+            detector.check(state.tick + 1, 1, &state, &Outdated::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn diverged_state_is_reported() {
+        let mut detector = detector();
+        let mut local = arena();
+        local.update(0.1);
+        detector.record(&local, 1);
+
+        let mut server = local.clone();
+        server.units[0].health -= 1;
+        let desync = detector
+            .check(server.tick, 1, &server, &Outdated::default())
+            .unwrap();
+        assert_eq!(desync.tick, local.tick);
+        assert_ne!(desync.local_checksum, desync.server_checksum);
+    }
+
+    #[test]
+    fn mispredictions_are_not_desyncs() {
+        let mut detector = detector();
+        let mut local = arena();
+        local.update(0.1);
+        // A placement the server has not applied yet
+        detector.record(&local, 2);
+        assert_eq!(
+            detector.check(local.tick, 1, &local, &Outdated::default()),
+            None
+        );
+
+        // The opponent placed a unit we could not have predicted
+        detector.record(&local, 1);
+        let mut server = local.clone();
+        assert!(server.spawn_unit(2, 1, 240.0, 200.0));
+        assert_eq!(
+            detector.check(server.tick, 1, &server, &Outdated::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn units_are_matched_by_owner_and_id() {
+        let mut detector = detector();
+        let mut local = arena();
+        local.update(0.1);
+        detector.record(&local, 1);
+
+        // The server gave the id we predicted for our unit to the opponent's
+        let mut server = local.clone();
+        server.units[0].owner = 2;
+        assert_eq!(
+            detector.check(server.tick, 1, &server, &Outdated::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn outdated_units_are_not_compared() {
+        let mut detector = detector();
+        let mut local = arena();
+        assert!(local.spawn_unit(2, 1, 240.0, 200.0));
+        local.update(0.1);
+        detector.record(&local, 1);
+
+        // The first unit went out stale, the opponent's is under fog
+        let mut server = local.clone();
+        server.units[0].y -= 5.0;
+        server.units.pop();
+        let outdated = Outdated {
+            stale: vec![local.units[0].id],
+            fogged: vec![local.units[1].id],
+        };
+        assert_eq!(detector.check(server.tick, 1, &server, &outdated), None);
+        assert!(
+            detector
+                .check(server.tick, 1, &server, &Outdated::default())
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn predictor_checks_every_tick_it_simulated() {
+        let mut predictor = Predictor::new(arena(), 1);
+        for _ in 0..3 {
+            predictor.step();
+        }
+        let mut server = predictor.state.clone();
+        let tick = server.tick;
+        assert_eq!(
+            predictor
+                .desync
+                .check(tick, 0, &server, &Outdated::default()),
+            None
+        );
+
+        // The server moved the unit somewhere we did not
+        server.units[0].y += 5.0;
+        let desync = predictor
+            .desync
+            .check(tick, 0, &server, &Outdated::default());
+        assert_eq!(desync.map(|d| d.tick), Some(tick));
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::VecDeque;
+
+use shared::Unit;
+use shared::tick::TICK_RATE;
+
+// Remote units are drawn this many ticks behind the newest snapshot, enough to
+// always have a snapshot on both sides with a packet or two lost
+pub const DEFAULT_DELAY_TICKS: f32 = 3.0;
+// Past the newest snapshot, units keep moving on their last known velocity for
+// at most this many ticks before freezing in place
+pub const MAX_EXTRAPOLATION_TICKS: f32 = 6.0;
+// If the render clock drifts further than this from where it should be (long
+// stall, server restart), jump instead of catching up slowly
+const RESYNC_TICKS: f32 = 15.0;
+const BUFFER_LEN: usize = 32;
+
+struct Snapshot {
+    tick: u64,
+    units: Vec<Unit>,
+}
+
+// Buffers snapshots and samples units in the past, between two known states,
+// so opponent units move smoothly even though snapshots arrive in bursts
+pub struct InterpolationBuffer {
+    snapshots: VecDeque<Snapshot>,
+    delay_ticks: f32,
+    render_tick: f32,
+}
+
+impl InterpolationBuffer {
+    pub fn new(delay_ticks: f32) -> Self {
+        InterpolationBuffer {
+            snapshots: VecDeque::with_capacity(BUFFER_LEN),
+            delay_ticks,
+            render_tick: 0.0,
+        }
+    }
+
+    pub fn set_delay(&mut self, delay_ticks: f32) {
+        self.delay_ticks = delay_ticks.max(0.0);
+    }
+
+    pub fn delay(&self) -> f32 {
+        self.delay_ticks
+    }
+
+    pub fn push(&mut self, tick: u64, units: &[Unit]) {
+        let newest = self.snapshots.back().map(|s| s.tick);
+        // Far behind the newest is a server that started over, not a late packet
+        if newest.is_some_and(|newest| newest as f32 - tick as f32 > RESYNC_TICKS) {
+            self.snapshots.clear();
+        } else if newest.is_some_and(|newest| newest >= tick) {
+            // Late or duplicated snapshots carry nothing new
+            return;
+        }
+        if self.snapshots.len() == BUFFER_LEN {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(Snapshot {
+            tick,
+            units: units.to_vec(),
+        });
+    }
+
+    // Moves the render clock forward, keeping it `delay_ticks` behind the server
+    pub fn advance(&mut self, dt: f32) {
+        let Some(latest) = self.snapshots.back() else {
+            return;
+        };
+        let target = latest.tick as f32 - self.delay_ticks;
+        let drift = target - self.render_tick;
+        if drift.abs() > RESYNC_TICKS {
+            self.render_tick = target;
+            return;
+        }
+        // Run the clock up to 5% fast or slow to settle back onto the target
+        let scale = 1.0 + (drift * 0.05).clamp(-0.05, 0.05);
+        self.render_tick += dt * TICK_RATE as f32 * scale;
+    }
+
+    pub fn render_tick(&self) -> f32 {
+        self.render_tick
+    }
+
+    // Units not owned by `player_id` as they were at the render tick
+    pub fn sample(&self, player_id: u32) -> Vec<Unit> {
+        let Some(newest) = self.snapshots.back() else {
+            return Vec::new();
+        };
+
+        let t = self.render_tick;
+        if t >= newest.tick as f32 {
+            return self.extrapolate(player_id, t);
+        }
+
+        let Some(index) = self.snapshots.iter().position(|s| s.tick as f32 > t) else {
+            return Vec::new();
+        };
+        let to = &self.snapshots[index];
+        let Some(from) = index.checked_sub(1).map(|i| &self.snapshots[i]) else {
+            return remote(&to.units, player_id).cloned().collect();
+        };
+
+        let alpha = (t - from.tick as f32) / (to.tick - from.tick) as f32;
+        remote(&to.units, player_id)
+            .map(|unit| match from.units.iter().find(|u| u.id == unit.id) {
+                Some(previous) => lerp_unit(previous, unit, alpha),
+                // Spawned between the two snapshots, nothing to blend from
+                None => unit.clone(),
+            })
+            .collect()
+    }
+
+    // Dead reckoning from the two newest snapshots when the next one is late
+    fn extrapolate(&self, player_id: u32, t: f32) -> Vec<Unit> {
+        let mut newest = self.snapshots.iter().rev();
+        let Some(last) = newest.next() else {
+            return Vec::new();
+        };
+        let ahead = (t - last.tick as f32).min(MAX_EXTRAPOLATION_TICKS);
+
+        let Some(previous) = newest.next() else {
+            return remote(&last.units, player_id).cloned().collect();
+        };
+        let span = (last.tick - previous.tick) as f32;
+        remote(&last.units, player_id)
+            .map(
+                |unit| match previous.units.iter().find(|u| u.id == unit.id) {
+                    Some(before) => {
+                        let mut unit = unit.clone();
+                        unit.x += (unit.x - before.x) / span * ahead;
+                        unit.y += (unit.y - before.y) / span * ahead;
+                        unit
+                    }
+                    None => unit.clone(),
+                },
+            )
+            .collect()
+    }
+}
+
+fn remote(units: &[Unit], player_id: u32) -> impl Iterator<Item = &Unit> {
+    units.iter().filter(move |u| u.owner != player_id)
+}
+
+fn lerp_unit(from: &Unit, to: &Unit, alpha: f32) -> Unit {
+    let lerp = |a: f32, b: f32| a + (b - a) * alpha;
+    let mut unit = to.clone();
+    unit.x = lerp(from.x, to.x);
+    unit.y = lerp(from.y, to.y);
+    unit.health = lerp(from.health as f32, to.health as f32).round() as u32;
+    unit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPPONENT: u32 = 2;
+
+    fn units(y: f32) -> Vec<Unit> {
+        vec![Unit {
+            id: 1,
+            owner: OPPONENT,
+            x: 240.0,
+            y,
+            health: 100,
+            ..Default::default()
+        }]
+    }
+
+    fn y(buffer: &InterpolationBuffer) -> f32 {
+        buffer.sample(1)[0].y
+    }
+
+    #[test]
+    fn blends_between_the_snapshots_around_the_render_tick() {
+        let mut buffer = InterpolationBuffer::new(1.0);
+        buffer.push(100, &units(100.0));
+        buffer.push(102, &units(120.0));
+        buffer.advance(0.0);
+        assert_eq!(buffer.render_tick(), 101.0);
+        assert_eq!(y(&buffer), 110.0);
+        // Only other players' units are interpolated
+        assert!(buffer.sample(OPPONENT).is_empty());
+    }
+
+    #[test]
+    fn late_snapshots_are_extrapolated_only_so_far() {
+        let mut buffer = InterpolationBuffer::new(0.0);
+        buffer.push(100, &units(100.0));
+        buffer.push(102, &units(120.0));
+        buffer.advance(0.0);
+        assert_eq!(y(&buffer), 120.0);
+
+        // Two ticks past the newest snapshot, moving at 10 per tick
+        buffer.advance(2.0 / TICK_RATE as f32);
+        assert!((y(&buffer) - 140.0).abs() < 0.5);
+        // A second later it stopped where the cap left it
+        buffer.advance(1.0);
+        assert_eq!(y(&buffer), 120.0 + 10.0 * MAX_EXTRAPOLATION_TICKS);
+    }
+
+    #[test]
+    fn jumps_to_the_server_after_a_gap_or_a_restart() {
+        let mut buffer = InterpolationBuffer::new(3.0);
+        buffer.push(100, &units(100.0));
+        buffer.advance(0.0);
+        assert_eq!(buffer.render_tick(), 97.0);
+
+        // A long stall is not caught up on slowly
+        buffer.push(400, &units(200.0));
+        buffer.advance(0.0);
+        assert_eq!(buffer.render_tick(), 397.0);
+
+        // Nor is a tick going backwards ignored as a late snapshot
+        buffer.push(10, &units(300.0));
+        buffer.advance(0.0);
+        assert_eq!(buffer.render_tick(), 7.0);
+        assert_eq!(y(&buffer), 300.0);
+        // While a slightly late one still is
+        buffer.push(9, &units(0.0));
+        assert_eq!(y(&buffer), 300.0);
+    }
+}
@@ -1,3 +1,8 @@
+pub mod clock;
+pub mod desync;
 pub mod globals;
+pub mod interpolation;
+pub mod network;
+pub mod prediction;
 pub mod render;
 pub mod ui;
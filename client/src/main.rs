@@ -1,10 +1,72 @@
+use std::net::SocketAddr;
+
 use client::{
+    clock::ClockSync,
     globals::{CARD_HEIGHT, CARD_WIDTH},
-    render::Renderer,
-    ui::{card_preview::CardPreview, deck::Deck, elixir_bar},
+    interpolation::{DEFAULT_DELAY_TICKS, InterpolationBuffer},
+    network::Connection,
+    prediction::Predictor,
+    render::{Renderer, VIRTUAL_HEIGHT, VIRTUAL_WIDTH},
+    ui::{arena, card_preview::CardPreview, debug_overlay, deck::Deck, elixir_bar},
 };
 use macroquad::prelude::*;
-use shared::{Card, Vec2D};
+use shared::{
+    Card, GameState, cards,
+    delta::Baselines,
+    net::{Conditions, ConnectionState, DisconnectReason},
+    protocol::{ClientMessage, SERVER_PORT, ServerMessage},
+    tick::FixedTimestep,
+};
+
+// Covers the server's snapshot history so any baseline it picks is still here
+const BASELINE_CAPACITY: usize = 64;
+
+// The hand the server dealt, laid out as deck cards
+fn hand_cards(hand: &[u32]) -> Vec<Card> {
+    let catalog = cards::catalog();
+    hand.iter()
+        .filter_map(|id| catalog.iter().find(|c| c.id == *id))
+        .map(|card| Card {
+            width: CARD_WIDTH,
+            height: CARD_HEIGHT,
+            ..card.clone()
+        })
+        .collect()
+}
+
+// `[server] [--host | --room CODE] [--rating N] [--conditions SPEC]`, the public
+// queue by default. SPEC is a simulated network as `Conditions::parse` reads it.
+fn parse_args() -> (SocketAddr, ClientMessage, Conditions) {
+    let mut server = SocketAddr::from(([127, 0, 0, 1], SERVER_PORT));
+    let mut room = None;
+    let mut host = false;
+    let mut rating = None;
+    let mut conditions = Conditions::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--host" => host = true,
+            "--room" => room = args.next(),
+            "--rating" => rating = args.next().and_then(|r| r.parse().ok()),
+            "--conditions" => match args.next().as_deref().and_then(Conditions::parse) {
+                Some(parsed) => conditions = parsed,
+                None => eprintln!("Ignoring malformed network conditions"),
+            },
+            _ => {
+                if let Ok(addr) = arg.parse() {
+                    server = addr;
+                }
+            }
+        }
+    }
+
+    let lobby = match room {
+        Some(code) => ClientMessage::JoinRoom { code },
+        None if host => ClientMessage::CreateRoom,
+        None => ClientMessage::FindMatch { rating },
+    };
+    (server, lobby, conditions)
+}
 
 fn conf() -> Conf {
     Conf {
@@ -18,74 +80,175 @@ fn conf() -> Conf {
 
 #[macroquad::main(conf)]
 async fn main() {
-    let mut game_state = shared::GameState::new();
+    let (server, lobby, conditions) = parse_args();
+    let mut connection = Connection::connect(server, &lobby)
+        .map(|c| c.with_conditions(conditions))
+        .inspect_err(|err| eprintln!("Playing offline, could not reach {}: {}", server, err))
+        .ok();
+
+    let mut game_state = GameState::new();
+    game_state.cards = cards::catalog();
+    let mut predictor = Predictor::new(game_state, 1);
+    let mut timestep = FixedTimestep::new();
+    let mut clock = ClockSync::new();
+    let mut show_debug = false;
+    let mut input_health = None;
+    // Set once welcomed, lets us take our slot back after a dropped connection
+    let mut rejoin_token = None;
+    let mut interpolation = InterpolationBuffer::new(DEFAULT_DELAY_TICKS);
+    let mut baselines = Baselines::new(BASELINE_CAPACITY);
     let mut elixir_bar = elixir_bar::ElixirBar::new();
-    let mut deck = Deck::new(vec![
-        Card {
-            id: 0,
-            name: "Pekka".to_string(),
-            cost: 7,
-            pos: Vec2D { x: 0.0, y: 0.0 },
-            width: CARD_WIDTH,
-            height: CARD_HEIGHT,
-            health: 1000,
-        },
-        Card {
-            id: 1,
-            name: "Goblin".to_string(),
-            cost: 2,
-            pos: Vec2D { x: 0.0, y: 0.0 },
-            width: CARD_WIDTH,
-            height: CARD_HEIGHT,
-            health: 200,
-        },
-        Card {
-            id: 2,
-            name: "Archer".to_string(),
-            cost: 3,
-            pos: Vec2D { x: 0.0, y: 0.0 },
-            width: CARD_WIDTH,
-            height: CARD_HEIGHT,
-            health: 300,
-        },
-        Card {
-            id: 3,
-            name: "Knight".to_string(),
-            cost: 4,
-            pos: Vec2D { x: 0.0, y: 0.0 },
-            width: CARD_WIDTH,
-            height: CARD_HEIGHT,
-            health: 800,
-        },
-    ]);
+    // Until the server deals a hand, the whole catalog
+    let catalog: Vec<u32> = cards::catalog().iter().map(|c| c.id).collect();
+    let mut deck = Deck::new(hand_cards(&catalog));
 
     let card_preview = CardPreview {
-        next_card: Some(Card {
-            id: 4,
-            name: "Knight".to_string(),
-            cost: 4,
-            pos: Vec2D { x: 0.0, y: 0.0 },
-            width: CARD_WIDTH,
-            height: CARD_HEIGHT,
-            health: 800,
-        }),
+        next_card: hand_cards(&catalog).pop(),
     };
 
+    // Lets us say goodbye to the server before the window closes
+    prevent_quit();
+
     loop {
+        if is_quit_requested() {
+            if let Some(connection) = &mut connection {
+                let _ = connection.disconnect();
+            }
+            break;
+        }
+
         let renderer = Renderer::new();
         clear_background(BLACK);
 
+        let now = get_time();
+        if let Some(connection) = &mut connection {
+            for message in connection.poll() {
+                match message {
+                    ServerMessage::Queued => eprintln!("Looking for an opponent"),
+                    ServerMessage::RoomCreated { code } => {
+                        eprintln!("Room {} is open, waiting for the other player", code)
+                    }
+                    ServerMessage::RoomUnavailable => {
+                        eprintln!("No room to join or create");
+                        let _ = connection.disconnect();
+                    }
+                    ServerMessage::Welcome {
+                        player_id,
+                        token,
+                        hand,
+                    } => {
+                        if rejoin_token.is_some() {
+                            predictor.clear_pending();
+                        }
+                        rejoin_token = Some(token);
+                        predictor.set_player(player_id);
+                        deck = Deck::new(hand_cards(&hand));
+                    }
+                    ServerMessage::RejoinFailed => {
+                        eprintln!("The server gave our slot away");
+                        rejoin_token = None;
+                        let _ = connection.disconnect();
+                    }
+                    ServerMessage::Snapshot {
+                        tick,
+                        checksum,
+                        ack,
+                        payload,
+                        outdated,
+                    } => {
+                        // Undecodable deltas are dropped, the server falls back to
+                        // a full snapshot once our last ack leaves its history
+                        let Some(state) = baselines.decode(payload, checksum) else {
+                            continue;
+                        };
+                        connection.send(&ClientMessage::AckSnapshot { tick });
+                        interpolation.push(tick, &state.units);
+                        // Logs and dumps both states if our simulation went its own way
+                        predictor.desync.check(tick, ack, &state, &outdated);
+                        predictor.reconcile(ack, state, &outdated.stale);
+                    }
+                    ServerMessage::Pong {
+                        client_time,
+                        server_time,
+                        server_tick,
+                    } => clock.pong(client_time, server_time, server_tick, now),
+                    ServerMessage::InputHealth { health } => input_health = Some(health),
+                    ServerMessage::PlacementRejected { sequence } => predictor.reject(sequence),
+                }
+            }
+
+            // Sent on its own so it is not held back until the next tick. Pings
+            // queued during the handshake would measure the handshake.
+            if connection.is_connected()
+                && let Some(ping) = clock.ping(now)
+            {
+                connection.send(&ping);
+                let _ = connection.flush();
+            }
+        }
+        match (connection.as_ref().map(|c| c.state()), rejoin_token) {
+            // Lost touch without a goodbye, the server holds our slot for a while
+            (Some(ConnectionState::Disconnected(DisconnectReason::TimedOut)), Some(token)) => {
+                eprintln!("Connection lost, rejoining {}", server);
+                connection = Connection::rejoin(server, token)
+                    .map(|c| c.with_conditions(conditions))
+                    .ok();
+            }
+            (Some(ConnectionState::Disconnected(reason)), _) => {
+                eprintln!(
+                    "Playing offline, disconnected from the server: {:?}",
+                    reason
+                );
+                connection = None;
+            }
+            _ => {}
+        }
+
+        let frame_dt = get_frame_time();
+        predictor.corrections.decay(frame_dt);
+        interpolation.advance(frame_dt);
+        // The prediction runs slightly fast or slow to stay just ahead of the server
+        let client_tick = predictor.state.tick as f64 + timestep.alpha() as f64;
+        let time_scale = clock.time_scale(client_tick, now);
+        for _ in 0..timestep.advance(frame_dt * time_scale) {
+            predictor.step();
+            if let Some(connection) = &mut connection {
+                let _ = connection.flush();
+            }
+        }
+
         let mouse_pos = mouse_position();
         let mouse_vec = Vec2::from(mouse_pos);
 
-        if let Some(unit) = deck.update(mouse_vec, &mut game_state.players[0].elixir) {
-            game_state.units.push(unit);
+        if let Some(placed) = deck.update(mouse_vec, predictor.elixir()) {
+            // The unit shows up right away, the server confirms it later
+            let (x, y) = (placed.x * VIRTUAL_WIDTH, placed.y * VIRTUAL_HEIGHT);
+            if let Some(message) = predictor.place_card(placed.card_id, x, y)
+                && let Some(connection) = &mut connection
+            {
+                connection.send(&message);
+            }
         }
+        elixir_bar.set_elixir(predictor.elixir());
 
         // Render
+        arena::render(
+            &renderer,
+            &predictor.state,
+            predictor.player_id,
+            &predictor.corrections,
+            &interpolation.sample(predictor.player_id),
+        );
         deck.render(&renderer);
         elixir_bar.render(&renderer);
         card_preview.render(&renderer);
+        if is_key_pressed(KeyCode::F3) {
+            show_debug = !show_debug;
+        }
+        if show_debug {
+            let clock_stats = clock.stats(client_tick, now);
+            debug_overlay::render(&renderer, &clock_stats, input_health.as_ref());
+        }
 
         next_frame().await;
     }
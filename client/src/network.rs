@@ -0,0 +1,110 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Instant;
+
+use shared::net::{ClientConnection, Conditioned, Conditions, ConnectionState, EndpointStats};
+use shared::net::{Transport, random_u64};
+use shared::protocol::{self, ClientMessage, ServerMessage};
+
+pub struct Connection {
+    // Ideal conditions unless `with_conditions` asked for a worse network
+    socket: Conditioned<UdpSocket>,
+    server: SocketAddr,
+    connection: ClientConnection,
+    started: Instant,
+}
+
+impl Connection {
+    // The handshake starts with the first `flush`, messages sent meanwhile go out
+    // once it completes. `lobby` says how to find a match: the queue or a room.
+    pub fn connect(server: SocketAddr, lobby: &ClientMessage) -> io::Result<Self> {
+        Self::open(server, lobby)
+    }
+
+    // Like `connect`, but takes back the slot the token was issued for
+    pub fn rejoin(server: SocketAddr, token: u64) -> io::Result<Self> {
+        Self::open(server, &ClientMessage::Rejoin { token })
+    }
+
+    fn open(server: SocketAddr, hello: &ClientMessage) -> io::Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.set_nonblocking(true)?;
+
+        let mut connection = Connection {
+            socket: Conditioned::new(socket, Conditions::default(), random_u64()),
+            server,
+            connection: ClientConnection::new(0.0),
+            started: Instant::now(),
+        };
+        connection.send(hello);
+        Ok(connection)
+    }
+
+    // Simulates a bad network for everything this connection sends and receives
+    // from now on
+    pub fn with_conditions(mut self, conditions: Conditions) -> Self {
+        self.socket.set_conditions(conditions);
+        self
+    }
+
+    // Queues a message on its channel, it goes out with the next `flush`
+    pub fn send(&mut self, message: &ClientMessage) {
+        self.connection
+            .send(message.channel(), protocol::encode(message));
+    }
+
+    // Puts handshake packets, queued messages, resends and acks on the wire, once
+    // per tick
+    pub fn flush(&mut self) -> io::Result<()> {
+        let now = self.now();
+        for packet in self.connection.packets(now) {
+            self.socket.send(self.server, &packet, now)?;
+        }
+        // Delayed datagrams go out even on flushes with nothing new
+        self.socket.release(now)
+    }
+
+    // Drains every datagram that has arrived since the last call
+    pub fn poll(&mut self) -> Vec<ServerMessage> {
+        let now = self.now();
+        loop {
+            match self.socket.receive(now) {
+                Ok(Some((addr, datagram))) if addr == self.server => {
+                    self.connection.receive(&datagram, now);
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                // ICMP errors from a server that is not up yet, keep trying
+                Err(_) => break,
+            }
+        }
+
+        self.connection
+            .drain_received()
+            .into_iter()
+            .filter_map(|(_, payload)| protocol::decode(&payload))
+            .collect()
+    }
+
+    // Tells the server we are leaving, nothing is sent after this
+    pub fn disconnect(&mut self) -> io::Result<()> {
+        self.connection.disconnect();
+        self.flush()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_connected()
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.connection.state()
+    }
+
+    pub fn stats(&self) -> EndpointStats {
+        self.connection.stats()
+    }
+
+    fn now(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+}
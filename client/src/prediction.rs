@@ -0,0 +1,342 @@
+use std::collections::{HashMap, VecDeque};
+
+use shared::event::GameEvent;
+use shared::protocol::ClientMessage;
+use shared::tick::{TICK_DT, TICK_RATE};
+use shared::{GameState, Vec2D};
+
+use crate::desync::DesyncDetector;
+
+// A placement applied locally that the server has not acknowledged yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PendingInput {
+    pub sequence: u32,
+    pub tick: u64,
+    pub card_id: u32,
+    pub x: f32,
+    pub y: f32,
+}
+
+impl PendingInput {
+    pub fn to_message(self) -> ClientMessage {
+        ClientMessage::PlaceCard {
+            sequence: self.sequence,
+            tick: self.tick,
+            card_id: self.card_id,
+            x: self.x,
+            y: self.y,
+        }
+    }
+}
+
+// Corrections further than this are applied instantly, smoothing them would look
+// like a unit sliding across the arena
+pub const SNAP_DISTANCE: f32 = 60.0;
+// Fraction of the remaining visual correction removed per second
+pub const CORRECTION_RATE: f32 = 10.0;
+// Furthest a reconcile re-simulates past the snapshot. A prediction further
+// ahead is pulled back, and the clock sync speeds the client up again from there.
+pub const MAX_RESIMULATE_TICKS: u64 = TICK_RATE as u64;
+
+// Visual offsets left over from reconciliation. Units are simulated at their
+// corrected positions but drawn at `position + offset`, with the offset decaying
+// to zero, so corrections blend in instead of teleporting.
+#[derive(Debug, Default)]
+pub struct Corrections {
+    offsets: HashMap<u32, Vec2D>,
+}
+
+impl Corrections {
+    pub fn offset(&self, unit_id: u32) -> Vec2D {
+        self.offsets
+            .get(&unit_id)
+            .copied()
+            .unwrap_or(Vec2D { x: 0.0, y: 0.0 })
+    }
+
+    pub fn decay(&mut self, dt: f32) {
+        let keep = (-CORRECTION_RATE * dt).exp();
+        self.offsets.retain(|_, offset| {
+            offset.x *= keep;
+            offset.y *= keep;
+            offset.x.abs() > 0.01 || offset.y.abs() > 0.01
+        });
+    }
+
+    // Remember where each unit was drawn before the state was replaced
+    fn track(&mut self, before: &GameState, after: &GameState) {
+        let mut offsets = HashMap::new();
+        for unit in &after.units {
+            let Some(old) = before.units.iter().find(|u| u.id == unit.id) else {
+                continue;
+            };
+            let drawn = self.offset(unit.id);
+            let offset = Vec2D {
+                x: old.x + drawn.x - unit.x,
+                y: old.y + drawn.y - unit.y,
+            };
+            if offset.x.hypot(offset.y) < SNAP_DISTANCE {
+                offsets.insert(unit.id, offset);
+            }
+        }
+        self.offsets = offsets;
+    }
+}
+
+// Applies the local player's card plays immediately to a predicted copy of the
+// match instead of waiting a round trip for the server to confirm them
+pub struct Predictor {
+    pub state: GameState,
+    pub player_id: u32,
+    pub corrections: Corrections,
+    // Every predicted tick, checked against the server's snapshots
+    pub desync: DesyncDetector,
+    pending: VecDeque<PendingInput>,
+    next_sequence: u32,
+}
+
+impl Predictor {
+    pub fn new(state: GameState, player_id: u32) -> Self {
+        Predictor {
+            state,
+            player_id,
+            corrections: Corrections::default(),
+            desync: DesyncDetector::new(std::env::temp_dir()),
+            pending: VecDeque::new(),
+            next_sequence: 1,
+        }
+    }
+
+    // Called once the server has told us which side we control
+    pub fn set_player(&mut self, player_id: u32) {
+        self.player_id = player_id;
+        self.state.add_player(player_id);
+    }
+
+    // Plays the card on the predicted state, returns the message to send or None
+    // if the placement is not valid locally
+    pub fn place_card(&mut self, card_id: u32, x: f32, y: f32) -> Option<ClientMessage> {
+        if !self.state.spawn_unit(self.player_id, card_id, x, y) {
+            return None;
+        }
+
+        let input = PendingInput {
+            sequence: self.next_sequence,
+            tick: self.state.tick,
+            card_id,
+            x,
+            y,
+        };
+        self.next_sequence += 1;
+        self.pending.push_back(input);
+        Some(input.to_message())
+    }
+
+    pub fn step(&mut self) -> Vec<GameEvent> {
+        let events = self.state.update(TICK_DT);
+        hide_opponents(&mut self.state, self.player_id);
+        self.desync.record(&self.state, self.next_sequence - 1);
+        events
+    }
+
+    // The server has applied every placement up to and including `sequence`
+    pub fn acknowledge(&mut self, sequence: u32) {
+        while self.pending.front().is_some_and(|p| p.sequence <= sequence) {
+            self.pending.pop_front();
+        }
+    }
+
+    // The server did not apply the placement, the next reconcile drops its unit
+    pub fn reject(&mut self, sequence: u32) {
+        self.pending.retain(|p| p.sequence != sequence);
+    }
+
+    // Rewinds to the authoritative state, reapplies every placement the server has
+    // not acknowledged and re-simulates back up to the predicted tick. The
+    // snapshot is only this client's view: opponents' elixir stays hidden and
+    // fogged units are left out rather than guessed at. `stale` units carry old
+    // values from the server, so they keep their predicted ones instead.
+    pub fn reconcile(&mut self, ack: u32, server_state: GameState, stale: &[u32]) {
+        self.acknowledge(ack);
+
+        let target = self
+            .state
+            .tick
+            .clamp(server_state.tick, server_state.tick + MAX_RESIMULATE_TICKS);
+        let mut state = server_state;
+        let mut sequence = ack;
+        let mut pending = self.pending.iter().peekable();
+        loop {
+            // Inputs the server has not seen yet land on their own tick, or right
+            // away if that tick is already behind the snapshot
+            while let Some(input) = pending.next_if(|input| input.tick <= state.tick) {
+                state.spawn_unit(self.player_id, input.card_id, input.x, input.y);
+                sequence = input.sequence;
+            }
+            if state.tick >= target {
+                break;
+            }
+            state.update(TICK_DT);
+            hide_opponents(&mut state, self.player_id);
+            self.desync.record(&state, sequence);
+        }
+        // Placements on ticks beyond a snap back wait for the next reconcile to
+        // land on their own tick, until then they are shown right away
+        for input in pending {
+            state.spawn_unit(self.player_id, input.card_id, input.x, input.y);
+        }
+        for unit in state.units.iter_mut().filter(|u| stale.contains(&u.id)) {
+            if let Some(predicted) = self.state.units.iter().find(|p| p.id == unit.id) {
+                *unit = predicted.clone();
+            }
+        }
+        // Everything re-simulated here was already shown when it was predicted
+        state.take_events();
+
+        self.corrections.track(&self.state, &state);
+        self.state = state;
+    }
+
+    // Forgets placements a lost connection took with it, the server never saw
+    // them or already reflects them in its state
+    pub fn clear_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    // Placements still to be confirmed, reapplied on every reconcile
+    pub fn pending(&self) -> impl Iterator<Item = &PendingInput> {
+        self.pending.iter()
+    }
+
+    pub fn elixir(&self) -> u32 {
+        self.state
+            .players
+            .iter()
+            .find(|p| p.id == self.player_id)
+            .map_or(0, |p| p.elixir)
+    }
+}
+
+// Snapshots carry no elixir for the other players, so it is not predicted either
+fn hide_opponents(state: &mut GameState, player_id: u32) {
+    for player in state.players.iter_mut().filter(|p| p.id != player_id) {
+        player.elixir = 0;
+        player.elixir_timer = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::UNIT_SPEED;
+
+    fn arena() -> GameState {
+        let mut state = GameState::for_match(3);
+        state.add_player(1);
+        state.add_player(2);
+        state
+    }
+
+    // What the server sends player 1, without the opponent's elixir
+    fn view(state: &GameState) -> GameState {
+        let mut view = state.clone();
+        view.players[1].elixir = 0;
+        view.players[1].elixir_timer = 0.0;
+        view
+    }
+
+    #[test]
+    fn hidden_elixir_is_not_predicted() {
+        let mut predictor = Predictor::new(arena(), 1);
+        for _ in 0..100 {
+            predictor.step();
+        }
+        predictor.reconcile(0, server(70, &[]), &[]);
+
+        assert_eq!(predictor.state.tick, 100);
+        let opponent = &predictor.state.players[1];
+        assert_eq!((opponent.elixir, opponent.elixir_timer), (0, 0.0));
+        assert_eq!(predictor.elixir(), 10);
+    }
+
+    // The server's side of the match, stepped to `tick` with the placements
+    // it received applied on their ticks
+    fn server(tick: u64, placed: &[PendingInput]) -> GameState {
+        let mut state = arena();
+        while state.tick < tick {
+            let now = state.tick;
+            for input in placed.iter().filter(|p| p.tick == now) {
+                state.spawn_unit(1, input.card_id, input.x, input.y);
+            }
+            state.update(TICK_DT);
+        }
+        view(&state)
+    }
+
+    // A predictor that placed a goblin on tick 10 and ran on to tick 20
+    fn predicted() -> (Predictor, PendingInput) {
+        let mut predictor = Predictor::new(arena(), 1);
+        for _ in 0..10 {
+            predictor.step();
+        }
+        predictor.place_card(1, 240.0, 600.0).unwrap();
+        for _ in 0..10 {
+            predictor.step();
+        }
+        let input = *predictor.pending().next().unwrap();
+        (predictor, input)
+    }
+
+    #[test]
+    fn pending_placements_are_reapplied_on_their_tick() {
+        let (mut predictor, input) = predicted();
+        let before = predictor.state.checksum();
+
+        // The snapshot is from before the placement reached the server
+        predictor.reconcile(0, server(8, &[]), &[]);
+        assert_eq!(predictor.state.tick, 20);
+        assert_eq!(predictor.pending().count(), 1);
+        assert_eq!(predictor.state.checksum(), before);
+
+        // Once applied and acked it comes from the server and is not added again
+        predictor.reconcile(1, server(15, &[input]), &[]);
+        assert_eq!(predictor.pending().count(), 0);
+        assert_eq!(predictor.state.units.len(), 1);
+        assert_eq!(predictor.state.checksum(), before);
+    }
+
+    #[test]
+    fn placement_behind_the_snapshot_is_applied_right_away() {
+        let (mut predictor, _) = predicted();
+        predictor.reconcile(0, server(12, &[]), &[]);
+
+        // Eight ticks of walking instead of ten
+        let walked = predictor.state.units[0].travelled;
+        assert!((walked - 8.0 * UNIT_SPEED * TICK_DT).abs() < 0.5);
+        assert_eq!(predictor.pending().count(), 1);
+    }
+
+    #[test]
+    fn rejected_placement_is_dropped() {
+        let (mut predictor, input) = predicted();
+        predictor.reject(input.sequence);
+        predictor.reconcile(0, server(15, &[]), &[]);
+        assert!(predictor.state.units.is_empty());
+    }
+
+    #[test]
+    fn far_ahead_prediction_is_pulled_back() {
+        let mut predictor = Predictor::new(arena(), 1);
+        for _ in 0..100 {
+            predictor.step();
+        }
+        predictor.place_card(1, 240.0, 600.0).unwrap();
+        predictor.step();
+
+        predictor.reconcile(0, server(10, &[]), &[]);
+        assert_eq!(predictor.state.tick, 10 + MAX_RESIMULATE_TICKS);
+        // The placement is still shown while its tick is out of reach
+        assert_eq!(predictor.state.units.len(), 1);
+        assert_eq!(predictor.pending().count(), 1);
+    }
+}
@@ -0,0 +1,48 @@
+use macroquad::color::{BLUE, Color, RED, WHITE};
+use shared::{GameState, Unit};
+
+use crate::{
+    globals::{TOWER_RADIUS, UNIT_RADIUS},
+    prediction::Corrections,
+    render::{Renderer, VIRTUAL_HEIGHT, VIRTUAL_WIDTH},
+};
+
+// Draws towers and units, the local player's in blue and the opponent's in red.
+// Local units come from the predicted state, `remote` ones from interpolation.
+pub fn render(
+    renderer: &Renderer,
+    state: &GameState,
+    player_id: u32,
+    corrections: &Corrections,
+    remote: &[Unit],
+) {
+    for tower in &state.towers {
+        let color = if tower.owner == player_id { BLUE } else { RED };
+        renderer.draw_circle(
+            tower.x / VIRTUAL_WIDTH,
+            tower.y / VIRTUAL_HEIGHT,
+            TOWER_RADIUS,
+            color,
+        );
+    }
+
+    for unit in state.units.iter().filter(|u| u.owner == player_id) {
+        let offset = corrections.offset(unit.id);
+        draw_unit(
+            renderer,
+            unit.x + offset.x,
+            unit.y + offset.y,
+            unit.health,
+            BLUE,
+        );
+    }
+    for unit in remote {
+        draw_unit(renderer, unit.x, unit.y, unit.health, RED);
+    }
+}
+
+fn draw_unit(renderer: &Renderer, x: f32, y: f32, health: u32, color: Color) {
+    let (x, y) = (x / VIRTUAL_WIDTH, y / VIRTUAL_HEIGHT);
+    renderer.draw_circle(x, y, UNIT_RADIUS * VIRTUAL_WIDTH, color);
+    renderer.draw_text(&health.to_string(), x, y, 16.0, WHITE);
+}
@@ -0,0 +1,41 @@
+use macroquad::color::{BLACK, WHITE};
+
+use shared::protocol::BufferHealth;
+
+use crate::{clock::ClockStats, render::Renderer};
+
+const X: f32 = 0.02;
+const Y: f32 = 0.03;
+const LINE_HEIGHT: f32 = 0.025;
+const WIDTH: f32 = 0.42;
+
+// Network timing readout, toggled with F3. `inputs` is the latest report on the
+// server's buffer of our placements, if any arrived yet.
+pub fn render(renderer: &Renderer, clock: &ClockStats, inputs: Option<&BufferHealth>) {
+    let mut lines = vec![
+        format!("rtt {:.1} ms", clock.rtt * 1000.0),
+        format!("offset {:.1} ms", clock.offset * 1000.0),
+        format!("server tick {:.1}", clock.server_tick),
+        format!("target tick {:.1}", clock.target_tick),
+        format!("clock error {:+.2} ticks", clock.error),
+        format!("time scale {:.3}", clock.time_scale),
+    ];
+    if let Some(inputs) = inputs {
+        lines.push(format!("input lead {:+.1} ticks", inputs.average_lead));
+        lines.push(format!(
+            "inputs late {} dropped {} buffered {}",
+            inputs.late, inputs.dropped, inputs.buffered
+        ));
+    }
+
+    renderer.draw_rectangle(
+        X - 0.01,
+        Y - LINE_HEIGHT,
+        WIDTH,
+        LINE_HEIGHT * (lines.len() as f32 + 0.5),
+        BLACK,
+    );
+    for (i, line) in lines.iter().enumerate() {
+        renderer.draw_text(line, X, Y + LINE_HEIGHT * i as f32, 16.0, WHITE);
+    }
+}
@@ -5,7 +5,15 @@ use crate::globals::{
 use crate::render::{Renderer, VIRTUAL_HEIGHT, VIRTUAL_WIDTH};
 use macroquad::color::{BLUE, BROWN, GOLD, LIGHTGRAY, WHITE};
 use macroquad::prelude::*;
-use shared::{Card, Unit, Vec2D};
+use shared::{Card, Vec2D};
+
+// A card dropped on the battlefield, position in normalized screen coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlacedCard {
+    pub card_id: u32,
+    pub x: f32,
+    pub y: f32,
+}
 
 pub struct Deck {
     pub cards: Vec<Card>,
@@ -118,13 +126,13 @@ impl Deck {
         }
     }
 
-    pub fn update(&mut self, mouse_vec: Vec2, elixir: &mut u32) -> Option<Unit> {
-        let mut new_unit: Option<Unit> = None;
+    pub fn update(&mut self, mouse_vec: Vec2, elixir: u32) -> Option<PlacedCard> {
+        let mut placed: Option<PlacedCard> = None;
         let unit_x = mouse_vec.x / VIRTUAL_WIDTH;
         let unit_y = mouse_vec.y / VIRTUAL_HEIGHT;
 
         // Check if card is dragging out of the deck or not
-        let in_deck = unit_y >= DECK_Y && unit_x >= DECK_X && unit_x <= DECK_X + DECK_WIDTH;
+        let in_deck = unit_y >= DECK_Y && (DECK_X..=DECK_X + DECK_WIDTH).contains(&unit_x);
 
         // Start dragging a card
         if is_mouse_button_pressed(MouseButton::Left)
@@ -144,24 +152,24 @@ impl Deck {
         }
 
         // Update dragging card
-        if let Some((card_id, offset, original_pos)) = self.dragging_card {
-            if let Some(card) = self.cards.iter_mut().find(|c| c.id == card_id) {
-                card.pos.x = (mouse_vec.x - offset.x) / VIRTUAL_WIDTH;
-                card.pos.y = (mouse_vec.y - offset.y) / VIRTUAL_HEIGHT;
-                card.pos.x = card.pos.x.clamp(0.0, 1.0 - CARD_WIDTH);
-                card.pos.y = card.pos.y.clamp(0.0, 1.0 - CARD_HEIGHT);
-
-                // Transition to unit if dragged above the deck
-                if card.pos.y < DECK_Y - 0.05 {
-                    self.dragging_card = None;
-                    self.dragging_unit = Some((card_id, Vec2::new(0.0, 0.0), original_pos));
-                }
+        if let Some((card_id, offset, original_pos)) = self.dragging_card
+            && let Some(card) = self.cards.iter_mut().find(|c| c.id == card_id)
+        {
+            card.pos.x = (mouse_vec.x - offset.x) / VIRTUAL_WIDTH;
+            card.pos.y = (mouse_vec.y - offset.y) / VIRTUAL_HEIGHT;
+            card.pos.x = card.pos.x.clamp(0.0, 1.0 - CARD_WIDTH);
+            card.pos.y = card.pos.y.clamp(0.0, 1.0 - CARD_HEIGHT);
+
+            // Transition to unit if dragged above the deck
+            if card.pos.y < DECK_Y - 0.05 {
+                self.dragging_card = None;
+                self.dragging_unit = Some((card_id, Vec2::new(0.0, 0.0), original_pos));
             }
         }
 
         // Update dragging unit
-        if let Some((unit_id, offset, original_pos)) = self.dragging_unit {
-            if let Some(card) = self.cards.iter_mut().find(|c| c.id == unit_id) {
+        if let Some((unit_id, _, original_pos)) = self.dragging_unit {
+            if self.cards.iter().any(|c| c.id == unit_id) {
                 // Transition back to card if dragged into the deck
                 if is_mouse_button_down(MouseButton::Left) && in_deck {
                     self.dragging_unit = None;
@@ -180,17 +188,14 @@ impl Deck {
                 let card = &self.cards[card_index];
 
                 // Check if the unit can be deploy to the battlefield or not
-                let in_battlefield = unit_y < DECK_Y && *elixir >= card.cost;
+                let in_battlefield = unit_y < DECK_Y && elixir >= card.cost;
 
                 if in_battlefield && !in_deck {
-                    let new_unit = Some(Unit {
-                        id: unit_id,
+                    placed = Some(PlacedCard {
+                        card_id: unit_id,
                         x: unit_x,
                         y: unit_y,
-                        health: card.health,
-                        velocity: 10.0,
                     });
-                    *elixir -= card.cost;
                     self.cards.remove(card_index);
 
                 // TODO: ADD new card to replace the used one
@@ -208,14 +213,14 @@ impl Deck {
         }
 
         if is_mouse_button_released(MouseButton::Left) && self.dragging_card.is_some() {
-            if let Some((card_id, _, original_pos)) = self.dragging_card {
-                if let Some(card) = self.cards.iter_mut().find(|c| c.id == card_id) {
-                    card.pos = original_pos;
-                }
+            if let Some((card_id, _, original_pos)) = self.dragging_card
+                && let Some(card) = self.cards.iter_mut().find(|c| c.id == card_id)
+            {
+                card.pos = original_pos;
             }
             self.dragging_card = None;
         }
 
-        new_unit
+        placed
     }
 }
@@ -1,7 +1,7 @@
 use macroquad::color::{DARKGRAY, LIGHTGRAY, PURPLE, WHITE};
 
 use crate::{
-    globals::{DECK_WIDTH, DECK_X, DECK_Y, ELIXIR_BAR_HEIGHT, ELIXIR_MARGIN},
+    globals::{DECK_WIDTH, DECK_X, ELIXIR_BAR_HEIGHT, ELIXIR_MARGIN},
     render::{Renderer, VIRTUAL_HEIGHT},
 };
 
@@ -15,6 +15,10 @@ impl ElixirBar {
         ElixirBar { elixir: 7 }
     }
 
+    pub fn set_elixir(&mut self, elixir: u32) {
+        self.elixir = elixir;
+    }
+
     pub fn render(&self, renderer: &Renderer) {
         for i in 0..10 {
             let filled = i < self.elixir as usize;
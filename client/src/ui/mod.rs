@@ -1,3 +1,5 @@
+pub mod arena;
+pub mod debug_overlay;
 pub mod deck;
 pub mod elixir_bar;
 pub  mod card_preview;
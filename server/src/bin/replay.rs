@@ -0,0 +1,92 @@
+// Plays back replays saved by `server --replays DIR`. Each match is simulated
+// again from its seed and recorded actions and checked against the checksums
+// stored along the way, so a desync between builds or a tampered file shows up
+// as the first tick that came out differently.
+//
+// replay FILE...
+
+use std::fs;
+use std::process::ExitCode;
+
+use shared::replay::{Action, Replay};
+use shared::tick::TICK_RATE;
+
+fn main() -> ExitCode {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    if paths.is_empty() {
+        eprintln!("Usage: replay FILE...");
+        return ExitCode::FAILURE;
+    }
+
+    let mut failed = 0;
+    for path in &paths {
+        if !check(path) {
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        println!("{} of {} replays failed", failed, paths.len());
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+fn check(path: &str) -> bool {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            println!("{}: {}", path, err);
+            return false;
+        }
+    };
+    let replay = match Replay::decode(&bytes) {
+        Ok(replay) => replay,
+        Err(err) => {
+            println!("{}: {:?}", path, err);
+            return false;
+        }
+    };
+
+    let header = &replay.header;
+    println!(
+        "{}: {} bytes, seed {:016x}, game {}",
+        path,
+        bytes.len(),
+        header.seed,
+        header.game_version
+    );
+    // Only the file layout must match, the checksums tell whether the
+    // simulation still does
+    if header.game_version != env!("CARGO_PKG_VERSION") {
+        println!("  recorded by game version {}", header.game_version);
+    }
+    for player in &header.players {
+        let cards = replay.actions.iter().filter(|(_, action)| {
+            matches!(action, Action::PlaceCard { player_id, .. } if *player_id == player.player_id)
+        });
+        println!(
+            "  player {}: deck {:?}, {} cards played",
+            player.player_id,
+            player.deck,
+            cards.count()
+        );
+    }
+
+    match replay.play() {
+        Ok(state) => {
+            let winner = state.winner.map_or("none".to_string(), |id| id.to_string());
+            println!(
+                "  ok: {} ticks ({:.1}s), {} checksums matched, winner {}",
+                state.tick,
+                state.tick as f64 / TICK_RATE as f64,
+                replay.checksums.len(),
+                winner
+            );
+            true
+        }
+        Err(err) => {
+            println!("  failed: {:?}", err);
+            false
+        }
+    }
+}
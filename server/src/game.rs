@@ -0,0 +1,673 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use shared::GameState;
+use shared::bitpack;
+use shared::delta::{self, SnapshotPayload};
+use shared::event::GameEvent;
+use shared::net::random_u64;
+use shared::protocol::ServerMessage;
+use shared::replay::{Action, CHECKSUM_INTERVAL_TICKS, Replay, ReplayPlayer};
+use shared::tick::{TICK_DT, TICK_RATE};
+
+use crate::history::SnapshotHistory;
+use crate::input::{Arrival, InputBuffer, TimedInput};
+use crate::interest::Interest;
+
+// Two seconds of past states, capped so a crowded arena cannot blow up memory
+pub const HISTORY_TICKS: usize = 2 * TICK_RATE as usize;
+pub const HISTORY_MAX_BYTES: usize = 4 * 1024 * 1024;
+
+// Furthest back a placement may be applied, anything older is applied now.
+// Keeps a high-ping player from rewriting too much of what the opponent saw.
+pub const MAX_REWIND_TICKS: u64 = 12;
+// Late placements applied per client and second at most. Each costs a state
+// clone and up to MAX_REWIND_TICKS of re-simulation, and elixir keeps honest
+// clients well under this.
+pub const MAX_REWINDS_PER_SECOND: usize = 4;
+
+// Views kept per client as delta baselines, about a second's worth
+pub const VIEW_HISTORY: usize = TICK_RATE as usize;
+
+// How long a dropped player's slot is held for a rejoin before they forfeit
+pub const RECONNECT_GRACE_TICKS: u64 = 30 * TICK_RATE as u64;
+// Cards every player holds until decks exist
+pub const HAND_SIZE: usize = 4;
+
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub player_id: u32,
+    // Proves ownership of the slot when rejoining from a new connection
+    pub token: u64,
+    // Tick the connection dropped on, while the slot waits for a rejoin
+    pub away_since: Option<u64>,
+    pub hand: Vec<u32>,
+    // Placements waiting for their tick, also decides the `ack` in snapshots
+    pub inputs: InputBuffer,
+    // What this client sees of the match
+    pub interest: Interest,
+    // Views sent to this client and not yet superseded by an ack, oldest first.
+    // Deltas are taken against these rather than the match history, so they are
+    // exactly what the client holds even after a rewind rewrote the history.
+    pub views: VecDeque<GameState>,
+    // Newest snapshot the client confirmed, the baseline for its deltas
+    pub acked_tick: Option<u64>,
+    // Sequences of placements that did not apply, told to the client next tick
+    pub rejected: Vec<u32>,
+    // Ticks this client's late placements rewound the match on, the last second
+    rewinds: VecDeque<u64>,
+}
+
+impl Peer {
+    pub fn welcome(&self) -> ServerMessage {
+        ServerMessage::Welcome {
+            player_id: self.player_id,
+            token: self.token,
+            hand: self.hand.clone(),
+        }
+    }
+
+    // Whether a late placement on `tick` may still rewind the match
+    fn allow_rewind(&mut self, tick: u64) -> bool {
+        while self
+            .rewinds
+            .front()
+            .is_some_and(|&t| t + TICK_RATE as u64 <= tick)
+        {
+            self.rewinds.pop_front();
+        }
+        if self.rewinds.len() >= MAX_REWINDS_PER_SECOND {
+            return false;
+        }
+        self.rewinds.push_back(tick);
+        true
+    }
+}
+
+// A card placement as applied to the simulation, recorded so re-simulation after
+// a rewind replays the same inputs
+#[derive(Debug, Clone, Copy)]
+pub struct PlaceCard {
+    pub player_id: u32,
+    pub sequence: u32,
+    pub card_id: u32,
+    pub x: f32,
+    pub y: f32,
+}
+
+impl PlaceCard {
+    pub fn new(player_id: u32, input: TimedInput) -> Self {
+        PlaceCard {
+            player_id,
+            sequence: input.sequence,
+            card_id: input.card_id,
+            x: input.x,
+            y: input.y,
+        }
+    }
+}
+
+impl From<PlaceCard> for Action {
+    fn from(input: PlaceCard) -> Self {
+        Action::PlaceCard {
+            player_id: input.player_id,
+            card_id: input.card_id,
+            x: input.x,
+            y: input.y,
+        }
+    }
+}
+
+// An action on the match timeline. Placements keep the client's sequence, so one
+// that a rewind undoes can be rejected to the client that sent it.
+#[derive(Debug, Clone, Copy)]
+struct Timed {
+    tick: u64,
+    action: Action,
+    sequence: Option<u32>,
+}
+
+pub struct Match {
+    pub state: GameState,
+    pub history: SnapshotHistory,
+    pub peers: Vec<Peer>,
+    // Actions by the tick they were applied on, oldest first
+    inputs: VecDeque<Timed>,
+    events: Vec<GameEvent>,
+    // Ticks no rewind can reach any more, recorded before their inputs are
+    // forgotten
+    replay: Replay,
+    // First tick not in `replay` yet
+    recorded_until: u64,
+}
+
+impl Match {
+    pub fn new(seed: u64) -> Self {
+        let state = GameState::for_match(seed);
+        let mut history = SnapshotHistory::new(HISTORY_TICKS, HISTORY_MAX_BYTES);
+        history.push(state.clone());
+
+        Match {
+            state,
+            history,
+            peers: Vec::new(),
+            inputs: VecDeque::new(),
+            events: Vec::new(),
+            replay: Replay::new(seed),
+            recorded_until: 0,
+        }
+    }
+
+    pub fn join(&mut self, addr: SocketAddr) -> &Peer {
+        if let Some(index) = self.peers.iter().position(|p| p.addr == addr) {
+            return &self.peers[index];
+        }
+
+        // Players who left keep their side of the arena, so ids are never reused
+        let player_id = self.state.players.iter().map(|p| p.id).max().unwrap_or(0) + 1;
+        let hand: Vec<u32> = self
+            .state
+            .cards
+            .iter()
+            .map(|c| c.id)
+            .take(HAND_SIZE)
+            .collect();
+        self.peers.push(Peer {
+            addr,
+            player_id,
+            token: random_u64(),
+            away_since: None,
+            hand: hand.clone(),
+            inputs: InputBuffer::default(),
+            interest: Interest::player(player_id),
+            views: VecDeque::new(),
+            acked_tick: None,
+            rejected: Vec::new(),
+            rewinds: VecDeque::new(),
+        });
+        self.state.add_player(player_id);
+        // Replaces the recorded state of the current tick, rewinds to it must see
+        // the player too
+        self.history.push(self.state.clone());
+        self.replay.header.players.push(ReplayPlayer {
+            player_id,
+            deck: hand,
+        });
+        &self.peers[self.peers.len() - 1]
+    }
+
+    // Hands a held slot to a new connection. Returns the peer and the address it
+    // was using before, whose session should be closed if still open.
+    pub fn rejoin(&mut self, addr: SocketAddr, token: u64) -> Option<(&Peer, SocketAddr)> {
+        let peer = self.peers.iter_mut().find(|p| p.token == token)?;
+        let previous = peer.addr;
+        peer.addr = addr;
+        peer.away_since = None;
+        // The new client starts from a full snapshot and its own sequence numbers
+        peer.views.clear();
+        peer.acked_tick = None;
+        peer.inputs = InputBuffer::default();
+        Some((peer, previous))
+    }
+
+    // The connection dropped without a goodbye, the slot is held for a rejoin
+    pub fn disconnect(&mut self, addr: SocketAddr) {
+        let tick = self.state.tick;
+        if let Some(peer) = self.peers.iter_mut().find(|p| p.addr == addr) {
+            peer.away_since.get_or_insert(tick);
+        }
+    }
+
+    // Leaving on purpose forfeits. The player's units and towers stay in the
+    // match, only the peer goes.
+    pub fn leave(&mut self, addr: SocketAddr) -> Option<u32> {
+        let index = self.peers.iter().position(|p| p.addr == addr)?;
+        let player_id = self.peers.remove(index).player_id;
+        self.apply_now(Action::Forfeit { player_id }, None);
+        Some(player_id)
+    }
+
+    pub fn player_id(&self, addr: SocketAddr) -> Option<u32> {
+        self.peers
+            .iter()
+            .find(|p| p.addr == addr)
+            .map(|p| p.player_id)
+    }
+
+    // Buffers a placement until the tick it targets, late ones go through lag
+    // compensation right away
+    pub fn receive_input(&mut self, addr: SocketAddr, input: TimedInput) -> Option<Arrival> {
+        let current = self.state.tick;
+        let peer = self.peers.iter_mut().find(|p| p.addr == addr)?;
+        let player_id = peer.player_id;
+        let arrival = peer.inputs.push(input, current);
+        let rewind = arrival == Arrival::Late && peer.allow_rewind(current);
+        match arrival {
+            Arrival::Late if rewind => {
+                let applied = self.place_card(input.tick, PlaceCard::new(player_id, input));
+                self.settle(player_id, input.sequence, applied);
+            }
+            // Over the rewind limit, rejected like one that did not apply
+            Arrival::Late | Arrival::Dropped => self.settle(player_id, input.sequence, false),
+            Arrival::Buffered | Arrival::Duplicate => {}
+        }
+        Some(arrival)
+    }
+
+    pub fn acknowledge_snapshot(&mut self, addr: SocketAddr, tick: u64) {
+        if let Some(peer) = self.peers.iter_mut().find(|p| p.addr == addr)
+            && peer.acked_tick.is_none_or(|acked| acked < tick)
+            && peer.views.iter().any(|view| view.tick == tick)
+        {
+            peer.acked_tick = Some(tick);
+            // Older views can no longer be picked as a baseline
+            while peer.views.front().is_some_and(|view| view.tick < tick) {
+                peer.views.pop_front();
+            }
+        }
+    }
+
+    pub fn tick(&mut self) -> Vec<GameEvent> {
+        let expired: Vec<u32> = self
+            .peers
+            .extract_if(.., |p| {
+                p.away_since
+                    .is_some_and(|since| self.state.tick - since >= RECONNECT_GRACE_TICKS)
+            })
+            .map(|p| p.player_id)
+            .collect();
+        for player_id in expired {
+            self.apply_now(Action::Forfeit { player_id }, None);
+        }
+
+        // Placements targeting the current tick go in before it is simulated, the
+        // same point the client applied them when predicting
+        let current = self.state.tick;
+        for i in 0..self.peers.len() {
+            let player_id = self.peers[i].player_id;
+            for input in self.peers[i].inputs.take_due(current) {
+                let place = PlaceCard::new(player_id, input);
+                let applied = self.apply_now(place.into(), Some(input.sequence));
+                self.settle(player_id, input.sequence, applied);
+            }
+        }
+
+        let mut events = std::mem::take(&mut self.events);
+        events.extend(self.state.update(TICK_DT));
+        self.history.push(self.state.clone());
+
+        // Anything before the furthest a late input may rewind to is final
+        let oldest = self.history.oldest_tick().unwrap_or(0);
+        self.record_until(self.state.tick.saturating_sub(MAX_REWIND_TICKS).max(oldest));
+        while self.inputs.front().is_some_and(|input| input.tick < oldest) {
+            self.inputs.pop_front();
+        }
+        events
+    }
+
+    // Applies a placement at the tick the client saw it, so high-ping players are
+    // validated against the state they were looking at. The match is rewound to
+    // that tick and re-simulated forward with every other recorded input. Those
+    // that no longer apply are taken out of the match and rejected.
+    pub fn place_card(&mut self, client_tick: u64, input: PlaceCard) -> bool {
+        let current = self.state.tick;
+        let earliest = current
+            .saturating_sub(MAX_REWIND_TICKS)
+            .max(self.history.oldest_tick().unwrap_or(current));
+        let target = client_tick.clamp(earliest, current);
+
+        let sequence = Some(input.sequence);
+        let input = Action::from(input);
+        let Some(past) = self.history.get(target).filter(|_| target < current) else {
+            return self.apply_now(input, sequence);
+        };
+
+        let mut state = past.clone();
+        // Inputs already applied on the target tick go first, they arrived
+        // earlier. Nothing leaves the timeline unless the late one applies.
+        let failed: Vec<usize> = (0..self.inputs.len())
+            .filter(|&i| self.inputs[i].tick == target && !self.inputs[i].action.apply(&mut state))
+            .collect();
+        if !input.apply(&mut state) {
+            return false;
+        }
+        let mut undone: Vec<Timed> = failed
+            .into_iter()
+            .rev()
+            .filter_map(|i| self.inputs.remove(i))
+            .collect();
+        undone.reverse();
+        // Only the late input's own events are new, the rest were already reported
+        let mut events = state.take_events();
+
+        let position = self.inputs.partition_point(|i| i.tick <= target);
+        let timed = Timed {
+            tick: target,
+            action: input,
+            sequence,
+        };
+        self.inputs.insert(position, timed);
+
+        // A match that ends no longer advances
+        while state.tick < current && !state.ended {
+            state.update(TICK_DT);
+            self.history.push(state.clone());
+            self.replay_inputs(&mut state, &mut undone);
+            state.take_events();
+        }
+        // Whatever came after an earlier end never happened
+        let tick = state.tick;
+        let after = self.inputs.partition_point(|i| i.tick <= tick);
+        undone.extend(self.inputs.split_off(after));
+
+        for input in undone {
+            if let (Action::PlaceCard { player_id, .. }, Some(sequence)) =
+                (input.action, input.sequence)
+            {
+                self.settle(player_id, sequence, false);
+            }
+        }
+        // Keep what was raised on the live state since the last tick, the replayed
+        // copies of those events were discarded above
+        self.events.extend(self.state.take_events());
+        self.events.append(&mut events);
+        self.state = state;
+        true
+    }
+
+    // Applies the recorded inputs of the state's tick again, moving those that
+    // fail now into `undone`
+    fn replay_inputs(&mut self, state: &mut GameState, undone: &mut Vec<Timed>) {
+        let tick = state.tick;
+        self.inputs.retain(|input| {
+            if input.tick != tick || input.action.apply(state) {
+                return true;
+            }
+            undone.push(*input);
+            false
+        });
+    }
+
+    fn apply_now(&mut self, action: Action, sequence: Option<u32>) -> bool {
+        if !action.apply(&mut self.state) {
+            return false;
+        }
+        self.inputs.push_back(Timed {
+            tick: self.state.tick,
+            action,
+            sequence,
+        });
+        true
+    }
+
+    // Lets the placement count towards the client's ack, and tells the client
+    // if it did not apply
+    fn settle(&mut self, player_id: u32, sequence: u32, applied: bool) {
+        if let Some(peer) = self.peers.iter_mut().find(|p| p.player_id == player_id) {
+            peer.inputs.settle(sequence);
+            if !applied {
+                peer.rejected.push(sequence);
+            }
+        }
+    }
+
+    // The match so far, with ticks a rewind could still change as they are now
+    pub fn replay(&self) -> Replay {
+        let mut replay = self.replay.clone();
+        let pending = self
+            .inputs
+            .iter()
+            .filter(|input| input.tick >= self.recorded_until);
+        replay
+            .actions
+            .extend(pending.map(|input| (input.tick, input.action)));
+        replay.end_tick = self.state.tick;
+        replay.end_checksum = self.state.checksum();
+        replay
+    }
+
+    // Moves the inputs and checksums of every tick before `tick` into the replay
+    fn record_until(&mut self, tick: u64) {
+        let from = self.recorded_until;
+        if tick <= from {
+            return;
+        }
+        let inputs = self
+            .inputs
+            .iter()
+            .filter(|input| (from..tick).contains(&input.tick));
+        self.replay
+            .actions
+            .extend(inputs.map(|input| (input.tick, input.action)));
+        // States the history already dropped go without
+        for checked in (from..tick).filter(|t| t.is_multiple_of(CHECKSUM_INTERVAL_TICKS)) {
+            if let Some(state) = self.history.get(checked) {
+                self.replay.checksums.push((checked, state.checksum()));
+            }
+        }
+        self.recorded_until = tick;
+    }
+
+    // Each client's view of the current tick, as a delta against the view it
+    // acknowledged last or in full when that view is gone
+    pub fn snapshots(&mut self) -> Vec<(SocketAddr, ServerMessage)> {
+        let state = &self.state;
+        self.peers
+            .iter_mut()
+            .filter(|peer| peer.away_since.is_none())
+            .map(|peer| {
+                let (view, outdated) = peer.interest.view(state, peer.views.back());
+                let baseline = peer
+                    .acked_tick
+                    .and_then(|tick| peer.views.iter().find(|v| v.tick == tick));
+                let payload = match baseline {
+                    Some(baseline) => {
+                        SnapshotPayload::Delta(bitpack::pack(&delta::diff(baseline, &view)))
+                    }
+                    None => SnapshotPayload::Full(bitpack::pack(&view)),
+                };
+                let message = ServerMessage::Snapshot {
+                    tick: view.tick,
+                    // Of the view, the full state never leaves the server
+                    checksum: view.checksum(),
+                    ack: peer.inputs.ack(),
+                    payload,
+                    outdated,
+                };
+
+                if peer.views.len() == VIEW_HISTORY {
+                    peer.views.pop_front();
+                }
+                peer.views.push_back(view);
+                (peer.addr, message)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::UNIT_SPEED;
+
+    const GOBLIN: u32 = 1;
+    const KNIGHT: u32 = 3;
+    const PEKKA: u32 = 0;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    // Two players forty ticks in, with full elixir and nothing placed yet
+    fn started() -> Match {
+        let mut game = Match::new(1);
+        game.join(addr(1));
+        game.join(addr(2));
+        for _ in 0..40 {
+            game.tick();
+        }
+        game
+    }
+
+    fn place(game: &mut Match, sequence: u32, tick: u64, card_id: u32, x: f32) -> Arrival {
+        let input = TimedInput {
+            sequence,
+            tick,
+            card_id,
+            x,
+            y: 600.0,
+        };
+        game.receive_input(addr(1), input).unwrap()
+    }
+
+    // Whether the unit has been walking for `ticks` ticks
+    fn walked(unit: &shared::Unit, ticks: u64) -> bool {
+        (unit.travelled - ticks as f32 * UNIT_SPEED * TICK_DT).abs() < 0.5
+    }
+
+    #[test]
+    fn late_placement_lands_on_the_tick_it_was_made() {
+        let mut game = started();
+        assert_eq!(place(&mut game, 1, 35, GOBLIN, 240.0), Arrival::Late);
+
+        assert!(walked(&game.state.units[0], 5));
+        assert!(game.history.get(35).unwrap().units.is_empty());
+        assert_eq!(game.history.get(36).unwrap().units.len(), 1);
+        assert_eq!(game.replay().actions[0].0, 35);
+    }
+
+    #[test]
+    fn rewinds_go_back_no_further_than_the_limit() {
+        let mut game = started();
+        place(&mut game, 1, 0, GOBLIN, 240.0);
+
+        assert!(walked(&game.state.units[0], MAX_REWIND_TICKS));
+        assert_eq!(game.replay().actions[0].0, 40 - MAX_REWIND_TICKS);
+    }
+
+    #[test]
+    fn earlier_placement_reorders_ids_and_reports_its_own_events() {
+        let mut game = started();
+        place(&mut game, 1, 35, GOBLIN, 100.0);
+        place(&mut game, 2, 30, GOBLIN, 300.0);
+
+        // Ids follow the order on the timeline, not the order of arrival
+        let ids: Vec<(u32, f32)> = game.state.units.iter().map(|u| (u.id, u.x)).collect();
+        assert_eq!(ids, vec![(1, 300.0), (2, 100.0)]);
+        assert!(walked(&game.state.units[0], 10));
+        assert!(walked(&game.state.units[1], 5));
+        let ticks: Vec<u64> = game.replay().actions.iter().map(|(t, _)| *t).collect();
+        assert_eq!(ticks, vec![30, 35]);
+
+        // Each placement is reported once, the replayed one is not repeated
+        let events = game.tick();
+        let played = events
+            .iter()
+            .filter(|e| matches!(e, GameEvent::CardPlayed { .. }))
+            .count();
+        assert_eq!(played, 2);
+    }
+
+    #[test]
+    fn placements_a_rewind_makes_unaffordable_are_rejected() {
+        let mut game = started();
+        place(&mut game, 1, 38, KNIGHT, 100.0);
+        assert!(game.peers[0].rejected.is_empty());
+
+        // The pekka goes first and leaves too little elixir for the knight
+        assert_eq!(place(&mut game, 2, 33, PEKKA, 300.0), Arrival::Late);
+        assert_eq!(game.peers[0].rejected, vec![1]);
+        assert_eq!(game.state.units.len(), 1);
+        assert_eq!(game.state.units[0].x, 300.0);
+        assert_eq!(game.replay().actions.len(), 1);
+
+        // One that cannot be afforded on arrival is rejected too
+        place(&mut game, 3, 39, PEKKA, 100.0);
+        assert_eq!(game.peers[0].rejected, vec![1, 3]);
+        assert_eq!(game.state.units.len(), 1);
+        assert_eq!(game.peers[0].inputs.ack(), 3);
+
+        // A buffered one is acked once its tick came and it was applied
+        assert_eq!(place(&mut game, 4, 42, GOBLIN, 200.0), Arrival::Buffered);
+        game.tick();
+        assert_eq!(game.peers[0].inputs.ack(), 3);
+        game.tick();
+        game.tick();
+        assert_eq!(game.peers[0].inputs.ack(), 4);
+    }
+
+    #[test]
+    fn slot_held_for_the_grace_period_then_forfeited() {
+        let mut game = started();
+        let token = game.peers[0].token;
+        game.disconnect(addr(1));
+        for _ in 0..RECONNECT_GRACE_TICKS {
+            game.tick();
+        }
+        assert_eq!(game.peers.len(), 2);
+        assert!(!game.state.ended);
+
+        let events = game.tick();
+        assert_eq!(game.peers.len(), 1);
+        assert!(game.state.ended);
+        assert_eq!(game.state.winner, Some(game.peers[0].player_id));
+        assert!(events.contains(&GameEvent::MatchEnded {
+            winner: game.state.winner
+        }));
+        assert!(game.rejoin(addr(3), token).is_none());
+    }
+
+    #[test]
+    fn refused_late_placement_leaves_the_timeline_alone() {
+        let mut game = started();
+        // Recorded on tick 35, but no longer applies when replayed there
+        let stale = PlaceCard {
+            player_id: 1,
+            sequence: 7,
+            card_id: 99,
+            x: 100.0,
+            y: 600.0,
+        };
+        let position = game.inputs.partition_point(|i| i.tick <= 35);
+        game.inputs.insert(
+            position,
+            Timed {
+                tick: 35,
+                action: Action::from(stale),
+                sequence: Some(7),
+            },
+        );
+
+        // No such card, nothing is rewound and nothing is rejected in its wake
+        assert_eq!(place(&mut game, 1, 35, 99, 240.0), Arrival::Late);
+        assert_eq!(game.peers[0].rejected, vec![1]);
+        assert!(game.inputs.iter().any(|i| i.sequence == Some(7)));
+    }
+
+    #[test]
+    fn late_placements_over_the_rewind_limit_are_rejected() {
+        let mut game = started();
+        let mut sequence = 0;
+        for _ in 0..MAX_REWINDS_PER_SECOND {
+            sequence += 1;
+            assert_eq!(place(&mut game, sequence, 39, GOBLIN, 100.0), Arrival::Late);
+        }
+        assert!(game.peers[0].rejected.is_empty());
+        sequence += 1;
+        assert_eq!(place(&mut game, sequence, 39, GOBLIN, 100.0), Arrival::Late);
+        assert_eq!(game.peers[0].rejected, vec![sequence]);
+        assert_eq!(game.peers[0].inputs.ack(), sequence);
+
+        // A second later the client may rewind again
+        for _ in 0..TICK_RATE {
+            game.tick();
+        }
+        let current = game.state.tick;
+        sequence += 1;
+        assert_eq!(
+            place(&mut game, sequence, current - 1, GOBLIN, 100.0),
+            Arrival::Late
+        );
+        assert_eq!(game.peers[0].rejected, vec![sequence - 1]);
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+
+use shared::GameState;
+
+// Bounded ring buffer of past authoritative states keyed by tick. Rewinding for
+// lag compensation, delta baselines and reconnects all read from here.
+pub struct SnapshotHistory {
+    snapshots: VecDeque<(GameState, usize)>,
+    capacity: usize,
+    max_bytes: usize,
+    bytes: usize,
+}
+
+impl SnapshotHistory {
+    // Keeps at most `capacity` snapshots and roughly `max_bytes` of encoded state,
+    // whichever limit is hit first. The newest snapshot is never evicted.
+    pub fn new(capacity: usize, max_bytes: usize) -> Self {
+        SnapshotHistory {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            max_bytes,
+            bytes: 0,
+        }
+    }
+
+    pub fn push(&mut self, state: GameState) {
+        // Re-simulating an older tick replaces everything recorded after it
+        self.truncate_from(state.tick);
+
+        let size = bincode::serialized_size(&state).unwrap_or(0) as usize;
+        self.bytes += size;
+        self.snapshots.push_back((state, size));
+
+        while self.snapshots.len() > 1
+            && (self.snapshots.len() > self.capacity || self.bytes > self.max_bytes)
+        {
+            if let Some((_, size)) = self.snapshots.pop_front() {
+                self.bytes -= size;
+            }
+        }
+    }
+
+    pub fn get(&self, tick: u64) -> Option<&GameState> {
+        self.index_of(tick)
+            .ok()
+            .map(|index| &self.snapshots[index].0)
+    }
+
+    pub fn latest(&self) -> Option<&GameState> {
+        self.snapshots.back().map(|(state, _)| state)
+    }
+
+    pub fn oldest_tick(&self) -> Option<u64> {
+        self.snapshots.front().map(|(state, _)| state.tick)
+    }
+
+    pub fn latest_tick(&self) -> Option<u64> {
+        self.snapshots.back().map(|(state, _)| state.tick)
+    }
+
+    // Drops every snapshot at or after `tick`
+    pub fn truncate_from(&mut self, tick: u64) {
+        let index = match self.index_of(tick) {
+            Ok(index) | Err(index) => index,
+        };
+        for (_, size) in self.snapshots.drain(index..) {
+            self.bytes -= size;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn index_of(&self, tick: u64) -> Result<usize, usize> {
+        self.snapshots
+            .binary_search_by_key(&tick, |(state, _)| state.tick)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(tick: u64) -> GameState {
+        let mut state = GameState::for_match(1);
+        state.add_player(1);
+        state.tick = tick;
+        state
+    }
+
+    fn ticks(history: &SnapshotHistory) -> Vec<u64> {
+        (0..20).filter(|t| history.get(*t).is_some()).collect()
+    }
+
+    #[test]
+    fn oldest_go_once_over_capacity() {
+        let mut history = SnapshotHistory::new(3, usize::MAX);
+        for tick in 0..5 {
+            history.push(state(tick));
+        }
+        assert_eq!(ticks(&history), vec![2, 3, 4]);
+        assert_eq!(
+            (history.oldest_tick(), history.latest_tick()),
+            (Some(2), Some(4))
+        );
+        assert_eq!(history.latest().unwrap().tick, 4);
+    }
+
+    #[test]
+    fn oldest_go_once_over_the_byte_budget() {
+        let size = bincode::serialized_size(&state(0)).unwrap() as usize;
+        let mut history = SnapshotHistory::new(10, 2 * size + size / 2);
+        for tick in 0..5 {
+            history.push(state(tick));
+        }
+        assert_eq!(ticks(&history), vec![3, 4]);
+        assert_eq!(history.bytes(), 2 * size);
+
+        // The newest is kept even when it alone is over the budget
+        let mut tiny = SnapshotHistory::new(10, 1);
+        tiny.push(state(0));
+        tiny.push(state(1));
+        assert_eq!(ticks(&tiny), vec![1]);
+        assert_eq!(tiny.bytes(), size);
+    }
+
+    #[test]
+    fn truncating_drops_the_tick_and_everything_after() {
+        let mut history = SnapshotHistory::new(10, usize::MAX);
+        for tick in 0..6 {
+            history.push(state(tick));
+        }
+        let size = history.bytes() / 6;
+        history.truncate_from(4);
+        assert_eq!(ticks(&history), vec![0, 1, 2, 3]);
+        assert_eq!(history.bytes(), 4 * size);
+        // A tick past the newest leaves everything
+        history.truncate_from(9);
+        assert_eq!(history.len(), 4);
+
+        // Pushing an older tick replaces it and what came after
+        history.push(state(2));
+        assert_eq!(ticks(&history), vec![0, 1, 2]);
+        history.truncate_from(0);
+        assert!(history.is_empty());
+        assert_eq!(history.bytes(), 0);
+    }
+}
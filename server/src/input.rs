@@ -0,0 +1,239 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use shared::protocol::BufferHealth;
+use shared::tick::TICK_RATE;
+
+// Furthest ahead of the server an input may target. Anything further is from a
+// client whose clock has run away and is dropped instead of held for seconds.
+pub const MAX_LEAD_TICKS: u64 = TICK_RATE as u64;
+// Placements held per client at most
+pub const MAX_BUFFERED_INPUTS: usize = 32;
+// Furthest past the ack a sequence is tracked. No client has that many
+// placements in flight, so anything further is dropped and never settled.
+pub const MAX_SEQUENCE_GAP: u32 = 2 * MAX_BUFFERED_INPUTS as u32;
+// Weight of the newest arrival in the smoothed lead
+const LEAD_SMOOTHING: f32 = 0.1;
+
+// A placement as received, waiting for the tick it targets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimedInput {
+    pub sequence: u32,
+    pub tick: u64,
+    pub card_id: u32,
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    // Held until the server reaches its tick
+    Buffered,
+    // Its tick has already been simulated. The caller applies it through lag
+    // compensation, rewinding at most MAX_REWIND_TICKS and applying anything
+    // older on the oldest tick it can still rewind to.
+    Late,
+    // Already received
+    Duplicate,
+    // Too far ahead in ticks or sequence, or over the buffer limit, never
+    // applied. The caller rejects it to the client.
+    Dropped,
+}
+
+// Per-client placements keyed by the tick they target, applied by the match
+// right before that tick is simulated
+#[derive(Debug, Default)]
+pub struct InputBuffer {
+    inputs: BTreeMap<(u64, u32), TimedInput>,
+    // Every sequence up to this one was applied or rejected, None until the
+    // first placement arrives
+    acked: Option<u32>,
+    // Sequences past `acked` that arrived, and those of them already settled
+    received: BTreeSet<u32>,
+    settled: BTreeSet<u32>,
+    health: BufferHealth,
+}
+
+impl InputBuffer {
+    pub fn push(&mut self, input: TimedInput, current_tick: u64) -> Arrival {
+        let sequence = input.sequence;
+        if self
+            .acked
+            .is_some_and(|acked| sequence > acked.saturating_add(MAX_SEQUENCE_GAP))
+        {
+            self.health.dropped += 1;
+            return Arrival::Dropped;
+        }
+        if self.acked.is_some_and(|acked| sequence <= acked) || !self.received.insert(sequence) {
+            self.health.duplicates += 1;
+            return Arrival::Duplicate;
+        }
+        // A new client or a rejoined one numbers on from wherever it was
+        let first = self.acked.is_none();
+        if first {
+            self.acked = Some(sequence.saturating_sub(1));
+        }
+
+        let lead = input.tick as f32 - current_tick as f32;
+        if first {
+            self.health.average_lead = lead;
+        } else {
+            self.health.average_lead += (lead - self.health.average_lead) * LEAD_SMOOTHING;
+        }
+
+        if input.tick < current_tick {
+            self.health.late += 1;
+            return Arrival::Late;
+        }
+        if input.tick > current_tick + MAX_LEAD_TICKS || self.inputs.len() >= MAX_BUFFERED_INPUTS {
+            self.health.dropped += 1;
+            return Arrival::Dropped;
+        }
+        self.inputs.insert((input.tick, input.sequence), input);
+        Arrival::Buffered
+    }
+
+    // Removes and returns every input targeting `tick` or earlier, in tick then
+    // sequence order
+    pub fn take_due(&mut self, tick: u64) -> Vec<TimedInput> {
+        let later = self.inputs.split_off(&(tick + 1, 0));
+        std::mem::replace(&mut self.inputs, later)
+            .into_values()
+            .collect()
+    }
+
+    // Called once the match applied or rejected the placement
+    pub fn settle(&mut self, sequence: u32) {
+        let Some(acked) = &mut self.acked else {
+            return;
+        };
+        if sequence <= *acked || sequence > acked.saturating_add(MAX_SEQUENCE_GAP) {
+            return;
+        }
+        self.settled.insert(sequence);
+        while self.settled.remove(&(*acked + 1)) {
+            *acked += 1;
+            self.received.remove(acked);
+        }
+    }
+
+    // Highest sequence with it and every placement before it settled, the `ack`
+    // a client may drop its predictions by. One still buffered or missing holds
+    // back everything after it.
+    pub fn ack(&self) -> u32 {
+        self.acked.unwrap_or(0)
+    }
+
+    pub fn health(&self) -> BufferHealth {
+        BufferHealth {
+            buffered: self.inputs.len() as u32,
+            ..self.health
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(sequence: u32, tick: u64) -> TimedInput {
+        TimedInput {
+            sequence,
+            tick,
+            card_id: 1,
+            x: 240.0,
+            y: 600.0,
+        }
+    }
+
+    #[test]
+    fn each_arrival_is_classified() {
+        let mut buffer = InputBuffer::default();
+        assert_eq!(buffer.push(input(1, 12), 10), Arrival::Buffered);
+        assert_eq!(buffer.push(input(2, 8), 10), Arrival::Late);
+        assert_eq!(buffer.push(input(2, 12), 10), Arrival::Duplicate);
+        assert_eq!(buffer.push(input(1, 12), 10), Arrival::Duplicate);
+        assert_eq!(
+            buffer.push(input(3, 11 + MAX_LEAD_TICKS), 10),
+            Arrival::Dropped
+        );
+        assert_eq!(
+            buffer.push(input(4, 10 + MAX_LEAD_TICKS), 10),
+            Arrival::Buffered
+        );
+
+        let health = buffer.health();
+        assert_eq!((health.buffered, health.late), (2, 1));
+        assert_eq!((health.dropped, health.duplicates), (1, 2));
+        assert!(buffer.take_due(11).is_empty());
+        assert_eq!(buffer.take_due(12), vec![input(1, 12)]);
+    }
+
+    #[test]
+    fn full_buffer_drops_the_rest() {
+        let mut buffer = InputBuffer::default();
+        for sequence in 1..=MAX_BUFFERED_INPUTS as u32 {
+            assert_eq!(buffer.push(input(sequence, 20), 10), Arrival::Buffered);
+        }
+        let over = MAX_BUFFERED_INPUTS as u32 + 1;
+        assert_eq!(buffer.push(input(over, 20), 10), Arrival::Dropped);
+        assert_eq!(buffer.take_due(20).len(), MAX_BUFFERED_INPUTS);
+    }
+
+    #[test]
+    fn ack_stops_below_the_first_unsettled_input() {
+        let mut buffer = InputBuffer::default();
+        assert_eq!(buffer.ack(), 0);
+        buffer.push(input(1, 10), 10);
+        buffer.push(input(2, 15), 10);
+        assert_eq!(buffer.push(input(3, 9), 10), Arrival::Late);
+        buffer.push(input(4, 12), 10);
+        buffer.settle(3);
+        assert_eq!(buffer.ack(), 0);
+
+        // Sequence 2 still waits for tick 15, 3 and 4 are behind it
+        for due in buffer.take_due(12) {
+            buffer.settle(due.sequence);
+        }
+        assert_eq!(buffer.ack(), 1);
+        for due in buffer.take_due(15) {
+            buffer.settle(due.sequence);
+        }
+        assert_eq!(buffer.ack(), 4);
+
+        // Dropped is not applied, it counts once the client was told
+        assert_eq!(buffer.push(input(5, 100), 20), Arrival::Dropped);
+        assert_eq!(buffer.ack(), 4);
+        buffer.settle(5);
+        assert_eq!(buffer.ack(), 5);
+    }
+
+    #[test]
+    fn rejoined_client_numbers_on() {
+        let mut buffer = InputBuffer::default();
+        assert_eq!(buffer.push(input(7, 12), 10), Arrival::Buffered);
+        assert_eq!(buffer.ack(), 6);
+        assert_eq!(buffer.push(input(5, 12), 10), Arrival::Duplicate);
+        // One overtaken by a later one is still applied and waited for
+        assert_eq!(buffer.push(input(9, 12), 10), Arrival::Buffered);
+        assert_eq!(buffer.push(input(8, 12), 10), Arrival::Buffered);
+        buffer.settle(7);
+        buffer.settle(9);
+        assert_eq!(buffer.ack(), 7);
+        buffer.settle(8);
+        assert_eq!(buffer.ack(), 9);
+    }
+
+    #[test]
+    fn sequences_far_past_the_ack_are_not_tracked() {
+        let mut buffer = InputBuffer::default();
+        buffer.push(input(1, 10), 10);
+        let far = MAX_SEQUENCE_GAP + 1;
+        assert_eq!(buffer.push(input(far, 12), 10), Arrival::Dropped);
+        buffer.settle(far);
+        assert!(buffer.received.len() == 1 && buffer.settled.is_empty());
+
+        assert_eq!(buffer.push(input(far - 1, 12), 10), Arrival::Buffered);
+        buffer.settle(1);
+        assert_eq!(buffer.ack(), 1);
+    }
+}
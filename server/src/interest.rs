@@ -0,0 +1,289 @@
+use std::collections::{HashMap, HashSet};
+
+use shared::protocol::Outdated;
+use shared::{GameState, Unit};
+
+// Units refreshed per snapshot at most. Roughly a kilobyte of unit deltas, so a
+// crowded arena stays within one packet per tick per client.
+pub const DEFAULT_UNIT_BUDGET: usize = 48;
+// Units closer than this to one of the viewer's buildings gain priority faster
+pub const RELEVANCE_DISTANCE: f32 = 400.0;
+// Extra priority per tick for a unit right on top of the viewer's buildings
+const NEARBY_BONUS: f32 = 2.0;
+
+// Decides what one client gets to see of the match: which units are visible,
+// which fields are hidden, and which units are refreshed this tick when there
+// are more than the budget allows
+#[derive(Debug, Clone)]
+pub struct Interest {
+    // Player whose view this is, None for a spectator that sees everything
+    viewer: Option<u32>,
+    // Enemy units further than this from every friendly unit and tower are hidden
+    fog_radius: Option<f32>,
+    unit_budget: usize,
+    // Accumulated priority of units that were left stale, reset once sent
+    priorities: HashMap<u32, f32>,
+    // Units this client has been sent, so fog only flags units it knows about
+    seen: HashSet<u32>,
+}
+
+impl Interest {
+    pub fn player(player_id: u32) -> Self {
+        Interest {
+            viewer: Some(player_id),
+            fog_radius: None,
+            unit_budget: DEFAULT_UNIT_BUDGET,
+            priorities: HashMap::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn spectator() -> Self {
+        Interest {
+            viewer: None,
+            ..Self::player(0)
+        }
+    }
+
+    pub fn with_fog(mut self, radius: f32) -> Self {
+        self.fog_radius = Some(radius);
+        self
+    }
+
+    pub fn with_unit_budget(mut self, unit_budget: usize) -> Self {
+        self.unit_budget = unit_budget.max(1);
+        self
+    }
+
+    // The state as this client should receive it, and the units in it that are
+    // not up to date. Units over budget keep the values from `previous`, the last
+    // view sent, until their priority wins.
+    pub fn view(
+        &mut self,
+        state: &GameState,
+        previous: Option<&GameState>,
+    ) -> (GameState, Outdated) {
+        let mut view = state.clone();
+        view.take_events();
+        let mut outdated = Outdated::default();
+
+        if let Some(viewer) = self.viewer {
+            // Opponents' elixir would tell the player what they can afford
+            for player in view.players.iter_mut().filter(|p| p.id != viewer) {
+                player.elixir = 0;
+                player.elixir_timer = 0.0;
+            }
+        }
+        view.units.retain(|unit| {
+            let visible = self.is_visible(unit, state);
+            if !visible && self.seen.contains(&unit.id) {
+                outdated.fogged.push(unit.id);
+            }
+            visible
+        });
+        // Dead units are gone for good, ids are never reused
+        self.seen
+            .retain(|id| state.units.iter().any(|u| u.id == *id));
+        self.seen.extend(view.units.iter().map(|u| u.id));
+
+        if view.units.len() <= self.unit_budget {
+            self.priorities.clear();
+            return (view, outdated);
+        }
+
+        for unit in &view.units {
+            *self.priorities.entry(unit.id).or_default() += self.relevance(unit, state);
+        }
+        let previous: HashMap<u32, &Unit> = previous
+            .map(|p| p.units.iter().map(|u| (u.id, u)).collect())
+            .unwrap_or_default();
+
+        // Units the client has never seen always go out, the rest by priority
+        let mut candidates: Vec<(u32, f32)> = view
+            .units
+            .iter()
+            .map(|unit| {
+                if previous.contains_key(&unit.id) {
+                    (unit.id, self.priorities[&unit.id])
+                } else {
+                    (unit.id, f32::INFINITY)
+                }
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let fresh: HashSet<u32> = candidates
+            .iter()
+            .take(self.unit_budget)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for unit in &mut view.units {
+            if fresh.contains(&unit.id) {
+                self.priorities.insert(unit.id, 0.0);
+            } else if let Some(stale) = previous.get(&unit.id) {
+                *unit = (*stale).clone();
+                outdated.stale.push(unit.id);
+            }
+        }
+        let visible: HashSet<u32> = view.units.iter().map(|u| u.id).collect();
+        self.priorities.retain(|id, _| visible.contains(id));
+        (view, outdated)
+    }
+
+    fn is_visible(&self, unit: &Unit, state: &GameState) -> bool {
+        let (Some(viewer), Some(radius)) = (self.viewer, self.fog_radius) else {
+            return true;
+        };
+        if unit.owner == viewer {
+            return true;
+        }
+        let near = |x: f32, y: f32| (unit.x - x).hypot(unit.y - y) <= radius;
+        state
+            .units
+            .iter()
+            .filter(|u| u.owner == viewer)
+            .any(|u| near(u.x, u.y))
+            || state
+                .towers
+                .iter()
+                .filter(|t| t.owner == viewer)
+                .any(|t| near(t.x, t.y))
+    }
+
+    // Priority gained per tick while stale: units bearing down on the viewer's
+    // towers matter more than ones across the arena
+    fn relevance(&self, unit: &Unit, state: &GameState) -> f32 {
+        let Some(viewer) = self.viewer else {
+            return 1.0;
+        };
+        let distance = state
+            .towers
+            .iter()
+            .filter(|t| t.owner == viewer)
+            .map(|t| (unit.x - t.x).hypot(unit.y - t.y))
+            .fold(f32::INFINITY, f32::min);
+        let closeness = (1.0 - distance / RELEVANCE_DISTANCE).max(0.0);
+        1.0 + NEARBY_BONUS * closeness
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{Player, Tower, cards};
+
+    fn arena(units: u32) -> GameState {
+        let mut state = GameState::with_seed(1);
+        state.players = vec![Player::new(1), Player::new(2)];
+        state.cards = cards::catalog();
+        for (id, owner, y) in [(1, 1, 760.0), (2, 2, 90.0)] {
+            state.towers.push(Tower {
+                id,
+                owner,
+                health: 3000,
+                x: 240.0,
+                y,
+                damage: 80,
+                attack_cooldown: 0.0,
+            });
+        }
+        for _ in 0..units {
+            state.spawn_unit(2, 1, 240.0, 100.0);
+        }
+        state
+    }
+
+    #[test]
+    fn hides_opponent_elixir_and_fogged_units() {
+        let state = arena(1);
+        let (view, _) = Interest::player(1).with_fog(100.0).view(&state, None);
+        assert!(view.units.is_empty());
+        assert_eq!(view.players[1].elixir, 0);
+        assert_eq!(view.players[0].elixir, state.players[0].elixir);
+
+        let (spectator, _) = Interest::spectator().view(&state, None);
+        assert_eq!(spectator.units.len(), 1);
+        assert_eq!(spectator.players, state.players);
+    }
+
+    #[test]
+    fn stale_units_take_turns_under_the_budget() {
+        let mut state = arena(4);
+        let mut interest = Interest::player(1).with_unit_budget(2);
+        let (mut previous, _) = interest.view(&state, None);
+        // Never seen units go out even over budget
+        assert_eq!(previous.units, state.units);
+
+        let mut refreshed = HashSet::new();
+        for _ in 0..2 {
+            state.update(0.1);
+            let (view, _) = interest.view(&state, Some(&previous));
+            let fresh: Vec<u32> = view
+                .units
+                .iter()
+                .filter(|u| state.units.contains(u))
+                .map(|u| u.id)
+                .collect();
+            assert_eq!(fresh.len(), 2);
+            refreshed.extend(fresh);
+            previous = view;
+        }
+        assert_eq!(refreshed.len(), 4);
+    }
+
+    #[test]
+    fn units_left_out_of_date_are_flagged() {
+        let mut state = arena(0);
+        for id in 0..60 {
+            state.units.push(Unit {
+                id,
+                owner: 2,
+                x: 240.0,
+                y: 100.0 + id as f32,
+                health: 100,
+                velocity: 50.0,
+                ..Default::default()
+            });
+        }
+        let mut interest = Interest::player(1);
+        let (previous, outdated) = interest.view(&state, None);
+        assert_eq!(outdated, Outdated::default());
+
+        state.update(0.1);
+        let (view, outdated) = interest.view(&state, Some(&previous));
+        assert_eq!(view.units.len(), 60);
+        assert_eq!(outdated.stale.len(), 60 - DEFAULT_UNIT_BUDGET);
+        // Everything not flagged is exactly what the server has
+        for unit in &view.units {
+            let current = state.units.iter().find(|u| u.id == unit.id).unwrap();
+            assert_eq!(unit == current, !outdated.stale.contains(&unit.id));
+        }
+    }
+
+    #[test]
+    fn only_units_the_client_has_seen_are_flagged_fogged() {
+        let mut state = arena(0);
+        state.units.push(Unit {
+            id: 1,
+            owner: 2,
+            x: 240.0,
+            y: 700.0,
+            ..Default::default()
+        });
+        let mut interest = Interest::player(1).with_fog(100.0);
+        let (previous, _) = interest.view(&state, None);
+        assert_eq!(previous.units.len(), 1);
+
+        state.units[0].y = 300.0;
+        state.units.push(Unit {
+            id: 2,
+            owner: 2,
+            x: 240.0,
+            y: 300.0,
+            ..Default::default()
+        });
+        let (view, outdated) = interest.view(&state, Some(&previous));
+        assert!(view.units.is_empty());
+        assert_eq!(outdated.fogged, vec![1]);
+    }
+}
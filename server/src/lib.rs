@@ -0,0 +1,260 @@
+pub mod game;
+pub mod history;
+pub mod input;
+pub mod interest;
+pub mod lobby;
+pub mod matches;
+pub mod session;
+pub mod socket;
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use shared::net::{Conditions, DisconnectReason, Endpoint};
+use shared::protocol::{self, ClientMessage, ServerMessage};
+use shared::tick::TICK_DT;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration, Instant};
+
+use input::TimedInput;
+use lobby::Lobby;
+use matches::{Command, MatchEvent, Matches};
+use session::{MAX_SESSIONS, SessionEvent, Sessions};
+use socket::Socket;
+
+fn send(endpoint: &mut Endpoint, message: &ServerMessage) {
+    if !endpoint.send(message.channel(), protocol::encode(message)) {
+        eprintln!("Dropped a message over the maximum message size");
+    }
+}
+
+fn send_to(sessions: &mut Sessions, messages: Vec<(SocketAddr, ServerMessage)>) {
+    for (addr, message) in messages {
+        if let Some(endpoint) = sessions.endpoint_mut(addr) {
+            send(endpoint, &message);
+        }
+    }
+}
+
+async fn send_all(socket: &mut Socket, packets: Vec<(SocketAddr, Vec<u8>)>) {
+    for (addr, packet) in packets {
+        socket.send_to(&packet, addr).await;
+    }
+}
+
+// Everything the socket task does, without the socket: sessions, the lobby and
+// routing messages to matches. Every call returns the datagrams to send, so it
+// runs the same on a real socket and in tests.
+pub struct Server {
+    pub sessions: Sessions,
+    pub lobby: Lobby,
+    pub matches: Matches,
+}
+
+impl Server {
+    pub fn new(matches: Matches) -> Self {
+        Server {
+            sessions: Sessions::new(MAX_SESSIONS),
+            lobby: Lobby::new(),
+            matches,
+        }
+    }
+
+    // Once per tick: starts matches for paired players, then flushes every
+    // session, which also notices timeouts
+    pub fn tick(&mut self, now: f64) -> Vec<(SocketAddr, Vec<u8>)> {
+        for (a, b) in self.lobby.pair(now) {
+            self.start_match([a, b]);
+        }
+        self.matches.flush();
+        let packets = self.sessions.packets(now);
+        self.handle_events();
+        packets
+    }
+
+    // Messages from a match go out with the next `tick`
+    pub fn match_event(&mut self, id: u64, event: MatchEvent) {
+        self.matches.update(id, &event);
+        match event {
+            MatchEvent::Send(messages) => send_to(&mut self.sessions, messages),
+            // The old connection is still open when the client noticed the drop
+            // before we did
+            MatchEvent::Rejoined { addr, previous } if previous != addr => {
+                self.sessions.disconnect(previous);
+            }
+            MatchEvent::Ended => println!("Match {} ended", id),
+            MatchEvent::Rejoined { .. } | MatchEvent::Expired(_) => {}
+        }
+    }
+
+    // Handles a datagram. Returns handshake answers, and pongs which go out
+    // right away since waiting for the next tick would add up to a tick of delay
+    // to the measured round trip.
+    pub fn receive(
+        &mut self,
+        bytes: &[u8],
+        addr: SocketAddr,
+        now: f64,
+    ) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.sessions.receive(bytes, addr, now);
+        let mut packets = self.sessions.replies();
+        self.handle_events();
+        // Stray datagrams and handshakes stop here
+        let Some(endpoint) = self.sessions.endpoint_mut(addr) else {
+            return packets;
+        };
+
+        let matches = &mut self.matches;
+        let lobby = &mut self.lobby;
+        let mut answered_ping = false;
+        let mut opponent = None;
+        for (_, payload) in endpoint.drain_received() {
+            // Ignore anything that is not a valid message
+            let Some(message) = protocol::decode::<ClientMessage>(&payload) else {
+                continue;
+            };
+            // The lobby is only for clients not already in a match
+            let playing = matches.is_playing(addr);
+            match message {
+                ClientMessage::FindMatch { rating } if !playing => {
+                    lobby.enqueue(addr, rating, now);
+                    send(endpoint, &ServerMessage::Queued);
+                }
+                ClientMessage::CreateRoom if !playing => match lobby.create_room(addr) {
+                    Some(code) => send(endpoint, &ServerMessage::RoomCreated { code }),
+                    None => send(endpoint, &ServerMessage::RoomUnavailable),
+                },
+                ClientMessage::JoinRoom { code } if !playing => {
+                    match lobby.join_room(addr, &code) {
+                        Some(host) => opponent = Some(host),
+                        None => send(endpoint, &ServerMessage::RoomUnavailable),
+                    }
+                }
+                ClientMessage::LeaveLobby => lobby.leave(addr),
+                ClientMessage::Rejoin { token } => {
+                    lobby.leave(addr);
+                    // The match answers with a welcome
+                    if !matches.rejoin(addr, token) {
+                        send(endpoint, &ServerMessage::RejoinFailed);
+                    }
+                }
+                ClientMessage::PlaceCard {
+                    sequence,
+                    tick,
+                    card_id,
+                    x,
+                    y,
+                } => {
+                    let input = TimedInput {
+                        sequence,
+                        tick,
+                        card_id,
+                        x,
+                        y,
+                    };
+                    matches.send(addr, Command::Input(addr, input));
+                }
+                ClientMessage::AckSnapshot { tick } => {
+                    matches.send(addr, Command::AckSnapshot(addr, tick));
+                }
+                ClientMessage::Ping { client_time } => {
+                    // Clients in the lobby get tick 0 until their match starts
+                    let pong = ServerMessage::Pong {
+                        client_time,
+                        server_time: now,
+                        server_tick: matches.tick(addr).unwrap_or(0),
+                    };
+                    send(endpoint, &pong);
+                    answered_ping = true;
+                }
+                ClientMessage::FindMatch { .. }
+                | ClientMessage::CreateRoom
+                | ClientMessage::JoinRoom { .. } => {}
+            }
+        }
+
+        if let Some(host) = opponent {
+            self.start_match([host, addr]);
+        }
+        if answered_ping {
+            let flushed = self.sessions.flush(addr, now);
+            packets.extend(flushed.into_iter().map(|p| (addr, p)));
+        }
+        packets
+    }
+
+    // Starts a match between the pair and welcomes both
+    fn start_match(&mut self, players: [SocketAddr; 2]) {
+        let (id, welcomes) = self.matches.start(&players);
+        println!("Match {} between {} and {}", id, players[0], players[1]);
+        send_to(&mut self.sessions, welcomes);
+    }
+
+    fn handle_events(&mut self) {
+        for event in self.sessions.events() {
+            match event {
+                SessionEvent::Connected(addr) => println!("{} connected", addr),
+                SessionEvent::Disconnected(addr, reason) => {
+                    println!("{} disconnected: {:?}", addr, reason);
+                    self.lobby.leave(addr);
+                    // Only a goodbye gives the slot up, a crash or a dropped
+                    // network gets the grace period to rejoin
+                    match reason {
+                        DisconnectReason::Closed | DisconnectReason::Local => {
+                            self.matches.leave(addr)
+                        }
+                        DisconnectReason::TimedOut | DisconnectReason::Denied(_) => {
+                            self.matches.disconnect(addr)
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The socket task: owns the socket and the `Server`, and forwards each client's
+// messages to the task running its match. Matches send their messages back
+// here, they go out with the next flush. `conditions` puts a simulated network
+// between the server and everyone, `replays` is where finished matches are saved.
+pub async fn run(
+    socket: UdpSocket,
+    conditions: Conditions,
+    replays: Option<PathBuf>,
+) -> io::Result<()> {
+    let mut socket = Socket::new(socket, conditions);
+    let started = Instant::now();
+    let (mut matches, mut match_events) = Matches::new();
+    if let Some(dir) = replays {
+        matches.save_replays(dir);
+    }
+    let mut server = Server::new(matches);
+    let mut interval = time::interval(Duration::from_secs_f32(TICK_DT));
+    let mut buf = [0u8; 2048];
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let now = started.elapsed().as_secs_f64();
+                let packets = server.tick(now);
+                send_all(&mut socket, packets).await;
+            }
+            Some((id, event)) = match_events.recv() => server.match_event(id, event),
+            received = socket.recv_from(&mut buf) => {
+                // One bad datagram must not take everyone's matches down, e.g.
+                // Windows reporting an ICMP port unreachable as ConnectionReset
+                let (len, addr) = match received {
+                    Ok(received) => received,
+                    Err(err) => {
+                        eprintln!("Failed to receive: {}", err);
+                        continue;
+                    }
+                };
+                let now = started.elapsed().as_secs_f64();
+                let packets = server.receive(&buf[..len], addr, now);
+                send_all(&mut socket, packets).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use shared::net::random_u64;
+
+// Rating difference accepted right away, widened the longer a player waits so
+// nobody queues forever on a quiet server
+pub const RATING_WINDOW: u32 = 100;
+pub const RATING_WINDOW_GROWTH: f64 = 50.0;
+pub const ROOM_CODE_LENGTH: usize = 5;
+pub const MAX_ROOMS: usize = 256;
+// No 0/O or 1/I, codes get read out loud
+const ROOM_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+#[derive(Debug, Clone, Copy)]
+struct Ticket {
+    addr: SocketAddr,
+    // None plays anyone
+    rating: Option<u32>,
+    queued_at: f64,
+}
+
+// Clients waiting for a match: the public 1v1 queue and private rooms, where a
+// host waits for whoever enters the room's code
+#[derive(Debug, Default)]
+pub struct Lobby {
+    // Oldest first
+    queue: Vec<Ticket>,
+    rooms: HashMap<String, SocketAddr>,
+}
+
+impl Lobby {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn enqueue(&mut self, addr: SocketAddr, rating: Option<u32>, now: f64) {
+        self.leave(addr);
+        self.queue.push(Ticket {
+            addr,
+            rating,
+            queued_at: now,
+        });
+    }
+
+    // Opens a room hosted by `addr` and returns its code, None when every room is
+    // taken
+    pub fn create_room(&mut self, addr: SocketAddr) -> Option<String> {
+        self.leave(addr);
+        if self.rooms.len() >= MAX_ROOMS {
+            return None;
+        }
+        let code = loop {
+            let mut bits = random_u64();
+            let code: String = (0..ROOM_CODE_LENGTH)
+                .map(|_| {
+                    let index = (bits % ROOM_CODE_ALPHABET.len() as u64) as usize;
+                    bits /= ROOM_CODE_ALPHABET.len() as u64;
+                    ROOM_CODE_ALPHABET[index] as char
+                })
+                .collect();
+            if !self.rooms.contains_key(&code) {
+                break code;
+            }
+        };
+        self.rooms.insert(code.clone(), addr);
+        Some(code)
+    }
+
+    // Closes the room and returns its host, who is to play `addr`
+    pub fn join_room(&mut self, addr: SocketAddr, code: &str) -> Option<SocketAddr> {
+        let code = code.trim().to_ascii_uppercase();
+        if self.rooms.get(&code).is_none_or(|&host| host == addr) {
+            return None;
+        }
+        self.leave(addr);
+        self.rooms.remove(&code)
+    }
+
+    // Takes `addr` out of the queue and closes any room it hosts
+    pub fn leave(&mut self, addr: SocketAddr) {
+        self.queue.retain(|t| t.addr != addr);
+        self.rooms.retain(|_, host| *host != addr);
+    }
+
+    pub fn is_waiting(&self, addr: SocketAddr) -> bool {
+        self.queue.iter().any(|t| t.addr == addr) || self.rooms.values().any(|&h| h == addr)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    // Pairs off queued players, longest waiting first, each with the closest
+    // rating inside its window
+    pub fn pair(&mut self, now: f64) -> Vec<(SocketAddr, SocketAddr)> {
+        let mut pairs = Vec::new();
+        let mut i = 0;
+        while i < self.queue.len() {
+            let ticket = self.queue[i];
+            let window =
+                RATING_WINDOW as f64 + RATING_WINDOW_GROWTH * (now - ticket.queued_at).max(0.0);
+            let opponent = self.queue[i + 1..]
+                .iter()
+                .enumerate()
+                .filter_map(|(j, other)| {
+                    let distance = match (ticket.rating, other.rating) {
+                        (Some(a), Some(b)) => a.abs_diff(b),
+                        _ => 0,
+                    };
+                    (distance as f64 <= window).then_some((i + 1 + j, distance))
+                })
+                .min_by_key(|(_, distance)| *distance);
+
+            match opponent {
+                Some((j, _)) => {
+                    let other = self.queue.remove(j);
+                    self.queue.remove(i);
+                    pairs.push((ticket.addr, other.addr));
+                }
+                None => i += 1,
+            }
+        }
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn pairs_closest_ratings_and_widens_over_time() {
+        let mut lobby = Lobby::new();
+        lobby.enqueue(addr(1), Some(1000), 0.0);
+        lobby.enqueue(addr(2), Some(1500), 0.0);
+        lobby.enqueue(addr(3), Some(1080), 0.0);
+        lobby.enqueue(addr(4), Some(1040), 0.0);
+        assert_eq!(lobby.pair(0.0), vec![(addr(1), addr(4))]);
+        assert_eq!(lobby.queued(), 2);
+
+        // 420 apart, inside the window once the older ticket waited long enough
+        assert!(lobby.pair(1.0).is_empty());
+        assert_eq!(lobby.pair(7.0), vec![(addr(2), addr(3))]);
+    }
+
+    #[test]
+    fn rooms_pair_the_host_with_whoever_has_the_code() {
+        let mut lobby = Lobby::new();
+        let code = lobby.create_room(addr(1)).unwrap();
+        assert_eq!(code.len(), ROOM_CODE_LENGTH);
+        // Rooms are private, the queue never sees the host
+        lobby.enqueue(addr(2), None, 0.0);
+        assert!(lobby.pair(0.0).is_empty());
+
+        assert_eq!(lobby.join_room(addr(1), &code), None);
+        assert_eq!(
+            lobby.join_room(addr(3), &code.to_lowercase()),
+            Some(addr(1))
+        );
+        assert_eq!(lobby.join_room(addr(4), &code), None);
+        assert!(!lobby.is_waiting(addr(1)));
+    }
+}
@@ -1,3 +1,35 @@
-fn main() {
-    println!("Hello, world!");
+use std::path::PathBuf;
+
+use shared::net::Conditions;
+use shared::protocol::SERVER_PORT;
+use tokio::net::UdpSocket;
+
+// `server [--conditions SPEC] [--replays DIR]`, SPEC being a simulated network
+// as `Conditions::parse` reads it. Finished matches are saved to DIR, to be
+// checked with the `replay` binary.
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let mut conditions = Conditions::default();
+    let mut replays = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--conditions" {
+            match args.next().as_deref().and_then(Conditions::parse) {
+                Some(parsed) => conditions = parsed,
+                None => eprintln!("Ignoring malformed network conditions"),
+            }
+        } else if arg == "--replays" {
+            replays = args.next().map(PathBuf::from);
+        }
+    }
+
+    let socket = UdpSocket::bind(("0.0.0.0", SERVER_PORT)).await?;
+    println!("Server listening on {}", socket.local_addr()?);
+    if !conditions.is_ideal() {
+        println!("Simulating {:?}", conditions);
+    }
+    if let Some(dir) = &replays {
+        println!("Saving replays to {}", dir.display());
+    }
+    server::run(socket, conditions, replays).await
 }
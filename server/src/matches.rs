@@ -0,0 +1,651 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use shared::net::random_u64;
+use shared::protocol::ServerMessage;
+use shared::tick::{TICK_DT, TICK_RATE};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender, error::TrySendError};
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
+
+use crate::game::Match;
+use crate::input::TimedInput;
+
+// Ticks between reports of a client's input buffer health
+const HEALTH_INTERVAL_TICKS: u64 = TICK_RATE as u64;
+// How long a finished match stays up so both clients see the result
+pub const END_LINGER_TICKS: u64 = 10 * TICK_RATE as u64;
+// Commands waiting for a match at most. A match that stops draining them loses
+// its clients' commands instead of holding up the socket task.
+pub const COMMAND_CAPACITY: usize = 256;
+// Placements held back while a match's queue is full. A gap in the sequences
+// would hold back its client's ack for good, so they are only lost once a
+// match has stopped draining altogether.
+pub const MAX_OVERFLOW: usize = COMMAND_CAPACITY;
+
+// What the socket task forwards to a match for one of its clients
+#[derive(Debug, Clone, Copy)]
+pub enum Command {
+    Input(SocketAddr, TimedInput),
+    AckSnapshot(SocketAddr, u64),
+    Rejoin(SocketAddr, u64),
+    Disconnect(SocketAddr),
+    Leave(SocketAddr),
+}
+
+// What a match reports back to the socket task
+#[derive(Debug)]
+pub enum MatchEvent {
+    // Messages for the match's clients, once per tick and for rejoins
+    Send(Vec<(SocketAddr, ServerMessage)>),
+    // `addr` took over the slot played from `previous` until now
+    Rejoined {
+        addr: SocketAddr,
+        previous: SocketAddr,
+    },
+    // Slots whose grace period ran out
+    Expired(Vec<SocketAddr>),
+    // The task is gone, finished or panicked
+    Ended,
+}
+
+// How a match is driven
+enum Runner {
+    // Its own task, reached through its command queue
+    Task(mpsc::Sender<Command>),
+    // Stepped by `Matches::step` on the caller's thread, for deterministic tests
+    Inline(Box<MatchRunner>),
+}
+
+struct MatchHandle {
+    runner: Runner,
+    // Published by the match every tick, for pongs
+    tick: Arc<AtomicU64>,
+    tokens: Vec<u64>,
+    // Placements that found the queue full, sent ahead of any later ones
+    overflow: VecDeque<Command>,
+}
+
+// Every match in progress, each running as its own task with its own tick loop,
+// and which one each client plays in. Lives on the socket task, which only ever
+// talks to matches through their command queues.
+pub struct Matches {
+    // Ordered so inline matches are always stepped in the same order
+    handles: BTreeMap<u64, MatchHandle>,
+    players: HashMap<SocketAddr, u64>,
+    next_id: u64,
+    events: UnboundedSender<(u64, MatchEvent)>,
+    // Set for inline matches, which derive their seeds from it
+    inline_seed: Option<u64>,
+    // Where finished matches write their replays, if anywhere
+    replays: Option<PathBuf>,
+}
+
+impl Matches {
+    // The receiver gets the events of every match, tagged with its id
+    pub fn new() -> (Self, UnboundedReceiver<(u64, MatchEvent)>) {
+        let (events, receiver) = mpsc::unbounded_channel();
+        let matches = Matches {
+            handles: BTreeMap::new(),
+            players: HashMap::new(),
+            next_id: 0,
+            events,
+            inline_seed: None,
+            replays: None,
+        };
+        (matches, receiver)
+    }
+
+    // Matches that only advance when `step` is called, seeded from `seed`, so a
+    // test gets the same games every run. Needs no runtime.
+    pub fn inline(seed: u64) -> (Self, UnboundedReceiver<(u64, MatchEvent)>) {
+        let (mut matches, receiver) = Self::new();
+        matches.inline_seed = Some(seed);
+        (matches, receiver)
+    }
+
+    // Matches started from now on save a replay into `dir` when they finish
+    pub fn save_replays(&mut self, dir: PathBuf) {
+        self.replays = Some(dir);
+    }
+
+    // Starts a match between `players`, joined in order, and returns its id and
+    // the welcomes to send
+    pub fn start(&mut self, players: &[SocketAddr]) -> (u64, Vec<(SocketAddr, ServerMessage)>) {
+        self.next_id += 1;
+        let id = self.next_id;
+        let seed = match self.inline_seed {
+            Some(seed) => seed.wrapping_add(id),
+            None => random_u64(),
+        };
+        let mut game = Match::new(seed);
+        let welcomes = players
+            .iter()
+            .map(|&addr| (addr, game.join(addr).welcome()))
+            .collect();
+        for &addr in players {
+            self.players.insert(addr, id);
+        }
+
+        let tick = Arc::new(AtomicU64::new(0));
+        let tokens = game.peers.iter().map(|p| p.token).collect();
+        let runner = MatchRunner {
+            id,
+            game,
+            ended_for: 0,
+            tick: tick.clone(),
+            replays: self.replays.clone(),
+        };
+        let runner = if self.inline_seed.is_some() {
+            Runner::Inline(Box::new(runner))
+        } else {
+            let (commands, receiver) = mpsc::channel(COMMAND_CAPACITY);
+            let events = self.events.clone();
+            supervise(id, run_match(runner, receiver, events.clone()), events);
+            Runner::Task(commands)
+        };
+        self.handles.insert(
+            id,
+            MatchHandle {
+                runner,
+                tick,
+                tokens,
+                overflow: VecDeque::new(),
+            },
+        );
+        (id, welcomes)
+    }
+
+    // Advances every inline match by a tick, their events go to the receiver
+    pub fn step(&mut self) {
+        let mut finished = Vec::new();
+        for (&id, handle) in &mut self.handles {
+            if let Runner::Inline(runner) = &mut handle.runner {
+                let (events, done) = runner.tick();
+                for event in events {
+                    let _ = self.events.send((id, event));
+                }
+                if done {
+                    runner.finish();
+                    finished.push(id);
+                }
+            }
+        }
+        for id in finished {
+            self.handles.remove(&id);
+            let _ = self.events.send((id, MatchEvent::Ended));
+        }
+    }
+
+    // An inline match, for tests to inspect
+    pub fn get(&self, id: u64) -> Option<&Match> {
+        match &self.handles.get(&id)?.runner {
+            Runner::Inline(runner) => Some(&runner.game),
+            Runner::Task(_) => None,
+        }
+    }
+
+    pub fn match_id(&self, addr: SocketAddr) -> Option<u64> {
+        self.players.get(&addr).copied()
+    }
+
+    pub fn is_playing(&self, addr: SocketAddr) -> bool {
+        self.players.contains_key(&addr)
+    }
+
+    // Current tick of the client's match
+    pub fn tick(&self, addr: SocketAddr) -> Option<u64> {
+        let id = self.players.get(&addr)?;
+        Some(self.handles.get(id)?.tick.load(Ordering::Relaxed))
+    }
+
+    // Forwards a command to the client's match, false when it is in none
+    pub fn send(&mut self, addr: SocketAddr, command: Command) -> bool {
+        match self.players.get(&addr) {
+            Some(&id) => self.send_to(id, command),
+            None => false,
+        }
+    }
+
+    fn send_to(&mut self, id: u64, command: Command) -> bool {
+        let Some(handle) = self.handles.get_mut(&id) else {
+            return false;
+        };
+        let commands = match &mut handle.runner {
+            Runner::Task(commands) => commands,
+            Runner::Inline(runner) => {
+                for event in runner.command(command) {
+                    let _ = self.events.send((id, event));
+                }
+                return true;
+            }
+        };
+        if !retry(commands, &mut handle.overflow) {
+            return false;
+        }
+        // Placements queue up behind the held back ones to stay in order
+        if let Command::Input(..) = command
+            && !handle.overflow.is_empty()
+        {
+            return hold(id, &mut handle.overflow, command);
+        }
+        match commands.try_send(command) {
+            Ok(()) => true,
+            Err(TrySendError::Full(command @ Command::Input(..))) => {
+                hold(id, &mut handle.overflow, command)
+            }
+            Err(TrySendError::Full(_)) => {
+                eprintln!("Match {} is not keeping up, dropped a command", id);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    // Sends held back placements to matches that have room for them again
+    pub fn flush(&mut self) {
+        for handle in self.handles.values_mut() {
+            if let Runner::Task(commands) = &handle.runner {
+                retry(commands, &mut handle.overflow);
+            }
+        }
+    }
+
+    // Asks the match holding the token's slot to hand it to `addr`. The answer
+    // comes back as a `Rejoined` event and a welcome, false if no match has it.
+    pub fn rejoin(&mut self, addr: SocketAddr, token: u64) -> bool {
+        let Some(id) = self
+            .handles
+            .iter()
+            .find(|(_, handle)| handle.tokens.contains(&token))
+            .map(|(id, _)| *id)
+        else {
+            return false;
+        };
+        self.send_to(id, Command::Rejoin(addr, token))
+    }
+
+    pub fn disconnect(&mut self, addr: SocketAddr) {
+        self.send(addr, Command::Disconnect(addr));
+    }
+
+    pub fn leave(&mut self, addr: SocketAddr) {
+        self.send(addr, Command::Leave(addr));
+        self.players.remove(&addr);
+    }
+
+    // Keeps the routing in step with what a match reported
+    pub fn update(&mut self, id: u64, event: &MatchEvent) {
+        match event {
+            MatchEvent::Rejoined { addr, previous } => {
+                self.players.remove(previous);
+                self.players.insert(*addr, id);
+            }
+            MatchEvent::Expired(addrs) => {
+                for addr in addrs {
+                    self.players.remove(addr);
+                }
+            }
+            MatchEvent::Ended => {
+                self.handles.remove(&id);
+                self.players.retain(|_, match_id| *match_id != id);
+            }
+            MatchEvent::Send(_) => {}
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+}
+
+// One match and its bookkeeping, stepped by its task or by `Matches::step`
+pub struct MatchRunner {
+    id: u64,
+    game: Match,
+    // Ticks since the match was decided, counted here since a decided match
+    // stops advancing its own tick
+    ended_for: u64,
+    tick: Arc<AtomicU64>,
+    replays: Option<PathBuf>,
+}
+
+impl MatchRunner {
+    // Simulates a tick. Returns what to report and whether the match is over:
+    // everyone left, or it was decided a while ago.
+    fn tick(&mut self) -> (Vec<MatchEvent>, bool) {
+        let game = &mut self.game;
+        let before: Vec<SocketAddr> = game.peers.iter().map(|p| p.addr).collect();
+        game.tick();
+        self.tick.store(game.state.tick, Ordering::Relaxed);
+
+        let mut messages = game.snapshots();
+        for peer in &mut game.peers {
+            for sequence in peer.rejected.drain(..) {
+                messages.push((peer.addr, ServerMessage::PlacementRejected { sequence }));
+            }
+        }
+        // A decided match stops its tick, which would send this every tick
+        if !game.state.ended && game.state.tick.is_multiple_of(HEALTH_INTERVAL_TICKS) {
+            for peer in &game.peers {
+                let health = peer.inputs.health();
+                messages.push((peer.addr, ServerMessage::InputHealth { health }));
+            }
+        }
+        let expired: Vec<SocketAddr> = before
+            .into_iter()
+            .filter(|addr| game.peers.iter().all(|p| p.addr != *addr))
+            .collect();
+        let mut events = Vec::new();
+        if !expired.is_empty() {
+            events.push(MatchEvent::Expired(expired));
+        }
+        events.push(MatchEvent::Send(messages));
+
+        if game.state.ended {
+            self.ended_for += 1;
+        }
+        (events, game.peers.is_empty() || self.ended_for >= END_LINGER_TICKS)
+    }
+
+    // The encoded replay and the directory to save it in, when asked to
+    fn replay(&self) -> Option<(PathBuf, Vec<u8>)> {
+        let dir = self.replays.clone()?;
+        Some((dir, self.game.replay().encode()))
+    }
+
+    // Saves the replay right here, blocking on the file system
+    fn finish(&self) {
+        if let Some((dir, bytes)) = self.replay() {
+            save_replay(&dir, self.id, &bytes);
+        }
+    }
+
+    fn command(&mut self, command: Command) -> Vec<MatchEvent> {
+        let game = &mut self.game;
+        match command {
+            Command::Input(addr, input) => {
+                game.receive_input(addr, input);
+            }
+            Command::AckSnapshot(addr, tick) => game.acknowledge_snapshot(addr, tick),
+            Command::Rejoin(addr, token) => {
+                return match game.rejoin(addr, token) {
+                    Some((peer, previous)) => vec![
+                        MatchEvent::Rejoined { addr, previous },
+                        MatchEvent::Send(vec![(addr, peer.welcome())]),
+                    ],
+                    None => vec![MatchEvent::Send(vec![(addr, ServerMessage::RejoinFailed)])],
+                };
+            }
+            Command::Disconnect(addr) => game.disconnect(addr),
+            Command::Leave(addr) => {
+                game.leave(addr);
+            }
+        }
+        Vec::new()
+    }
+}
+
+// Sends as much of `overflow` as the queue takes, false once the match is gone
+fn retry(commands: &mpsc::Sender<Command>, overflow: &mut VecDeque<Command>) -> bool {
+    while let Some(command) = overflow.pop_front() {
+        match commands.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Full(command)) => {
+                overflow.push_front(command);
+                return true;
+            }
+            Err(TrySendError::Closed(_)) => {
+                overflow.clear();
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn hold(id: u64, overflow: &mut VecDeque<Command>, command: Command) -> bool {
+    if overflow.len() >= MAX_OVERFLOW {
+        eprintln!("Match {} stopped taking commands, dropped a placement", id);
+        return false;
+    }
+    overflow.push_back(command);
+    true
+}
+
+// Runs a match's task and reports the end however it stopped, so a panic only
+// takes down its own match
+fn supervise(
+    id: u64,
+    task: impl Future<Output = ()> + Send + 'static,
+    events: UnboundedSender<(u64, MatchEvent)>,
+) {
+    let task = tokio::spawn(task);
+    tokio::spawn(async move {
+        if let Err(err) = task.await {
+            eprintln!("Match {} crashed: {}", id, err);
+        }
+        let _ = events.send((id, MatchEvent::Ended));
+    });
+}
+
+// A match's task: ticks on its own interval and applies commands in between
+async fn run_match(
+    mut runner: MatchRunner,
+    mut commands: mpsc::Receiver<Command>,
+    events: UnboundedSender<(u64, MatchEvent)>,
+) {
+    let id = runner.id;
+    // A match that fell behind catches up in a burst, so its tick stays in step
+    // with the wall clock the clients sync to
+    let mut interval = time::interval(Duration::from_secs_f32(TICK_DT));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let started = Instant::now();
+                let (ticked, done) = runner.tick();
+                for event in ticked {
+                    // The socket task is gone
+                    if events.send((id, event)).is_err() {
+                        return;
+                    }
+                }
+
+                let elapsed = started.elapsed().as_secs_f32();
+                if elapsed > TICK_DT {
+                    eprintln!("Match {} tick took {:.1} ms", id, elapsed * 1000.0);
+                }
+                if done {
+                    // Written on the blocking pool, the runtime's threads keep
+                    // ticking the other matches meanwhile
+                    if let Some((dir, bytes)) = runner.replay() {
+                        let _ = task::spawn_blocking(move || save_replay(&dir, id, &bytes)).await;
+                    }
+                    return;
+                }
+            }
+            command = commands.recv() => {
+                let Some(command) = command else {
+                    return;
+                };
+                for event in runner.command(command) {
+                    let _ = events.send((id, event));
+                }
+            }
+        }
+    }
+}
+
+// Named by when the match finished and its id, so runs of the server do not
+// overwrite each other
+fn save_replay(dir: &Path, id: u64, bytes: &[u8]) {
+    let finished = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let path = dir.join(format!("match-{}-{}.replay", finished, id));
+    match fs::create_dir_all(dir).and_then(|()| fs::write(&path, bytes)) {
+        Ok(()) => println!("Saved replay of match {} to {}", id, path.display()),
+        Err(err) => eprintln!("Failed to save replay of match {}: {}", id, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::game::RECONNECT_GRACE_TICKS;
+
+    fn input(sequence: u32) -> Command {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 1));
+        Command::Input(
+            addr,
+            TimedInput {
+                sequence,
+                tick: 10,
+                card_id: 1,
+                x: 240.0,
+                y: 600.0,
+            },
+        )
+    }
+
+    fn sequence(command: Command) -> u32 {
+        match command {
+            Command::Input(_, input) => input.sequence,
+            other => panic!("not an input: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn held_back_placements_go_out_in_order() {
+        let (commands, mut receiver) = mpsc::channel(1);
+        let mut overflow = VecDeque::new();
+        commands.try_send(input(1)).unwrap();
+        assert!(hold(1, &mut overflow, input(2)));
+        assert!(hold(1, &mut overflow, input(3)));
+
+        assert!(retry(&commands, &mut overflow));
+        assert_eq!(overflow.len(), 2);
+        assert_eq!(sequence(receiver.try_recv().unwrap()), 1);
+        assert!(retry(&commands, &mut overflow));
+        assert_eq!(sequence(receiver.try_recv().unwrap()), 2);
+        assert!(retry(&commands, &mut overflow));
+        assert_eq!(sequence(receiver.try_recv().unwrap()), 3);
+        assert!(overflow.is_empty());
+
+        // Nothing is held for a match that is gone
+        hold(1, &mut overflow, input(4));
+        drop(receiver);
+        assert!(!retry(&commands, &mut overflow));
+        assert!(overflow.is_empty());
+    }
+
+    #[test]
+    fn expired_slot_is_reported_and_cannot_be_rejoined() {
+        let (mut matches, mut events) = Matches::inline(1);
+        let (a, b) = (
+            SocketAddr::from(([10, 0, 0, 1], 1)),
+            SocketAddr::from(([10, 0, 0, 1], 2)),
+        );
+        let (id, welcomes) = matches.start(&[a, b]);
+        let Some((_, ServerMessage::Welcome { token, .. })) = welcomes.first() else {
+            panic!("no welcome for {}", a);
+        };
+
+        matches.disconnect(a);
+        let mut expired = Vec::new();
+        for _ in 0..=RECONNECT_GRACE_TICKS {
+            matches.step();
+            while let Ok((match_id, event)) = events.try_recv() {
+                matches.update(match_id, &event);
+                if let MatchEvent::Expired(addrs) = event {
+                    expired.extend(addrs);
+                }
+            }
+        }
+        assert_eq!(expired, vec![a]);
+        assert!(!matches.is_playing(a) && matches.is_playing(b));
+        assert!(matches.get(id).unwrap().state.ended);
+
+        // The match still knows the token, but the slot behind it is gone
+        let c = SocketAddr::from(([10, 0, 0, 1], 3));
+        assert!(matches.rejoin(c, *token));
+        let Ok((_, MatchEvent::Send(messages))) = events.try_recv() else {
+            panic!("no answer to the rejoin");
+        };
+        assert!(matches!(messages[..], [(to, ServerMessage::RejoinFailed)] if to == c));
+    }
+
+    fn addrs(first: u16) -> [SocketAddr; 2] {
+        [first, first + 1].map(|port| SocketAddr::from(([10, 0, 0, 1], port)))
+    }
+
+    #[tokio::test]
+    async fn crashed_match_leaves_the_others_running() {
+        let (mut matches, mut events) = Matches::new();
+        let (first, _) = matches.start(&addrs(1));
+        let (second, _) = matches.start(&addrs(3));
+        supervise(
+            99,
+            async { panic!("simulation bug") },
+            matches.events.clone(),
+        );
+
+        let mut ended = Vec::new();
+        let mut ticked = HashSet::new();
+        while ticked.len() < 2 || ended.is_empty() {
+            let (id, event) = events.recv().await.unwrap();
+            matches.update(id, &event);
+            match event {
+                MatchEvent::Ended => ended.push(id),
+                // Only what arrives after the crash counts
+                MatchEvent::Send(_) if !ended.is_empty() => {
+                    ticked.insert(id);
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(ended, vec![99]);
+        assert_eq!(ticked, HashSet::from([first, second]));
+        assert_eq!(matches.len(), 2);
+    }
+
+    #[test]
+    fn decided_match_stops_reporting_input_health() {
+        let (mut matches, mut events) = Matches::inline(1);
+        let (id, _) = matches.start(&addrs(1));
+        // Decided right on a tick that reports, which it then stays on
+        for _ in 0..HEALTH_INTERVAL_TICKS {
+            matches.step();
+        }
+        while events.try_recv().is_ok() {}
+        matches.leave(addrs(1)[0]);
+        assert_eq!(matches.get(id).unwrap().state.tick, HEALTH_INTERVAL_TICKS);
+
+        let mut reports = 0;
+        for _ in 0..END_LINGER_TICKS {
+            matches.step();
+            while let Ok((_, event)) = events.try_recv() {
+                if let MatchEvent::Send(messages) = event {
+                    reports += messages
+                        .iter()
+                        .filter(|(_, m)| matches!(m, ServerMessage::InputHealth { .. }))
+                        .count();
+                }
+            }
+        }
+        assert_eq!(reports, 0);
+        assert!(matches.is_empty());
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::net::SocketAddr;
+
+use shared::net::connection::{CONNECTION_TIMEOUT, DISCONNECT_REDUNDANCY};
+use shared::net::handshake::{Datagram, DenyReason};
+use shared::net::{DisconnectReason, Endpoint, random_u64};
+
+// A challenge cookie is accepted for at least this long after it was sent
+pub const COOKIE_LIFETIME: f64 = 10.0;
+pub const MAX_SESSIONS: usize = 1024;
+
+pub struct Session {
+    pub id: u64,
+    pub endpoint: Endpoint,
+    salt: u64,
+    last_received: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    Connected(SocketAddr),
+    Disconnected(SocketAddr, DisconnectReason),
+}
+
+// Server side of the connection handshake. Addresses only get a session once
+// they answered a challenge, which they can only do if they really receive
+// packets at that address. Everything else from unknown addresses is dropped.
+pub struct Sessions {
+    // Keys the cookie hash, random per server run
+    secret: RandomState,
+    sessions: HashMap<SocketAddr, Session>,
+    max_sessions: usize,
+    replies: Vec<(SocketAddr, Vec<u8>)>,
+    events: Vec<SessionEvent>,
+}
+
+impl Sessions {
+    pub fn new(max_sessions: usize) -> Self {
+        Sessions {
+            secret: RandomState::new(),
+            sessions: HashMap::new(),
+            max_sessions,
+            replies: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn receive(&mut self, bytes: &[u8], addr: SocketAddr, now: f64) {
+        let Some(datagram) = Datagram::read(bytes) else {
+            return;
+        };
+        match datagram {
+            Datagram::Request { salt } => {
+                let cookie = self.cookie(addr, salt, now);
+                self.reply(addr, Datagram::Challenge { salt, cookie });
+            }
+            Datagram::Response { salt, cookie } => {
+                let window = (now / COOKIE_LIFETIME) as u64;
+                let valid = [window, window.saturating_sub(1)]
+                    .into_iter()
+                    .any(|window| self.cookie_for(addr, salt, window) == cookie);
+                if valid {
+                    self.accept(addr, salt, now);
+                }
+            }
+            Datagram::Payload { session, packet } => {
+                if let Some(existing) = self.sessions.get_mut(&addr)
+                    && existing.id == session
+                    && existing.endpoint.receive(&packet, now)
+                {
+                    existing.last_received = now;
+                }
+            }
+            Datagram::Disconnect { session } => {
+                if self.sessions.get(&addr).is_some_and(|s| s.id == session) {
+                    self.sessions.remove(&addr);
+                    let reason = DisconnectReason::Closed;
+                    self.events.push(SessionEvent::Disconnected(addr, reason));
+                }
+            }
+            // Only servers send these
+            Datagram::Challenge { .. } | Datagram::Accepted { .. } | Datagram::Denied { .. } => {}
+        }
+    }
+
+    fn accept(&mut self, addr: SocketAddr, salt: u64, now: f64) {
+        // The client lost our answer and responded again
+        if let Some(existing) = self.sessions.get(&addr)
+            && existing.salt == salt
+        {
+            let session = existing.id;
+            self.reply(addr, Datagram::Accepted { salt, session });
+            return;
+        }
+
+        // A fresh handshake from a known address means the old client is gone
+        if self.sessions.remove(&addr).is_some() {
+            let reason = DisconnectReason::Closed;
+            self.events.push(SessionEvent::Disconnected(addr, reason));
+        }
+        if self.sessions.len() >= self.max_sessions {
+            let reason = DenyReason::ServerFull;
+            self.reply(addr, Datagram::Denied { salt, reason });
+            return;
+        }
+
+        let session = random_u64();
+        self.sessions.insert(
+            addr,
+            Session {
+                id: session,
+                endpoint: Endpoint::new(),
+                salt,
+                last_received: now,
+            },
+        );
+        self.reply(addr, Datagram::Accepted { salt, session });
+        self.events.push(SessionEvent::Connected(addr));
+    }
+
+    fn cookie(&self, addr: SocketAddr, salt: u64, now: f64) -> u64 {
+        self.cookie_for(addr, salt, (now / COOKIE_LIFETIME) as u64)
+    }
+
+    fn cookie_for(&self, addr: SocketAddr, salt: u64, window: u64) -> u64 {
+        self.secret.hash_one((addr, salt, window))
+    }
+
+    fn reply(&mut self, addr: SocketAddr, datagram: Datagram) {
+        self.replies.push((addr, datagram.write()));
+    }
+
+    // Handshake answers, to be sent right away rather than on the next tick
+    pub fn replies(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        std::mem::take(&mut self.replies)
+    }
+
+    pub fn events(&mut self) -> Vec<SessionEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn endpoint_mut(&mut self, addr: SocketAddr) -> Option<&mut Endpoint> {
+        self.sessions.get_mut(&addr).map(|s| &mut s.endpoint)
+    }
+
+    pub fn get(&self, addr: SocketAddr) -> Option<&Session> {
+        self.sessions.get(&addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    // Every session's datagrams plus pending replies. Sessions silent for longer
+    // than CONNECTION_TIMEOUT are dropped first and reported as events.
+    pub fn packets(&mut self, now: f64) -> Vec<(SocketAddr, Vec<u8>)> {
+        let timed_out: Vec<SocketAddr> = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| now - s.last_received > CONNECTION_TIMEOUT)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in timed_out {
+            self.sessions.remove(&addr);
+            let reason = DisconnectReason::TimedOut;
+            self.events.push(SessionEvent::Disconnected(addr, reason));
+        }
+
+        let mut packets = self.replies();
+        for addr in self.sessions.keys().copied().collect::<Vec<_>>() {
+            packets.extend(self.flush(addr, now).into_iter().map(|p| (addr, p)));
+        }
+        packets
+    }
+
+    // Datagrams for one session only, for answers that should not wait a tick
+    pub fn flush(&mut self, addr: SocketAddr, now: f64) -> Vec<Vec<u8>> {
+        let Some(session) = self.sessions.get_mut(&addr) else {
+            return Vec::new();
+        };
+        let id = session.id;
+        session
+            .endpoint
+            .packets(now)
+            .into_iter()
+            .map(|packet| {
+                Datagram::Payload {
+                    session: id,
+                    packet,
+                }
+                .write()
+            })
+            .collect()
+    }
+
+    // Drops the session and queues the goodbye
+    pub fn disconnect(&mut self, addr: SocketAddr) {
+        if let Some(session) = self.sessions.remove(&addr) {
+            let goodbye = Datagram::Disconnect {
+                session: session.id,
+            }
+            .write();
+            for _ in 0..DISCONNECT_REDUNDANCY {
+                self.replies.push((addr, goodbye.clone()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::net::{ClientConnection, ConnectionState};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    // Delivers client datagrams to the server and the answers back
+    fn exchange(
+        client: &mut ClientConnection,
+        sessions: &mut Sessions,
+        from: SocketAddr,
+        now: f64,
+    ) {
+        for datagram in client.packets(now) {
+            sessions.receive(&datagram, from, now);
+        }
+        for (to, datagram) in sessions.replies() {
+            if to == from {
+                client.receive(&datagram, now);
+            }
+        }
+    }
+
+    #[test]
+    fn handshake_creates_a_session() {
+        let mut sessions = Sessions::new(MAX_SESSIONS);
+        let mut client = ClientConnection::new(0.0);
+        exchange(&mut client, &mut sessions, addr(1), 0.0);
+        assert!(sessions.is_empty());
+        exchange(&mut client, &mut sessions, addr(1), 0.0);
+
+        let ConnectionState::Connected { session } = client.state() else {
+            panic!("not connected: {:?}", client.state());
+        };
+        assert_eq!(sessions.get(addr(1)).map(|s| s.id), Some(session));
+        assert_eq!(sessions.events(), vec![SessionEvent::Connected(addr(1))]);
+    }
+
+    #[test]
+    fn challenge_answered_from_another_address_is_ignored() {
+        let mut sessions = Sessions::new(MAX_SESSIONS);
+        let mut client = ClientConnection::new(0.0);
+        exchange(&mut client, &mut sessions, addr(1), 0.0);
+        // The cookie was issued to port 1, a spoofer replaying it elsewhere fails
+        exchange(&mut client, &mut sessions, addr(2), 0.0);
+        assert!(sessions.is_empty());
+        assert!(!client.is_connected());
+    }
+
+    #[test]
+    fn silent_sessions_time_out() {
+        let mut sessions = Sessions::new(MAX_SESSIONS);
+        let mut client = ClientConnection::new(0.0);
+        for _ in 0..2 {
+            exchange(&mut client, &mut sessions, addr(1), 0.0);
+        }
+        sessions.events();
+        sessions.packets(CONNECTION_TIMEOUT + 1.0);
+        assert!(sessions.is_empty());
+        assert_eq!(
+            sessions.events(),
+            vec![SessionEvent::Disconnected(
+                addr(1),
+                DisconnectReason::TimedOut
+            )]
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::io;
+use std::net::SocketAddr;
+
+use shared::net::{Conditions, LinkConditioner, random_u64};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration, Instant};
+
+// The server's UDP socket, behind a simulated network in both directions when
+// asked for one. With ideal conditions datagrams pass straight through.
+pub struct Socket {
+    socket: UdpSocket,
+    outgoing: LinkConditioner,
+    incoming: LinkConditioner,
+    started: Instant,
+}
+
+impl Socket {
+    pub fn new(socket: UdpSocket, conditions: Conditions) -> Self {
+        let seed = random_u64();
+        Socket {
+            socket,
+            outgoing: LinkConditioner::new(conditions, seed),
+            incoming: LinkConditioner::new(conditions, !seed),
+            started: Instant::now(),
+        }
+    }
+
+    pub async fn send_to(&mut self, datagram: &[u8], to: SocketAddr) {
+        self.outgoing.push(to, datagram.to_vec(), self.now());
+        self.release().await;
+    }
+
+    // Waits for the next datagram to come through, sending delayed ones as they
+    // fall due meanwhile. Cancel safe like the socket's own `recv_from`.
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            self.release().await;
+            if let Some((from, datagram)) = self.incoming.pop(self.now()) {
+                let len = datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                return Ok((len, from));
+            }
+
+            let due = [self.outgoing.next_due(), self.incoming.next_due()]
+                .into_iter()
+                .flatten()
+                .reduce(f64::min);
+            let wake = self.started + Duration::from_secs_f64(due.unwrap_or(0.0));
+            tokio::select! {
+                received = self.socket.recv_from(buf) => {
+                    let (len, from) = received?;
+                    self.incoming.push(from, buf[..len].to_vec(), self.now());
+                }
+                _ = time::sleep_until(wake), if due.is_some() => {}
+            }
+        }
+    }
+
+    async fn release(&mut self) {
+        while let Some((to, datagram)) = self.outgoing.pop(self.now()) {
+            // One unreachable client must not take the server down
+            if let Err(err) = self.socket.send_to(&datagram, to).await {
+                eprintln!("Failed to send to {}: {}", to, err);
+            }
+        }
+    }
+
+    fn now(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+}
@@ -0,0 +1,262 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use server::Server;
+use server::game::Match;
+use server::matches::{MatchEvent, Matches};
+use shared::GameState;
+use shared::delta::Baselines;
+use shared::net::{ClientConnection, Conditioned, Conditions, MemoryNetwork, MemoryTransport};
+use shared::net::{ConnectionState, Transport};
+use shared::protocol::{self, ClientMessage, ServerMessage};
+use shared::tick::TICK_DT;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+const SERVER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 7777);
+const BASELINE_CAPACITY: usize = 64;
+
+// A client as the game would run it, minus the rendering and prediction: it
+// connects, decodes and acknowledges snapshots, and places cards when told to
+pub struct SimClient {
+    pub addr: SocketAddr,
+    transport: Conditioned<MemoryTransport>,
+    conditions: Conditions,
+    connection: ClientConnection,
+    baselines: Baselines,
+    pub player_id: Option<u32>,
+    pub token: Option<u64>,
+    pub hand: Vec<u32>,
+    // Latest snapshot, checked against the server's checksum
+    pub view: Option<GameState>,
+    // Everything but snapshots, in arrival order
+    pub messages: Vec<ServerMessage>,
+    pub snapshots: u64,
+    pub decode_failures: u64,
+    next_sequence: u32,
+    // Stopped sending and receiving without a goodbye, like a pulled cable
+    vanished: bool,
+}
+
+impl SimClient {
+    pub fn send(&mut self, message: &ClientMessage) {
+        self.connection
+            .send(message.channel(), protocol::encode(message));
+    }
+
+    // Targets the tick of the latest snapshot, like a client placing a card on
+    // what it sees
+    pub fn place_card(&mut self, card_id: u32, x: f32, y: f32) {
+        let tick = self.view.as_ref().map_or(0, |view| view.tick);
+        self.send(&ClientMessage::PlaceCard {
+            sequence: self.next_sequence,
+            tick,
+            card_id,
+            x,
+            y,
+        });
+        self.next_sequence += 1;
+    }
+
+    pub fn disconnect(&mut self) {
+        self.connection.disconnect();
+    }
+
+    pub fn vanish(&mut self) {
+        self.vanished = true;
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.connection.state()
+    }
+
+    pub fn elixir(&self) -> u32 {
+        let view = self.view.as_ref();
+        view.and_then(|v| v.players.iter().find(|p| Some(p.id) == self.player_id))
+            .map_or(0, |p| p.elixir)
+    }
+
+    fn update(&mut self, now: f64) {
+        while let Ok(Some((from, datagram))) = self.transport.receive(now) {
+            if from == SERVER_ADDR {
+                self.connection.receive(&datagram, now);
+            }
+        }
+        let received = self.connection.drain_received();
+        for message in received.iter().filter_map(|(_, p)| protocol::decode(p)) {
+            match message {
+                ServerMessage::Snapshot {
+                    tick,
+                    checksum,
+                    payload,
+                    ..
+                } => {
+                    let Some(state) = self.baselines.decode(payload, checksum) else {
+                        self.decode_failures += 1;
+                        continue;
+                    };
+                    self.send(&ClientMessage::AckSnapshot { tick });
+                    self.snapshots += 1;
+                    if self.view.as_ref().is_none_or(|view| view.tick < tick) {
+                        self.view = Some(state);
+                    }
+                }
+                ServerMessage::Welcome {
+                    player_id,
+                    token,
+                    ref hand,
+                } => {
+                    self.player_id = Some(player_id);
+                    self.token = Some(token);
+                    self.hand = hand.clone();
+                    self.messages.push(message);
+                }
+                message => self.messages.push(message),
+            }
+        }
+
+        for packet in self.connection.packets(now) {
+            let _ = self.transport.send(SERVER_ADDR, &packet, now);
+        }
+        let _ = self.transport.release(now);
+    }
+}
+
+// The server and its clients in one process, on an in-memory network and a
+// clock that only moves with `step`. Matches tick inline with the server, so a
+// seed always plays out the same.
+pub struct Harness {
+    network: MemoryNetwork,
+    transport: MemoryTransport,
+    pub server: Server,
+    events: UnboundedReceiver<(u64, MatchEvent)>,
+    pub clients: Vec<SimClient>,
+    pub now: f64,
+    seed: u64,
+    next_port: u16,
+}
+
+impl Harness {
+    pub fn new(seed: u64) -> Self {
+        let network = MemoryNetwork::new();
+        let transport = network.bind(SERVER_ADDR);
+        let (matches, events) = Matches::inline(seed);
+        Harness {
+            network,
+            transport,
+            server: Server::new(matches),
+            events,
+            clients: Vec::new(),
+            now: 0.0,
+            seed,
+            next_port: 10000,
+        }
+    }
+
+    // Connects a client that asks the lobby with `hello`, returns its index
+    pub fn add_client(&mut self, hello: ClientMessage) -> usize {
+        self.add_client_with(hello, Conditions::default())
+    }
+
+    pub fn add_client_with(&mut self, hello: ClientMessage, conditions: Conditions) -> usize {
+        let index = self.clients.len();
+        let client = self.connect(index, &hello, conditions);
+        self.clients.push(client);
+        index
+    }
+
+    // Reconnects a client from a new address with the token from its welcome,
+    // leaving the old connection to time out
+    pub fn rejoin(&mut self, index: usize) {
+        let token = self.clients[index]
+            .token
+            .expect("client was never welcomed");
+        let conditions = self.clients[index].conditions;
+        let mut client = self.connect(index, &ClientMessage::Rejoin { token }, conditions);
+        client.token = Some(token);
+        self.clients[index] = client;
+    }
+
+    // One tick: clients handle what arrived and send, the server handles that,
+    // matches tick, and the server flushes
+    pub fn step(&mut self) {
+        self.now += TICK_DT as f64;
+        let now = self.now;
+        for client in self.clients.iter_mut().filter(|c| !c.vanished) {
+            client.update(now);
+        }
+
+        while let Ok(Some((from, datagram))) = self.transport.receive(now) {
+            let packets = self.server.receive(&datagram, from, now);
+            self.send_all(packets);
+        }
+        self.server.matches.step();
+        while let Ok((id, event)) = self.events.try_recv() {
+            self.server.match_event(id, event);
+        }
+        let packets = self.server.tick(now);
+        self.send_all(packets);
+    }
+
+    pub fn run_ticks(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.step();
+        }
+    }
+
+    // Steps until `done` holds, false if it still does not after `max_ticks`
+    pub fn run_until(&mut self, max_ticks: u64, mut done: impl FnMut(&Harness) -> bool) -> bool {
+        for _ in 0..max_ticks {
+            if done(self) {
+                return true;
+            }
+            self.step();
+        }
+        done(self)
+    }
+
+    // The match the client plays in
+    pub fn game(&self, index: usize) -> Option<&Match> {
+        let id = self.server.matches.match_id(self.clients[index].addr)?;
+        self.server.matches.get(id)
+    }
+
+    pub fn state(&self, index: usize) -> Option<&GameState> {
+        self.game(index).map(|game| &game.state)
+    }
+
+    fn connect(
+        &mut self,
+        index: usize,
+        hello: &ClientMessage,
+        conditions: Conditions,
+    ) -> SimClient {
+        let addr = SocketAddr::from(([10, 0, 0, 1], self.next_port));
+        self.next_port += 1;
+        let transport = self.network.bind(addr);
+        // Every client and every reconnect rolls different dice
+        let seed = self.seed ^ ((index as u64) << 32) ^ addr.port() as u64;
+        let mut client = SimClient {
+            addr,
+            transport: Conditioned::new(transport, conditions, seed),
+            conditions,
+            connection: ClientConnection::new(self.now),
+            baselines: Baselines::new(BASELINE_CAPACITY),
+            player_id: None,
+            token: None,
+            hand: Vec::new(),
+            view: None,
+            messages: Vec::new(),
+            snapshots: 0,
+            decode_failures: 0,
+            next_sequence: 1,
+            vanished: false,
+        };
+        client.send(hello);
+        client
+    }
+
+    fn send_all(&mut self, packets: Vec<(SocketAddr, Vec<u8>)>) {
+        for (to, packet) in packets {
+            let _ = self.transport.send(to, &packet, self.now);
+        }
+    }
+}
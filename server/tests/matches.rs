@@ -0,0 +1,226 @@
+mod harness;
+
+use harness::Harness;
+use server::game::RECONNECT_GRACE_TICKS;
+use server::matches::END_LINGER_TICKS;
+use shared::net::connection::CONNECTION_TIMEOUT;
+use shared::net::{Conditions, ConnectionState};
+use shared::protocol::{ClientMessage, ServerMessage};
+use shared::replay::{Replay, ReplayError};
+use shared::tick::TICK_RATE;
+
+const SECOND: u64 = TICK_RATE as u64;
+
+fn find_match() -> ClientMessage {
+    ClientMessage::FindMatch { rating: None }
+}
+
+// Starts a queued match between two clients and waits for both to see it
+fn start_match(harness: &mut Harness) {
+    harness.add_client(find_match());
+    harness.add_client(find_match());
+    let started = harness.run_until(5 * SECOND, |h| h.clients.iter().all(|c| c.view.is_some()));
+    assert!(started, "no match started");
+}
+
+#[test]
+fn queued_clients_play_the_same_match() {
+    let mut harness = Harness::new(1);
+    start_match(&mut harness);
+    harness.run_ticks(2 * SECOND);
+
+    assert_eq!(harness.server.matches.len(), 1);
+    let (a, b) = (&harness.clients[0], &harness.clients[1]);
+    assert!(
+        a.messages
+            .iter()
+            .any(|m| matches!(m, ServerMessage::Queued))
+    );
+    assert_ne!(a.player_id, b.player_id);
+    assert_eq!(a.hand.len(), b.hand.len());
+
+    // Snapshots leave the server at the end of a step and arrive with the next
+    let state = harness.state(0).unwrap();
+    for client in &harness.clients {
+        assert!(matches!(client.state(), ConnectionState::Connected { .. }));
+        assert_eq!(client.decode_failures, 0);
+        assert_eq!(client.view.as_ref().unwrap().tick + 1, state.tick);
+    }
+}
+
+#[test]
+fn room_code_pairs_host_and_guest() {
+    let mut harness = Harness::new(2);
+    harness.add_client(ClientMessage::CreateRoom);
+    // Someone in the queue is not matched with either of them
+    harness.add_client(find_match());
+    let code = |h: &Harness| {
+        h.clients[0].messages.iter().find_map(|m| match m {
+            ServerMessage::RoomCreated { code } => Some(code.clone()),
+            _ => None,
+        })
+    };
+    assert!(harness.run_until(SECOND, |h| code(h).is_some()));
+
+    let code = code(&harness).unwrap();
+    harness.add_client(ClientMessage::JoinRoom { code: code.clone() });
+    assert!(harness.run_until(SECOND, |h| h.clients[2].view.is_some()));
+    assert!(harness.clients[0].view.is_some());
+    assert!(harness.clients[1].view.is_none());
+    assert_eq!(harness.server.lobby.queued(), 1);
+
+    // Codes only work once
+    harness.add_client(ClientMessage::JoinRoom { code });
+    harness.run_ticks(SECOND);
+    assert!(harness.clients[3].view.is_none());
+    let messages = &harness.clients[3].messages;
+    assert!(
+        messages
+            .iter()
+            .any(|m| matches!(m, ServerMessage::RoomUnavailable))
+    );
+}
+
+#[test]
+fn placed_card_reaches_the_server_and_the_opponent() {
+    let mut harness = Harness::new(3);
+    start_match(&mut harness);
+    let card_id = harness.clients[0].hand[0];
+    let cost = harness
+        .state(0)
+        .unwrap()
+        .cards
+        .iter()
+        .find(|c| c.id == card_id)
+        .unwrap()
+        .cost;
+    assert!(harness.run_until(20 * SECOND, |h| h.clients[0].elixir() >= cost));
+
+    let owner = harness.clients[0].player_id.unwrap();
+    harness.clients[0].place_card(card_id, 240.0, 600.0);
+    harness.run_ticks(3);
+
+    let state = harness.state(0).unwrap();
+    assert_eq!(state.units.iter().filter(|u| u.owner == owner).count(), 1);
+    let seen = harness.clients[1].view.as_ref().unwrap();
+    assert!(seen.units.iter().any(|u| u.owner == owner));
+}
+
+// Plays a match where both sides keep placing cards
+fn play_match(seed: u64, conditions: Conditions) -> Harness {
+    let mut harness = Harness::new(seed);
+    harness.add_client_with(find_match(), conditions);
+    harness.add_client_with(find_match(), conditions);
+    assert!(harness.run_until(10 * SECOND, |h| {
+        h.clients.iter().all(|c| c.view.is_some())
+    }));
+
+    for tick in 0..30 * SECOND {
+        for (i, client) in harness.clients.iter_mut().enumerate() {
+            let card_id = client.hand[tick as usize % client.hand.len()];
+            if tick % SECOND == i as u64 * 7 && client.elixir() >= 5 {
+                let y = if i == 0 { 600.0 } else { 250.0 };
+                client.place_card(card_id, 100.0 + tick as f32 % 280.0, y);
+            }
+        }
+        harness.step();
+    }
+    for client in &harness.clients {
+        assert_eq!(client.decode_failures, 0);
+        assert!(client.snapshots > 20 * SECOND);
+    }
+    harness
+}
+
+// The final checksum of `play_match`
+fn play(seed: u64, conditions: Conditions) -> u64 {
+    play_match(seed, conditions).state(0).unwrap().checksum()
+}
+
+#[test]
+fn same_seed_plays_out_the_same_over_a_bad_network() {
+    let conditions =
+        Conditions::parse("latency=40,jitter=20,loss=5,duplicate=2,reorder=2").unwrap();
+    let checksum = play(4, conditions);
+    assert_eq!(checksum, play(4, conditions));
+    assert_ne!(checksum, play(5, conditions));
+}
+
+#[test]
+fn client_rejoins_its_slot_from_a_new_address() {
+    let mut harness = Harness::new(6);
+    start_match(&mut harness);
+    let player_id = harness.clients[0].player_id;
+    let old_addr = harness.clients[0].addr;
+
+    harness.rejoin(0);
+    assert!(harness.run_until(SECOND, |h| h.clients[0].view.is_some()));
+    assert_eq!(harness.clients[0].player_id, player_id);
+    assert!(!harness.server.matches.is_playing(old_addr));
+    let game = harness.game(0).unwrap();
+    assert!(game.peers.iter().any(|p| p.addr == harness.clients[0].addr));
+    assert!(harness.server.sessions.get(old_addr).is_none());
+
+    // Leaving for good ends the match for the one left behind
+    harness.clients[0].disconnect();
+    harness.run_ticks(SECOND);
+    assert!(harness.state(1).unwrap().ended);
+}
+
+#[test]
+fn vanished_client_forfeits_once_its_grace_runs_out() {
+    let mut harness = Harness::new(8);
+    start_match(&mut harness);
+    let old_addr = harness.clients[0].addr;
+    harness.clients[0].vanish();
+
+    // The session times out first, then the slot is held for the grace period
+    let held = (CONNECTION_TIMEOUT * TICK_RATE as f64) as u64 + RECONNECT_GRACE_TICKS;
+    harness.run_ticks(held - SECOND);
+    assert!(harness.server.matches.is_playing(old_addr));
+    assert!(!harness.state(1).unwrap().ended);
+
+    assert!(harness.run_until(2 * SECOND, |h| h.state(1).unwrap().ended));
+    assert_eq!(
+        harness.state(1).unwrap().winner,
+        harness.clients[1].player_id
+    );
+    assert!(!harness.server.matches.is_playing(old_addr));
+
+    // Too late to take the slot back
+    harness.rejoin(0);
+    assert!(harness.run_until(SECOND, |h| {
+        h.clients[0]
+            .messages
+            .iter()
+            .any(|m| matches!(m, ServerMessage::RejoinFailed))
+    }));
+    assert_eq!(harness.clients[0].player_id, None);
+}
+
+#[test]
+fn replay_plays_back_to_the_final_state() {
+    // Late inputs on a laggy network make the server rewind
+    let conditions = Conditions::parse("latency=120,jitter=40,loss=5").unwrap();
+    let mut harness = play_match(7, conditions);
+    harness.clients[1].disconnect();
+    assert!(harness.run_until(SECOND, |h| h.state(0).unwrap().ended));
+
+    let game = harness.game(0).unwrap();
+    let replay = Replay::decode(&game.replay().encode()).unwrap();
+    assert_eq!(replay.header.players.len(), 2);
+    assert!(replay.actions.len() > 10);
+    assert!(replay.checksums.len() >= 30);
+    let state = replay.play().unwrap();
+    assert_eq!(state.checksum(), game.state.checksum());
+    assert_eq!(state.winner, game.state.winner);
+
+    // The match finishes once the result has been on screen for a while
+    harness.run_ticks(END_LINGER_TICKS);
+    assert!(harness.server.matches.is_empty());
+
+    // A different seed goes its own way from the first checksum
+    let mut forged = replay.clone();
+    forged.header.seed += 1;
+    assert_eq!(forged.play().err(), Some(ReplayError::Desync { tick: 0 }));
+}
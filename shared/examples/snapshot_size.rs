@@ -0,0 +1,67 @@
+// Bytes per full snapshot and per one tick delta, plain bincode versus the
+// bit-packed format.
+//
+//     cargo run -p shared --example snapshot_size
+
+use shared::{GameState, Tower, Unit, bitpack, cards, delta, tick::TICK_DT};
+
+fn state_with_units(count: u32) -> GameState {
+    let mut state = GameState::with_seed(1);
+    state.cards = cards::catalog();
+    state.add_player(2);
+    for (id, owner, y) in [(1, 1, 760.0), (2, 2, 90.0)] {
+        state.towers.push(Tower {
+            id,
+            owner,
+            health: 3000,
+            x: 240.0,
+            y,
+            damage: 80,
+            attack_cooldown: 0.0,
+        });
+    }
+    for i in 0..count {
+        state.deploy_unit(Unit {
+            owner: 1 + i % 2,
+            x: (i * 37 % 480) as f32,
+            y: (i * 53 % 854) as f32,
+            health: 200 + i * 13 % 800,
+            velocity: if i % 2 == 0 { 60.0 } else { -60.0 },
+            ..Default::default()
+        });
+    }
+    // A few ticks so positions and timers hold real simulated values
+    for _ in 0..15 {
+        state.update(TICK_DT);
+    }
+    state
+}
+
+fn main() {
+    println!(
+        "{:>6} {:>10} {:>10} {:>7} {:>10} {:>10} {:>7}",
+        "units", "bincode", "packed", "ratio", "delta", "packed", "ratio"
+    );
+    for count in [0, 10, 25, 50, 100, 200] {
+        let state = state_with_units(count);
+        let plain = bincode::serialized_size(&state).unwrap();
+        let packed = bitpack::pack(&state).len() as u64;
+
+        // What a client that acknowledged the previous tick gets
+        let mut next = state.clone();
+        next.update(TICK_DT);
+        let delta = delta::diff(&state, &next);
+        let plain_delta = bincode::serialized_size(&delta).unwrap();
+        let packed_delta = bitpack::pack(&delta).len() as u64;
+        println!(
+            "{:>6} {:>10} {:>10} {:>6.1}% {:>10} {:>10} {:>6.1}%",
+            state.units.len(),
+            plain,
+            packed,
+            100.0 * packed as f64 / plain as f64,
+            plain_delta,
+            packed_delta,
+            100.0 * packed_delta as f64 / plain_delta as f64
+        );
+    }
+}
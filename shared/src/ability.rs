@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    // Fires once when the unit is placed in the arena
+    OnDeploy,
+    // Fires once when the unit is removed with zero health
+    OnDeath,
+    // Fires every time the unit has travelled this far, used for charge attacks
+    AfterDistance(f32),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Effect {
+    // Damages every enemy unit and tower within `radius` of the source unit
+    AreaDamage {
+        radius: f32,
+        damage: u32,
+    },
+    // Spawns `count` new units owned by the same player around the source unit
+    SpawnUnits {
+        count: u32,
+        health: u32,
+        velocity: f32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Ability {
+    pub trigger: Trigger,
+    pub effect: Effect,
+}
+
+impl Ability {
+    pub fn new(trigger: Trigger, effect: Effect) -> Self {
+        Ability { trigger, effect }
+    }
+
+    // Whether an `AfterDistance` trigger fired while moving from `before` to `after`
+    pub fn crossed_distance(&self, before: f32, after: f32) -> bool {
+        match self.trigger {
+            Trigger::AfterDistance(distance) if distance > 0.0 => {
+                (before / distance).floor() < (after / distance).floor()
+            }
+            _ => false,
+        }
+    }
+}
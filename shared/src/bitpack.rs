@@ -0,0 +1,731 @@
+use crate::ability::{Ability, Effect, Trigger};
+use crate::delta::{ListDelta, PlayerDelta, StateDelta, TowerDelta, UnitDelta};
+use crate::rng::Rng;
+use crate::{Card, GameState, Player, Tower, Unit, Vec2D};
+
+// Positions are fixed point at 1/16 of an arena pixel, covering the arena with
+// plenty of room for units that walk off its edges
+pub const POSITION_SCALE: f32 = 16.0;
+pub const POSITION_BITS: u32 = 16;
+// Speeds up to +-1024 px/s at the same resolution as positions
+pub const VELOCITY_SCALE: f32 = 16.0;
+pub const VELOCITY_BITS: u32 = 15;
+// Timers in seconds at about millisecond resolution, within +-8 s
+pub const TIMER_SCALE: f32 = 1024.0;
+pub const TIMER_BITS: u32 = 14;
+// Most health values fit here, larger ones take an escape bit and a varint
+pub const HEALTH_BITS: u32 = 11;
+pub const ELIXIR_BITS: u32 = 4;
+pub const OWNER_BITS: u32 = 3;
+
+// Signed range of a `bits` wide fixed-point value
+fn range(bits: u32) -> (i64, i64) {
+    let half = 1i64 << (bits - 1);
+    (-half, half - 1)
+}
+
+fn to_fixed(value: f32, scale: f32, bits: u32) -> i64 {
+    let (min, max) = range(bits);
+    ((value * scale).round() as i64).clamp(min, max)
+}
+
+// Snaps `value` to the grid the wire format can represent exactly. The
+// simulation runs every float through this, so packing a state loses nothing.
+pub fn quantize(value: f32, scale: f32, bits: u32) -> f32 {
+    to_fixed(value, scale, bits) as f32 / scale
+}
+
+// Non-negative values that only grow, such as distance travelled
+pub fn quantize_unbounded(value: f32, scale: f32) -> f32 {
+    (value * scale).round().max(0.0) / scale
+}
+
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    scratch: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Writes the low `count` bits of `value`, least significant first
+    pub fn write_bits(&mut self, value: u64, count: u32) {
+        debug_assert!(count <= 32);
+        self.scratch |= (value & ((1 << count) - 1)) << self.bits;
+        self.bits += count;
+        while self.bits >= 8 {
+            self.bytes.push(self.scratch as u8);
+            self.scratch >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u64, 1);
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_bits(value & 0xFFFF_FFFF, 32);
+        self.write_bits(value >> 32, 32);
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_bits(value.to_bits() as u64, 32);
+    }
+
+    // LEB128 style: 7 bits per group plus a continuation bit
+    pub fn write_varint(&mut self, mut value: u64) {
+        loop {
+            let group = value & 0x7F;
+            value >>= 7;
+            self.write_bits(group, 7);
+            self.write_bool(value != 0);
+            if value == 0 {
+                break;
+            }
+        }
+    }
+
+    // `bits` bits when the value fits, otherwise an escape bit and a varint
+    pub fn write_bounded(&mut self, value: u64, bits: u32) {
+        let fits = value < 1 << bits;
+        self.write_bool(!fits);
+        if fits {
+            self.write_bits(value, bits);
+        } else {
+            self.write_varint(value);
+        }
+    }
+
+    pub fn write_fixed(&mut self, value: f32, scale: f32, bits: u32) {
+        let (min, _) = range(bits);
+        self.write_bits((to_fixed(value, scale, bits) - min) as u64, bits);
+    }
+
+    pub fn write_unbounded(&mut self, value: f32, scale: f32) {
+        self.write_varint((value * scale).round().max(0.0) as u64);
+    }
+
+    // A presence bit, then the value if there is one
+    pub fn write_option<T>(&mut self, value: &Option<T>, write: impl FnOnce(&mut Self, &T)) {
+        self.write_bool(value.is_some());
+        if let Some(value) = value {
+            write(self, value);
+        }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_varint(bytes.len() as u64);
+        for &byte in bytes {
+            self.write_bits(byte as u64, 8);
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.scratch as u8);
+        }
+        self.bytes
+    }
+}
+
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    pub fn read_bits(&mut self, count: u32) -> Option<u64> {
+        debug_assert!(count <= 32);
+        if self.position + count as usize > self.bytes.len() * 8 {
+            return None;
+        }
+        let mut value = 0u64;
+        for i in 0..count as usize {
+            let bit = self.position + i;
+            let set = (self.bytes[bit / 8] >> (bit % 8)) & 1;
+            value |= (set as u64) << i;
+        }
+        self.position += count as usize;
+        Some(value)
+    }
+
+    pub fn read_bool(&mut self) -> Option<bool> {
+        self.read_bits(1).map(|bit| bit == 1)
+    }
+
+    pub fn read_u64(&mut self) -> Option<u64> {
+        let low = self.read_bits(32)?;
+        let high = self.read_bits(32)?;
+        Some(low | (high << 32))
+    }
+
+    pub fn read_f32(&mut self) -> Option<f32> {
+        self.read_bits(32).map(|bits| f32::from_bits(bits as u32))
+    }
+
+    pub fn read_varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            value |= self.read_bits(7)? << shift;
+            if !self.read_bool()? {
+                return Some(value);
+            }
+        }
+        // More than ten groups, not something we wrote
+        None
+    }
+
+    pub fn read_bounded(&mut self, bits: u32) -> Option<u64> {
+        if self.read_bool()? {
+            self.read_varint()
+        } else {
+            self.read_bits(bits)
+        }
+    }
+
+    pub fn read_fixed(&mut self, scale: f32, bits: u32) -> Option<f32> {
+        let (min, _) = range(bits);
+        let raw = self.read_bits(bits)? as i64 + min;
+        Some(raw as f32 / scale)
+    }
+
+    pub fn read_unbounded(&mut self, scale: f32) -> Option<f32> {
+        self.read_varint().map(|raw| raw as f32 / scale)
+    }
+
+    // Outer None when the input ran out, inner None when the value was absent
+    pub fn read_option<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Option<T>,
+    ) -> Option<Option<T>> {
+        if self.read_bool()? {
+            read(self).map(Some)
+        } else {
+            Some(None)
+        }
+    }
+
+    pub fn read_bytes(&mut self, limit: usize) -> Option<Vec<u8>> {
+        let len = self.read_varint()? as usize;
+        if len > limit {
+            return None;
+        }
+        (0..len)
+            .map(|_| self.read_bits(8).map(|b| b as u8))
+            .collect()
+    }
+}
+
+// Longest card name accepted when unpacking
+const MAX_NAME_LEN: usize = 64;
+// Upper bound on any list length when unpacking, so a hostile length prefix
+// cannot make us allocate gigabytes
+const MAX_LIST_LEN: usize = 4096;
+
+pub trait Pack: Sized {
+    fn pack(&self, writer: &mut BitWriter);
+    fn unpack(reader: &mut BitReader) -> Option<Self>;
+}
+
+impl<T: Pack> Pack for Vec<T> {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.len() as u64);
+        for item in self {
+            item.pack(writer);
+        }
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        let len = reader.read_varint()? as usize;
+        if len > MAX_LIST_LEN {
+            return None;
+        }
+        (0..len).map(|_| T::unpack(reader)).collect()
+    }
+}
+
+impl Pack for Ability {
+    fn pack(&self, writer: &mut BitWriter) {
+        match self.trigger {
+            Trigger::OnDeploy => writer.write_bits(0, 2),
+            Trigger::OnDeath => writer.write_bits(1, 2),
+            Trigger::AfterDistance(distance) => {
+                writer.write_bits(2, 2);
+                writer.write_f32(distance);
+            }
+        }
+        match self.effect {
+            Effect::AreaDamage { radius, damage } => {
+                writer.write_bits(0, 1);
+                writer.write_f32(radius);
+                writer.write_varint(damage as u64);
+            }
+            Effect::SpawnUnits {
+                count,
+                health,
+                velocity,
+            } => {
+                writer.write_bits(1, 1);
+                writer.write_varint(count as u64);
+                writer.write_bounded(health as u64, HEALTH_BITS);
+                writer.write_f32(velocity);
+            }
+        }
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        let trigger = match reader.read_bits(2)? {
+            0 => Trigger::OnDeploy,
+            1 => Trigger::OnDeath,
+            2 => Trigger::AfterDistance(reader.read_f32()?),
+            _ => return None,
+        };
+        let effect = match reader.read_bits(1)? {
+            0 => Effect::AreaDamage {
+                radius: reader.read_f32()?,
+                damage: reader.read_varint()? as u32,
+            },
+            _ => Effect::SpawnUnits {
+                count: reader.read_varint()? as u32,
+                health: reader.read_bounded(HEALTH_BITS)? as u32,
+                velocity: reader.read_f32()?,
+            },
+        };
+        Some(Ability { trigger, effect })
+    }
+}
+
+impl Pack for Unit {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.id as u64);
+        writer.write_bounded(self.owner as u64, OWNER_BITS);
+        writer.write_fixed(self.x, POSITION_SCALE, POSITION_BITS);
+        writer.write_fixed(self.y, POSITION_SCALE, POSITION_BITS);
+        writer.write_bounded(self.health as u64, HEALTH_BITS);
+        writer.write_fixed(self.velocity, VELOCITY_SCALE, VELOCITY_BITS);
+        writer.write_unbounded(self.travelled, POSITION_SCALE);
+        self.abilities.pack(writer);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(Unit {
+            id: reader.read_varint()? as u32,
+            owner: reader.read_bounded(OWNER_BITS)? as u32,
+            x: reader.read_fixed(POSITION_SCALE, POSITION_BITS)?,
+            y: reader.read_fixed(POSITION_SCALE, POSITION_BITS)?,
+            health: reader.read_bounded(HEALTH_BITS)? as u32,
+            velocity: reader.read_fixed(VELOCITY_SCALE, VELOCITY_BITS)?,
+            travelled: reader.read_unbounded(POSITION_SCALE)?,
+            abilities: Vec::unpack(reader)?,
+        })
+    }
+}
+
+impl Pack for Tower {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.id as u64);
+        writer.write_bounded(self.owner as u64, OWNER_BITS);
+        writer.write_varint(self.health as u64);
+        writer.write_fixed(self.x, POSITION_SCALE, POSITION_BITS);
+        writer.write_fixed(self.y, POSITION_SCALE, POSITION_BITS);
+        writer.write_varint(self.damage as u64);
+        writer.write_fixed(self.attack_cooldown, TIMER_SCALE, TIMER_BITS);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(Tower {
+            id: reader.read_varint()? as u32,
+            owner: reader.read_bounded(OWNER_BITS)? as u32,
+            health: reader.read_varint()? as u32,
+            x: reader.read_fixed(POSITION_SCALE, POSITION_BITS)?,
+            y: reader.read_fixed(POSITION_SCALE, POSITION_BITS)?,
+            damage: reader.read_varint()? as u32,
+            attack_cooldown: reader.read_fixed(TIMER_SCALE, TIMER_BITS)?,
+        })
+    }
+}
+
+impl Pack for Player {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.id as u64);
+        writer.write_bounded(self.elixir as u64, ELIXIR_BITS);
+        writer.write_fixed(self.elixir_timer, TIMER_SCALE, TIMER_BITS);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(Player {
+            id: reader.read_varint()? as u32,
+            elixir: reader.read_bounded(ELIXIR_BITS)? as u32,
+            elixir_timer: reader.read_fixed(TIMER_SCALE, TIMER_BITS)?,
+        })
+    }
+}
+
+// Cards are static data, their floats go out untouched
+impl Pack for Card {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.id as u64);
+        writer.write_bytes(self.name.as_bytes());
+        writer.write_varint(self.cost as u64);
+        writer.write_f32(self.pos.x);
+        writer.write_f32(self.pos.y);
+        writer.write_f32(self.width);
+        writer.write_f32(self.height);
+        writer.write_bounded(self.health as u64, HEALTH_BITS);
+        self.abilities.pack(writer);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(Card {
+            id: reader.read_varint()? as u32,
+            name: String::from_utf8(reader.read_bytes(MAX_NAME_LEN)?).ok()?,
+            cost: reader.read_varint()? as u32,
+            pos: Vec2D {
+                x: reader.read_f32()?,
+                y: reader.read_f32()?,
+            },
+            width: reader.read_f32()?,
+            height: reader.read_f32()?,
+            health: reader.read_bounded(HEALTH_BITS)? as u32,
+            abilities: Vec::unpack(reader)?,
+        })
+    }
+}
+
+impl Pack for GameState {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.tick);
+        self.players.pack(writer);
+        self.units.pack(writer);
+        self.towers.pack(writer);
+        self.cards.pack(writer);
+        writer.write_varint(self.next_unit_id as u64);
+        writer.write_bool(self.winner.is_some());
+        if let Some(winner) = self.winner {
+            writer.write_varint(winner as u64);
+        }
+        writer.write_bool(self.ended);
+        writer.write_u64(self.seed);
+        writer.write_u64(self.rng.state());
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        let mut state = GameState {
+            tick: reader.read_varint()?,
+            players: Vec::unpack(reader)?,
+            units: Vec::unpack(reader)?,
+            towers: Vec::unpack(reader)?,
+            cards: Vec::unpack(reader)?,
+            next_unit_id: reader.read_varint()? as u32,
+            ..Default::default()
+        };
+        if reader.read_bool()? {
+            state.winner = Some(reader.read_varint()? as u32);
+        }
+        state.ended = reader.read_bool()?;
+        state.seed = reader.read_u64()?;
+        state.rng = Rng::from_state(reader.read_u64()?);
+        Some(state)
+    }
+}
+
+// Deltas use the same encodings as full states, each field behind a bit that
+// says whether it changed
+impl Pack for UnitDelta {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.id as u64);
+        writer.write_option(&self.owner, |w, &v| w.write_bounded(v as u64, OWNER_BITS));
+        writer.write_option(&self.x, |w, &v| {
+            w.write_fixed(v, POSITION_SCALE, POSITION_BITS)
+        });
+        writer.write_option(&self.y, |w, &v| {
+            w.write_fixed(v, POSITION_SCALE, POSITION_BITS)
+        });
+        writer.write_option(&self.health, |w, &v| w.write_bounded(v as u64, HEALTH_BITS));
+        writer.write_option(&self.velocity, |w, &v| {
+            w.write_fixed(v, VELOCITY_SCALE, VELOCITY_BITS)
+        });
+        writer.write_option(&self.travelled, |w, &v| {
+            w.write_unbounded(v, POSITION_SCALE)
+        });
+        writer.write_option(&self.abilities, |w, v| v.pack(w));
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(UnitDelta {
+            id: reader.read_varint()? as u32,
+            owner: reader.read_option(|r| r.read_bounded(OWNER_BITS).map(|v| v as u32))?,
+            x: reader.read_option(|r| r.read_fixed(POSITION_SCALE, POSITION_BITS))?,
+            y: reader.read_option(|r| r.read_fixed(POSITION_SCALE, POSITION_BITS))?,
+            health: reader.read_option(|r| r.read_bounded(HEALTH_BITS).map(|v| v as u32))?,
+            velocity: reader.read_option(|r| r.read_fixed(VELOCITY_SCALE, VELOCITY_BITS))?,
+            travelled: reader.read_option(|r| r.read_unbounded(POSITION_SCALE))?,
+            abilities: reader.read_option(Vec::unpack)?,
+        })
+    }
+}
+
+impl Pack for TowerDelta {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.id as u64);
+        writer.write_option(&self.owner, |w, &v| w.write_bounded(v as u64, OWNER_BITS));
+        writer.write_option(&self.health, |w, &v| w.write_varint(v as u64));
+        writer.write_option(&self.x, |w, &v| {
+            w.write_fixed(v, POSITION_SCALE, POSITION_BITS)
+        });
+        writer.write_option(&self.y, |w, &v| {
+            w.write_fixed(v, POSITION_SCALE, POSITION_BITS)
+        });
+        writer.write_option(&self.damage, |w, &v| w.write_varint(v as u64));
+        writer.write_option(&self.attack_cooldown, |w, &v| {
+            w.write_fixed(v, TIMER_SCALE, TIMER_BITS)
+        });
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(TowerDelta {
+            id: reader.read_varint()? as u32,
+            owner: reader.read_option(|r| r.read_bounded(OWNER_BITS).map(|v| v as u32))?,
+            health: reader.read_option(|r| r.read_varint().map(|v| v as u32))?,
+            x: reader.read_option(|r| r.read_fixed(POSITION_SCALE, POSITION_BITS))?,
+            y: reader.read_option(|r| r.read_fixed(POSITION_SCALE, POSITION_BITS))?,
+            damage: reader.read_option(|r| r.read_varint().map(|v| v as u32))?,
+            attack_cooldown: reader.read_option(|r| r.read_fixed(TIMER_SCALE, TIMER_BITS))?,
+        })
+    }
+}
+
+impl Pack for PlayerDelta {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.id as u64);
+        writer.write_option(&self.elixir, |w, &v| w.write_bounded(v as u64, ELIXIR_BITS));
+        writer.write_option(&self.elixir_timer, |w, &v| {
+            w.write_fixed(v, TIMER_SCALE, TIMER_BITS)
+        });
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(PlayerDelta {
+            id: reader.read_varint()? as u32,
+            elixir: reader.read_option(|r| r.read_bounded(ELIXIR_BITS).map(|v| v as u32))?,
+            elixir_timer: reader.read_option(|r| r.read_fixed(TIMER_SCALE, TIMER_BITS))?,
+        })
+    }
+}
+
+impl<T: Pack, D: Pack> Pack for ListDelta<T, D> {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.removed.len() as u64);
+        for &id in &self.removed {
+            writer.write_varint(id as u64);
+        }
+        self.changed.pack(writer);
+        self.added.pack(writer);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        let removed = reader.read_varint()? as usize;
+        if removed > MAX_LIST_LEN {
+            return None;
+        }
+        Some(ListDelta {
+            removed: (0..removed)
+                .map(|_| reader.read_varint().map(|id| id as u32))
+                .collect::<Option<_>>()?,
+            changed: Vec::unpack(reader)?,
+            added: Vec::unpack(reader)?,
+        })
+    }
+}
+
+impl Pack for StateDelta {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.baseline);
+        writer.write_varint(self.tick);
+        self.players.pack(writer);
+        self.units.pack(writer);
+        self.towers.pack(writer);
+        writer.write_option(&self.cards, |w, v| v.pack(w));
+        writer.write_option(&self.next_unit_id, |w, &v| w.write_varint(v as u64));
+        writer.write_option(&self.winner, |w, v| {
+            w.write_option(v, |w, &v| w.write_varint(v as u64))
+        });
+        writer.write_option(&self.ended, |w, &v| w.write_bool(v));
+        writer.write_option(&self.seed, |w, &v| w.write_u64(v));
+        writer.write_option(&self.rng, |w, v| w.write_u64(v.state()));
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(StateDelta {
+            baseline: reader.read_varint()?,
+            tick: reader.read_varint()?,
+            players: ListDelta::unpack(reader)?,
+            units: ListDelta::unpack(reader)?,
+            towers: ListDelta::unpack(reader)?,
+            cards: reader.read_option(Vec::unpack)?,
+            next_unit_id: reader.read_option(|r| r.read_varint().map(|v| v as u32))?,
+            winner: reader.read_option(|r| r.read_option(|r| r.read_varint().map(|v| v as u32)))?,
+            ended: reader.read_option(|r| r.read_bool())?,
+            seed: reader.read_option(|r| r.read_u64())?,
+            rng: reader.read_option(|r| r.read_u64().map(Rng::from_state))?,
+        })
+    }
+}
+
+pub fn pack<T: Pack>(value: &T) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    value.pack(&mut writer);
+    writer.finish()
+}
+
+pub fn unpack<T: Pack>(bytes: &[u8]) -> Option<T> {
+    T::unpack(&mut BitReader::new(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cards;
+
+    fn busy_state(units: u32) -> GameState {
+        let mut state = GameState::with_seed(7);
+        state.cards = cards::catalog();
+        state.cards[0].abilities.push(Ability::new(
+            Trigger::AfterDistance(40.0),
+            Effect::AreaDamage {
+                radius: 30.0,
+                damage: 120,
+            },
+        ));
+        state.add_player(2);
+        for (id, owner, y) in [(1, 1, 760.0), (2, 2, 90.0)] {
+            state.towers.push(Tower {
+                id,
+                owner,
+                health: 3000,
+                x: 240.0,
+                y,
+                damage: 80,
+                attack_cooldown: 0.0,
+            });
+        }
+        for i in 0..units {
+            state.deploy_unit(Unit {
+                owner: 1 + i % 2,
+                x: 13.37 * i as f32 % 480.0,
+                y: 7.77 * i as f32 % 854.0,
+                health: 100 + i * 37,
+                velocity: if i % 2 == 0 { 60.0 } else { -60.0 },
+                ..Default::default()
+            });
+        }
+        for _ in 0..10 {
+            state.update(1.0 / 30.0);
+        }
+        state
+    }
+
+    #[test]
+    fn bits_round_trip() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0b101, 3);
+        writer.write_bool(true);
+        writer.write_bits(0xDEAD_BEEF, 32);
+        writer.write_u64(u64::MAX - 1);
+        writer.write_f32(-1.5);
+        for value in [0, 1, 127, 128, 16_383, 16_384, u64::MAX] {
+            writer.write_varint(value);
+        }
+        writer.write_bounded(5, 4);
+        writer.write_bounded(5000, 4);
+        writer.write_fixed(-12.3, 16.0, 16);
+        writer.write_fixed(1.0e9, 16.0, 16);
+        writer.write_bytes(b"Pekka");
+        let bytes = writer.finish();
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read_bits(3), Some(0b101));
+        assert_eq!(reader.read_bool(), Some(true));
+        assert_eq!(reader.read_bits(32), Some(0xDEAD_BEEF));
+        assert_eq!(reader.read_u64(), Some(u64::MAX - 1));
+        assert_eq!(reader.read_f32(), Some(-1.5));
+        for value in [0, 1, 127, 128, 16_383, 16_384, u64::MAX] {
+            assert_eq!(reader.read_varint(), Some(value));
+        }
+        assert_eq!(reader.read_bounded(4), Some(5));
+        assert_eq!(reader.read_bounded(4), Some(5000));
+        assert_eq!(reader.read_fixed(16.0, 16), Some(quantize(-12.3, 16.0, 16)));
+        // Out of range values clamp to the edge of the representable range
+        assert_eq!(reader.read_fixed(16.0, 16), Some(32767.0 / 16.0));
+        assert_eq!(reader.read_bytes(64), Some(b"Pekka".to_vec()));
+    }
+
+    #[test]
+    fn quantized_values_survive_packing_exactly() {
+        for value in [0.0, 0.03, -0.03, 3.333_333, 479.99, -2048.0, 2047.9] {
+            let quantized = quantize(value, POSITION_SCALE, POSITION_BITS);
+            let mut writer = BitWriter::new();
+            writer.write_fixed(quantized, POSITION_SCALE, POSITION_BITS);
+            let bytes = writer.finish();
+            let read = BitReader::new(&bytes).read_fixed(POSITION_SCALE, POSITION_BITS);
+            assert_eq!(read, Some(quantized));
+        }
+    }
+
+    #[test]
+    fn unit_round_trip() {
+        let mut unit = Unit {
+            id: 300,
+            owner: 2,
+            x: 123.456,
+            y: -40.2,
+            health: 2500,
+            velocity: -100.0,
+            travelled: 812.3,
+            abilities: vec![Ability::new(
+                Trigger::OnDeath,
+                Effect::SpawnUnits {
+                    count: 3,
+                    health: 80,
+                    velocity: 50.0,
+                },
+            )],
+        };
+        unit.quantize();
+        assert_eq!(unpack::<Unit>(&pack(&unit)), Some(unit));
+    }
+
+    #[test]
+    fn simulated_state_round_trips_with_matching_checksum() {
+        let state = busy_state(40);
+        let unpacked: GameState = unpack(&pack(&state)).expect("state unpacks");
+        assert_eq!(unpacked.checksum(), state.checksum());
+        assert_eq!(unpacked.units, state.units);
+        assert_eq!(unpacked.rng, state.rng);
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let bytes = pack(&busy_state(5));
+        for len in [0, 1, bytes.len() / 2, bytes.len() - 1] {
+            assert!(unpack::<GameState>(&bytes[..len]).is_none(), "len {len}");
+        }
+    }
+
+    #[test]
+    fn packed_snapshot_is_smaller_than_bincode() {
+        let state = busy_state(100);
+        let packed = pack(&state).len();
+        let plain = bincode::serialized_size(&state).unwrap() as usize;
+        assert!(packed * 2 < plain, "packed {packed} vs bincode {plain}");
+    }
+}
@@ -0,0 +1,80 @@
+use crate::ability::{Ability, Effect, Trigger};
+use crate::{Card, Vec2D};
+
+fn card(id: u32, name: &str, cost: u32, health: u32, abilities: Vec<Ability>) -> Card {
+    Card {
+        id,
+        name: name.to_string(),
+        cost,
+        pos: Vec2D { x: 0.0, y: 0.0 },
+        width: 0.0,
+        height: 0.0,
+        health,
+        abilities,
+    }
+}
+
+// Cards every match is played with. Server and client must build the same list
+// so placements validate identically and checksums agree.
+pub fn catalog() -> Vec<Card> {
+    vec![
+        // Slams the ground every few steps of its charge
+        card(
+            0,
+            "Pekka",
+            7,
+            1000,
+            vec![Ability::new(
+                Trigger::AfterDistance(120.0),
+                Effect::AreaDamage {
+                    radius: 40.0,
+                    damage: 150,
+                },
+            )],
+        ),
+        // Blows up when it dies, taking weakened enemies with it
+        card(
+            1,
+            "Goblin",
+            2,
+            200,
+            vec![Ability::new(
+                Trigger::OnDeath,
+                Effect::AreaDamage {
+                    radius: 50.0,
+                    damage: 120,
+                },
+            )],
+        ),
+        // Comes with two more archers
+        card(
+            2,
+            "Archer",
+            3,
+            300,
+            vec![Ability::new(
+                Trigger::OnDeploy,
+                Effect::SpawnUnits {
+                    count: 2,
+                    health: 120,
+                    velocity: 80.0,
+                },
+            )],
+        ),
+        // Leaves its squire behind to fight on
+        card(
+            3,
+            "Knight",
+            4,
+            800,
+            vec![Ability::new(
+                Trigger::OnDeath,
+                Effect::SpawnUnits {
+                    count: 1,
+                    health: 250,
+                    velocity: 100.0,
+                },
+            )],
+        ),
+    ]
+}
@@ -0,0 +1,56 @@
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::GameState;
+
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+// FNV-1a over the bincode encoding. bincode writes fields in declaration
+// order with fixed-width little-endian numbers, so the hash is stable across
+// platforms and only changes when the simulated state does.
+struct Fnv1a(u64);
+
+impl Write for Fnv1a {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn checksum_of<T: Serialize + ?Sized>(value: &T) -> u64 {
+    let mut hasher = Fnv1a(FNV_OFFSET);
+    // Writing into the hasher cannot fail
+    bincode::serialize_into(&mut hasher, value).expect("value is always serializable");
+    hasher.0
+}
+
+impl GameState {
+    pub fn checksum(&self) -> u64 {
+        checksum_of(self)
+    }
+
+    // Checksum of every field but the units, for comparisons that go unit by
+    // unit. Fields are in declaration order, keep them in step with the struct.
+    pub fn checksum_without_units(&self) -> u64 {
+        checksum_of(&(
+            self.tick,
+            &self.players,
+            &self.towers,
+            &self.cards,
+            self.next_unit_id,
+            self.winner,
+            self.ended,
+            self.seed,
+            &self.rng,
+        ))
+    }
+}
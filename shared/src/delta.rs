@@ -0,0 +1,440 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ability::Ability;
+use crate::bitpack;
+use crate::rng::Rng;
+use crate::{Card, GameState, Player, Tower, Unit};
+
+// Only the fields that differ from the baseline are sent, `None` means unchanged
+fn changed<T: PartialEq + Clone>(base: &T, current: &T) -> Option<T> {
+    (base != current).then(|| current.clone())
+}
+
+fn update<T: Clone>(field: &mut T, value: &Option<T>) {
+    if let Some(value) = value {
+        *field = value.clone();
+    }
+}
+
+// Entities that can be matched by id between a baseline and the current state
+trait Diff: Clone {
+    type Delta;
+
+    fn id(&self) -> u32;
+    fn delta_id(delta: &Self::Delta) -> u32;
+    fn diff(&self, current: &Self) -> Option<Self::Delta>;
+    fn apply(&mut self, delta: &Self::Delta);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnitDelta {
+    pub id: u32,
+    pub owner: Option<u32>,
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+    pub health: Option<u32>,
+    pub velocity: Option<f32>,
+    pub travelled: Option<f32>,
+    pub abilities: Option<Vec<Ability>>,
+}
+
+impl Diff for Unit {
+    type Delta = UnitDelta;
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn delta_id(delta: &UnitDelta) -> u32 {
+        delta.id
+    }
+
+    fn diff(&self, current: &Self) -> Option<UnitDelta> {
+        if self == current {
+            return None;
+        }
+        Some(UnitDelta {
+            id: current.id,
+            owner: changed(&self.owner, &current.owner),
+            x: changed(&self.x, &current.x),
+            y: changed(&self.y, &current.y),
+            health: changed(&self.health, &current.health),
+            velocity: changed(&self.velocity, &current.velocity),
+            travelled: changed(&self.travelled, &current.travelled),
+            abilities: changed(&self.abilities, &current.abilities),
+        })
+    }
+
+    fn apply(&mut self, delta: &UnitDelta) {
+        update(&mut self.owner, &delta.owner);
+        update(&mut self.x, &delta.x);
+        update(&mut self.y, &delta.y);
+        update(&mut self.health, &delta.health);
+        update(&mut self.velocity, &delta.velocity);
+        update(&mut self.travelled, &delta.travelled);
+        update(&mut self.abilities, &delta.abilities);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TowerDelta {
+    pub id: u32,
+    pub owner: Option<u32>,
+    pub health: Option<u32>,
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+    pub damage: Option<u32>,
+    pub attack_cooldown: Option<f32>,
+}
+
+impl Diff for Tower {
+    type Delta = TowerDelta;
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn delta_id(delta: &TowerDelta) -> u32 {
+        delta.id
+    }
+
+    fn diff(&self, current: &Self) -> Option<TowerDelta> {
+        if self == current {
+            return None;
+        }
+        Some(TowerDelta {
+            id: current.id,
+            owner: changed(&self.owner, &current.owner),
+            health: changed(&self.health, &current.health),
+            x: changed(&self.x, &current.x),
+            y: changed(&self.y, &current.y),
+            damage: changed(&self.damage, &current.damage),
+            attack_cooldown: changed(&self.attack_cooldown, &current.attack_cooldown),
+        })
+    }
+
+    fn apply(&mut self, delta: &TowerDelta) {
+        update(&mut self.owner, &delta.owner);
+        update(&mut self.health, &delta.health);
+        update(&mut self.x, &delta.x);
+        update(&mut self.y, &delta.y);
+        update(&mut self.damage, &delta.damage);
+        update(&mut self.attack_cooldown, &delta.attack_cooldown);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerDelta {
+    pub id: u32,
+    pub elixir: Option<u32>,
+    pub elixir_timer: Option<f32>,
+}
+
+impl Diff for Player {
+    type Delta = PlayerDelta;
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn delta_id(delta: &PlayerDelta) -> u32 {
+        delta.id
+    }
+
+    fn diff(&self, current: &Self) -> Option<PlayerDelta> {
+        if self == current {
+            return None;
+        }
+        Some(PlayerDelta {
+            id: current.id,
+            elixir: changed(&self.elixir, &current.elixir),
+            elixir_timer: changed(&self.elixir_timer, &current.elixir_timer),
+        })
+    }
+
+    fn apply(&mut self, delta: &PlayerDelta) {
+        update(&mut self.elixir, &delta.elixir);
+        update(&mut self.elixir_timer, &delta.elixir_timer);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ListDelta<T, D> {
+    pub removed: Vec<u32>,
+    pub changed: Vec<D>,
+    pub added: Vec<T>,
+}
+
+fn diff_list<T: Diff>(base: &[T], current: &[T]) -> ListDelta<T, T::Delta> {
+    let current_ids: HashMap<u32, &T> = current.iter().map(|e| (e.id(), e)).collect();
+    let base_ids: HashMap<u32, &T> = base.iter().map(|e| (e.id(), e)).collect();
+
+    // Applying keeps the baseline order and appends new entities, which is how
+    // the simulation grows its lists. Anything else is resent in full.
+    let survivors = base.iter().filter(|e| current_ids.contains_key(&e.id()));
+    let added_count = current.len() - survivors.clone().count();
+    let in_order = survivors
+        .map(T::id)
+        .chain(current[current.len() - added_count..].iter().map(T::id))
+        .eq(current.iter().map(T::id));
+    if !in_order {
+        return ListDelta {
+            removed: base.iter().map(T::id).collect(),
+            changed: Vec::new(),
+            added: current.to_vec(),
+        };
+    }
+
+    ListDelta {
+        removed: base
+            .iter()
+            .filter(|e| !current_ids.contains_key(&e.id()))
+            .map(T::id)
+            .collect(),
+        changed: current
+            .iter()
+            .filter_map(|e| base_ids.get(&e.id()).and_then(|b| b.diff(e)))
+            .collect(),
+        added: current
+            .iter()
+            .filter(|e| !base_ids.contains_key(&e.id()))
+            .cloned()
+            .collect(),
+    }
+}
+
+fn apply_list<T: Diff>(base: &[T], delta: &ListDelta<T, T::Delta>) -> Vec<T> {
+    let changes: HashMap<u32, &T::Delta> =
+        delta.changed.iter().map(|d| (T::delta_id(d), d)).collect();
+    let mut list: Vec<T> = base
+        .iter()
+        .filter(|e| !delta.removed.contains(&e.id()))
+        .cloned()
+        .collect();
+    for entity in &mut list {
+        if let Some(change) = changes.get(&entity.id()) {
+            entity.apply(change);
+        }
+    }
+    list.extend(delta.added.iter().cloned());
+    list
+}
+
+// Everything that changed between the snapshot a client acknowledged (`baseline`)
+// and the current tick
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StateDelta {
+    pub baseline: u64,
+    pub tick: u64,
+    pub players: ListDelta<Player, PlayerDelta>,
+    pub units: ListDelta<Unit, UnitDelta>,
+    pub towers: ListDelta<Tower, TowerDelta>,
+    pub cards: Option<Vec<Card>>,
+    pub next_unit_id: Option<u32>,
+    pub winner: Option<Option<u32>>,
+    pub ended: Option<bool>,
+    pub seed: Option<u64>,
+    pub rng: Option<Rng>,
+}
+
+pub fn diff(baseline: &GameState, current: &GameState) -> StateDelta {
+    StateDelta {
+        baseline: baseline.tick,
+        tick: current.tick,
+        players: diff_list(&baseline.players, &current.players),
+        units: diff_list(&baseline.units, &current.units),
+        towers: diff_list(&baseline.towers, &current.towers),
+        cards: changed(&baseline.cards, &current.cards),
+        next_unit_id: changed(&baseline.next_unit_id, &current.next_unit_id),
+        winner: changed(&baseline.winner, &current.winner),
+        ended: changed(&baseline.ended, &current.ended),
+        seed: changed(&baseline.seed, &current.seed),
+        rng: changed(&baseline.rng, &current.rng),
+    }
+}
+
+// Rebuilds the current state from the baseline the delta was made against
+pub fn apply(baseline: &GameState, delta: &StateDelta) -> GameState {
+    let mut state = baseline.clone();
+    state.take_events();
+    state.tick = delta.tick;
+    state.players = apply_list(&baseline.players, &delta.players);
+    state.units = apply_list(&baseline.units, &delta.units);
+    state.towers = apply_list(&baseline.towers, &delta.towers);
+    update(&mut state.cards, &delta.cards);
+    update(&mut state.next_unit_id, &delta.next_unit_id);
+    update(&mut state.winner, &delta.winner);
+    update(&mut state.ended, &delta.ended);
+    update(&mut state.seed, &delta.seed);
+    update(&mut state.rng, &delta.rng);
+    state
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SnapshotPayload {
+    // Sent when the client has not acknowledged anything the server still has.
+    // Both are bit-packed with `bitpack`, snapshots are most of what we send.
+    Full(Vec<u8>),
+    // A `StateDelta`
+    Delta(Vec<u8>),
+}
+
+// Authoritative states the client has received, kept so later deltas can be
+// applied against whichever one the server picked as baseline
+pub struct Baselines {
+    states: VecDeque<GameState>,
+    capacity: usize,
+}
+
+impl Baselines {
+    pub fn new(capacity: usize) -> Self {
+        Baselines {
+            states: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+        }
+    }
+
+    // Returns the reconstructed state, or None if the baseline is gone or the
+    // result does not match the server's checksum
+    pub fn decode(&mut self, payload: SnapshotPayload, checksum: u64) -> Option<GameState> {
+        let state = match payload {
+            SnapshotPayload::Full(bytes) => bitpack::unpack(&bytes)?,
+            SnapshotPayload::Delta(bytes) => {
+                let delta: StateDelta = bitpack::unpack(&bytes)?;
+                let baseline = self.get(delta.baseline)?;
+                apply(baseline, &delta)
+            }
+        };
+        if state.checksum() != checksum {
+            return None;
+        }
+
+        if self.states.back().is_none_or(|s| s.tick < state.tick) {
+            if self.states.len() == self.capacity {
+                self.states.pop_front();
+            }
+            self.states.push_back(state.clone());
+        }
+        Some(state)
+    }
+
+    pub fn get(&self, tick: u64) -> Option<&GameState> {
+        self.states.iter().find(|s| s.tick == tick)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick::TICK_DT;
+
+    fn arena() -> GameState {
+        let mut state = GameState::for_match(5);
+        state.add_player(1);
+        state.add_player(2);
+        state.spawn_unit(1, 3, 120.0, 600.0);
+        state.spawn_unit(2, 1, 360.0, 250.0);
+        state.spawn_unit(2, 3, 240.0, 250.0);
+        state.update(TICK_DT);
+        state
+    }
+
+    // Applying the delta to its baseline gives back the current state
+    fn round_trip(baseline: &GameState, current: &GameState) -> StateDelta {
+        let delta = diff(baseline, current);
+        assert_eq!(bitpack::unpack(&bitpack::pack(&delta)), Some(delta.clone()));
+        let applied = apply(baseline, &delta);
+        assert_eq!(applied.tick, current.tick);
+        assert_eq!(applied.players, current.players);
+        assert_eq!(applied.units, current.units);
+        assert_eq!(applied.towers, current.towers);
+        assert_eq!(applied.checksum(), current.checksum());
+        delta
+    }
+
+    #[test]
+    fn unchanged_state_sends_only_the_tick() {
+        let state = arena();
+        let mut current = state.clone();
+        current.tick += 1;
+        let delta = round_trip(&state, &current);
+        assert!(delta.units.changed.is_empty() && delta.units.added.is_empty());
+        assert!(delta.towers.changed.is_empty() && delta.players.changed.is_empty());
+        assert_eq!((delta.rng, delta.next_unit_id), (None, None));
+    }
+
+    #[test]
+    fn units_added_removed_and_changed() {
+        let baseline = arena();
+        let mut current = baseline.clone();
+        let goblin = current.units[1].id;
+        current.units[1].health = 0;
+        current.update(TICK_DT);
+        assert!(current.spawn_unit(1, 1, 300.0, 650.0));
+        current.update(TICK_DT);
+
+        let delta = round_trip(&baseline, &current);
+        assert_eq!(delta.units.removed, vec![goblin]);
+        assert_eq!(delta.units.added.len(), 1);
+        // Only what moves is sent for units that stayed
+        let knight = &delta.units.changed[0];
+        assert!(knight.y.is_some() && knight.x.is_none() && knight.abilities.is_none());
+        assert!(delta.next_unit_id.is_some());
+    }
+
+    #[test]
+    fn towers_and_players() {
+        let baseline = arena();
+        let mut current = baseline.clone();
+        let destroyed = current.towers.remove(0).id;
+        current.towers[0].health -= 100;
+        current.players[1].elixir -= 4;
+
+        let delta = round_trip(&baseline, &current);
+        assert_eq!(delta.towers.removed, vec![destroyed]);
+        assert_eq!(delta.towers.changed.len(), 1);
+        assert_eq!(
+            delta.towers.changed[0].health,
+            Some(current.towers[0].health)
+        );
+        assert_eq!(delta.towers.changed[0].x, None);
+        assert_eq!(delta.players.changed.len(), 1);
+        assert_eq!(delta.players.changed[0].id, 2);
+    }
+
+    #[test]
+    fn reordered_lists_are_resent_in_full() {
+        let baseline = arena();
+        let mut current = baseline.clone();
+        current.units.swap(0, 2);
+        let delta = round_trip(&baseline, &current);
+        assert_eq!(delta.units.removed.len(), 3);
+        assert_eq!(delta.units.added, current.units);
+    }
+
+    #[test]
+    fn baselines_only_decode_against_what_was_received() {
+        let first = arena();
+        let mut second = first.clone();
+        second.update(TICK_DT);
+
+        let mut baselines = Baselines::new(4);
+        let full = SnapshotPayload::Full(bitpack::pack(&first));
+        assert!(baselines.decode(full, first.checksum()).is_some());
+        let delta = || SnapshotPayload::Delta(bitpack::pack(&diff(&first, &second)));
+        // A checksum from another state is refused
+        assert!(baselines.decode(delta(), first.checksum()).is_none());
+        let decoded = baselines.decode(delta(), second.checksum()).unwrap();
+        assert_eq!(decoded.units, second.units);
+
+        // A baseline the client never had cannot be applied
+        let mut third = second.clone();
+        third.update(TICK_DT);
+        let mut unknown = diff(&second, &third);
+        unknown.baseline += 10;
+        let payload = SnapshotPayload::Delta(bitpack::pack(&unknown));
+        assert!(baselines.decode(payload, third.checksum()).is_none());
+    }
+}
@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DamageTarget {
+    Unit(u32),
+    Tower(u32),
+}
+
+// Everything observable that happened during a tick, in the order it happened.
+// Rendering effects, sounds, analytics and replays consume these instead of
+// diffing `GameState` themselves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum GameEvent {
+    UnitSpawned {
+        unit_id: u32,
+        owner: u32,
+        x: f32,
+        y: f32,
+    },
+    DamageDealt {
+        target: DamageTarget,
+        amount: u32,
+    },
+    UnitDied {
+        unit_id: u32,
+        owner: u32,
+        x: f32,
+        y: f32,
+    },
+    TowerDestroyed {
+        tower_id: u32,
+        owner: u32,
+    },
+    ElixirChanged {
+        player_id: u32,
+        elixir: u32,
+    },
+    CardPlayed {
+        player_id: u32,
+        card_id: u32,
+        x: f32,
+        y: f32,
+    },
+    MatchEnded {
+        winner: Option<u32>,
+    },
+}
@@ -1,12 +1,49 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub mod ability;
+pub mod bitpack;
+pub mod cards;
+pub mod checksum;
+pub mod delta;
+pub mod event;
+pub mod net;
+pub mod protocol;
+pub mod replay;
+pub mod rng;
+pub mod tick;
+
+use ability::{Ability, Effect, Trigger};
+use bitpack::{
+    POSITION_BITS, POSITION_SCALE, TIMER_BITS, TIMER_SCALE, VELOCITY_BITS, VELOCITY_SCALE,
+    quantize, quantize_unbounded,
+};
+use event::{DamageTarget, GameEvent};
+use rng::Rng;
+
+pub const STARTING_ELIXIR: u32 = 10;
+pub const MAX_ELIXIR: u32 = 10;
+pub const ELIXIR_REGEN_SECONDS: f32 = 2.8;
+// The arena in world units, odd players defend the bottom edge and even ones the top
+pub const ARENA_WIDTH: f32 = 480.0;
+pub const ARENA_HEIGHT: f32 = 854.0;
+pub const UNIT_SPEED: f32 = 100.0;
+// A unit this close to an enemy tower crashes into it
+pub const TOWER_REACH: f32 = 30.0;
+// x, distance from the owner's edge, health and damage of each player's towers:
+// one on either flank and the king tower behind them
+const TOWER_LAYOUT: [(f32, f32, u32, u32); 3] = [
+    (120.0, 190.0, 1400, 50),
+    (360.0, 190.0, 1400, 50),
+    (240.0, 90.0, 2400, 80),
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub struct Vec2D {
     pub x: f32,
     pub y: f32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Card {
     pub id: u32,
     pub name: String,
@@ -15,6 +52,8 @@ pub struct Card {
     pub width: f32,
     pub height: f32,
     pub health: u32,
+    #[serde(default)]
+    pub abilities: Vec<Ability>,
 }
 
 impl Card {
@@ -42,82 +81,621 @@ impl Card {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct Unit {
     pub id: u32,
+    pub owner: u32,
     pub x: f32,
     pub y: f32,
     pub health: u32,
     pub velocity: f32,
+    // Total distance moved, drives `Trigger::AfterDistance`
+    pub travelled: f32,
+    pub abilities: Vec<Ability>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Tower {
     pub id: u32,
+    pub owner: u32,
+    pub health: u32,
     pub x: f32,
     pub y: f32,
     pub damage: u32,
     pub attack_cooldown: f32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Player {
     pub id: u32,
     pub elixir: u32,
+    // Seconds accumulated towards the next point of elixir
+    pub elixir_timer: f32,
+}
+
+impl Unit {
+    fn quantize(&mut self) {
+        self.x = quantize(self.x, POSITION_SCALE, POSITION_BITS);
+        self.y = quantize(self.y, POSITION_SCALE, POSITION_BITS);
+        self.velocity = quantize(self.velocity, VELOCITY_SCALE, VELOCITY_BITS);
+        self.travelled = quantize_unbounded(self.travelled, POSITION_SCALE);
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+impl Player {
+    pub fn new(id: u32) -> Self {
+        Player {
+            id,
+            elixir: STARTING_ELIXIR,
+            elixir_timer: 0.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct GameState {
+    pub tick: u64,
     pub players: Vec<Player>,
     pub units: Vec<Unit>,
     pub towers: Vec<Tower>,
     pub cards: Vec<Card>,
+    pub next_unit_id: u32,
+    pub winner: Option<u32>,
+    pub ended: bool,
+    pub seed: u64,
+    pub rng: Rng,
+    // Events raised since the last `update`, handed out by the next one
+    #[serde(skip)]
+    events: Vec<GameEvent>,
 }
 
 impl GameState {
     pub fn new() -> Self {
+        Self::with_seed(0)
+    }
+
+    // Every random roll in the simulation comes from `rng`, so two states built
+    // from the same seed and fed the same inputs stay identical
+    pub fn with_seed(seed: u64) -> Self {
         GameState {
-            players: vec![Player { id: 1, elixir: 10 }],
+            tick: 0,
+            players: vec![Player::new(1)],
             units: Vec::new(),
             towers: Vec::new(),
             cards: vec![],
+            next_unit_id: 1,
+            winner: None,
+            ended: false,
+            seed,
+            rng: Rng::new(seed),
+            events: Vec::new(),
         }
     }
 
-    pub fn spawn_unit(&mut self, card_id: u32, x: f32, y: f32) {
-        if let Some(card) = self.cards.iter().find(|c| c.id == card_id) {
-            if self.players[0].elixir >= card.cost {
-                self.players[0].elixir -= card.cost;
-                self.units.push(Unit {
-                    id: self.units.len() as u32 + 1,
-                    x,
-                    y,
-                    health: 1000,
-                    velocity: 100.0,
-                });
+    // A match before anyone joins: every card in the catalog and no players
+    pub fn for_match(seed: u64) -> Self {
+        let mut state = Self::with_seed(seed);
+        state.players.clear();
+        state.cards = cards::catalog();
+        state
+    }
+
+    // Joins `player_id` with a full set of towers on their side of the arena
+    pub fn add_player(&mut self, player_id: u32) {
+        if self.players.iter().any(|p| p.id == player_id) {
+            return;
+        }
+        self.players.push(Player::new(player_id));
+        for (i, (x, depth, health, damage)) in TOWER_LAYOUT.into_iter().enumerate() {
+            let y = if forward(player_id) < 0.0 {
+                ARENA_HEIGHT - depth
+            } else {
+                depth
+            };
+            self.towers.push(Tower {
+                id: player_id * TOWER_LAYOUT.len() as u32 + i as u32,
+                owner: player_id,
+                health,
+                x,
+                y: quantize(y, POSITION_SCALE, POSITION_BITS),
+                damage,
+                attack_cooldown: 0.0,
+            });
+        }
+    }
+
+    // Plays `card_id` for `player_id` if they can afford it, returns whether it was placed
+    pub fn spawn_unit(&mut self, player_id: u32, card_id: u32, x: f32, y: f32) -> bool {
+        let Some(card) = self.cards.iter().find(|c| c.id == card_id) else {
+            return false;
+        };
+        let Some(player) = self.players.iter_mut().find(|p| p.id == player_id) else {
+            return false;
+        };
+        if self.ended || player.elixir < card.cost {
+            return false;
+        }
+
+        player.elixir -= card.cost;
+        let unit = Unit {
+            owner: player.id,
+            x,
+            y,
+            health: card.health,
+            velocity: UNIT_SPEED * forward(player.id),
+            abilities: card.abilities.clone(),
+            ..Default::default()
+        };
+        self.events.push(GameEvent::CardPlayed {
+            player_id,
+            card_id,
+            x,
+            y,
+        });
+        self.events.push(GameEvent::ElixirChanged {
+            player_id,
+            elixir: player.elixir,
+        });
+        self.deploy_unit(unit);
+        true
+    }
+
+    // Adds a unit to the arena with a fresh id and fires its on-deploy abilities
+    pub fn deploy_unit(&mut self, mut unit: Unit) {
+        unit.id = self.next_unit_id;
+        self.next_unit_id += 1;
+        unit.quantize();
+
+        let on_deploy: Vec<Effect> = unit
+            .abilities
+            .iter()
+            .filter(|a| a.trigger == Trigger::OnDeploy)
+            .map(|a| a.effect.clone())
+            .collect();
+        let (owner, x, y) = (unit.owner, unit.x, unit.y);
+
+        self.events.push(GameEvent::UnitSpawned {
+            unit_id: unit.id,
+            owner,
+            x,
+            y,
+        });
+        self.units.push(unit);
+        for effect in on_deploy {
+            self.apply_effect(owner, x, y, &effect);
+        }
+    }
+
+    fn apply_effect(&mut self, owner: u32, x: f32, y: f32, effect: &Effect) {
+        match *effect {
+            Effect::AreaDamage { radius, damage } => {
+                let in_range =
+                    |ox: f32, oy: f32| ((ox - x).powi(2) + (oy - y).powi(2)).sqrt() < radius;
+                for unit in &mut self.units {
+                    if unit.owner != owner && unit.health > 0 && in_range(unit.x, unit.y) {
+                        let amount = damage.min(unit.health);
+                        unit.health -= amount;
+                        self.events.push(GameEvent::DamageDealt {
+                            target: DamageTarget::Unit(unit.id),
+                            amount,
+                        });
+                    }
+                }
+                for tower in &mut self.towers {
+                    if tower.owner != owner && tower.health > 0 && in_range(tower.x, tower.y) {
+                        let amount = damage.min(tower.health);
+                        tower.health -= amount;
+                        self.events.push(GameEvent::DamageDealt {
+                            target: DamageTarget::Tower(tower.id),
+                            amount,
+                        });
+                    }
+                }
             }
+            Effect::SpawnUnits {
+                count,
+                health,
+                velocity,
+            } => {
+                for i in 0..count {
+                    // Spread spawned units side by side around the source, with a
+                    // little jitter so they do not stack perfectly
+                    let offset = (i as f32 - (count - 1) as f32 / 2.0) * 10.0;
+                    let jitter = self.rng.range_f32(-2.0, 2.0);
+                    self.deploy_unit(Unit {
+                        owner,
+                        x: x + offset,
+                        y: y + jitter,
+                        health,
+                        velocity: velocity * forward(owner),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+    }
+
+    // Ends the match in favour of the other player, if there is one yet
+    pub fn forfeit(&mut self, player_id: u32) {
+        if self.ended {
+            return;
         }
+        let Some(winner) = self
+            .players
+            .iter()
+            .map(|p| p.id)
+            .find(|&id| id != player_id)
+        else {
+            return;
+        };
+        self.ended = true;
+        self.winner = Some(winner);
+        self.events.push(GameEvent::MatchEnded {
+            winner: self.winner,
+        });
+    }
+
+    // Events raised since the last `update`, without advancing the simulation
+    pub fn take_events(&mut self) -> Vec<GameEvent> {
+        std::mem::take(&mut self.events)
     }
 
-    pub fn update(&mut self, dt: f32) {
+    // Advances the simulation by one step and returns everything that happened,
+    // including events raised by `spawn_unit`/`deploy_unit` since the last call
+    pub fn update(&mut self, dt: f32) -> Vec<GameEvent> {
+        if self.ended {
+            return std::mem::take(&mut self.events);
+        }
+        self.tick += 1;
+
+        for player in &mut self.players {
+            if player.elixir >= MAX_ELIXIR {
+                player.elixir_timer = 0.0;
+                continue;
+            }
+            player.elixir_timer += dt;
+            if player.elixir_timer >= ELIXIR_REGEN_SECONDS {
+                player.elixir_timer -= ELIXIR_REGEN_SECONDS;
+                player.elixir += 1;
+                self.events.push(GameEvent::ElixirChanged {
+                    player_id: player.id,
+                    elixir: player.elixir,
+                });
+            }
+        }
+
+        let mut charges = Vec::new();
         for unit in &mut self.units {
-            unit.y += unit.velocity * dt;
+            let step = unit.velocity * dt;
+            let before = unit.travelled;
+            unit.y += step;
+            unit.travelled += step.abs();
+
+            for ability in &unit.abilities {
+                if ability.crossed_distance(before, unit.travelled) {
+                    charges.push((unit.owner, unit.x, unit.y, ability.effect.clone()));
+                }
+            }
+        }
+        for (owner, x, y, effect) in charges {
+            self.apply_effect(owner, x, y, &effect);
+        }
+        // Units only walk along y, one past either edge has nothing left to reach
+        self.units.retain(|u| (0.0..=ARENA_HEIGHT).contains(&u.y));
+
+        // Whatever reaches an enemy tower hits it with all the health it has left
+        for unit in &mut self.units {
+            if unit.health == 0 {
+                continue;
+            }
+            if let Some(tower) = self.towers.iter_mut().find(|t| {
+                t.owner != unit.owner
+                    && t.health > 0
+                    && ((unit.x - t.x).powi(2) + (unit.y - t.y).powi(2)).sqrt() < TOWER_REACH
+            }) {
+                let amount = unit.health.min(tower.health);
+                tower.health -= amount;
+                unit.health = 0;
+                self.events.push(GameEvent::DamageDealt {
+                    target: DamageTarget::Tower(tower.id),
+                    amount,
+                });
+            }
         }
 
         for tower in &mut self.towers {
             tower.attack_cooldown -= dt;
-            if tower.attack_cooldown <= 0.0 {
-                if let Some(unit) = self
-                    .units
-                    .iter_mut()
-                    .find(|u| ((u.x - tower.x).powi(2) + (u.y - tower.y).powi(2)).sqrt() < 100.0)
+            if tower.attack_cooldown <= 0.0
+                && let Some(unit) = self.units.iter_mut().find(|u| {
+                    u.owner != tower.owner
+                        && u.health > 0
+                        && ((u.x - tower.x).powi(2) + (u.y - tower.y).powi(2)).sqrt() < 100.0
+                })
+            {
+                let amount = tower.damage.min(unit.health);
+                unit.health -= amount;
+                tower.attack_cooldown = 1.0;
+                self.events.push(GameEvent::DamageDealt {
+                    target: DamageTarget::Unit(unit.id),
+                    amount,
+                });
+            }
+        }
+
+        // Death effects can kill more units, so keep resolving until nothing dies
+        loop {
+            let dead: Vec<Unit> = self.units.extract_if(.., |u| u.health == 0).collect();
+            if dead.is_empty() {
+                break;
+            }
+            for unit in dead {
+                self.events.push(GameEvent::UnitDied {
+                    unit_id: unit.id,
+                    owner: unit.owner,
+                    x: unit.x,
+                    y: unit.y,
+                });
+                for ability in unit
+                    .abilities
+                    .iter()
+                    .filter(|a| a.trigger == Trigger::OnDeath)
                 {
-                    unit.health = unit.health.saturating_sub(tower.damage);
-                    tower.attack_cooldown = 1.0;
+                    self.apply_effect(unit.owner, unit.x, unit.y, &ability.effect);
                 }
             }
         }
 
-        self.units.retain(|u| u.health > 0);
+        let destroyed: Vec<Tower> = self.towers.extract_if(.., |t| t.health == 0).collect();
+        for tower in destroyed {
+            self.events.push(GameEvent::TowerDestroyed {
+                tower_id: tower.id,
+                owner: tower.owner,
+            });
+            // Losing the last tower loses the match
+            if !self.ended && !self.towers.iter().any(|t| t.owner == tower.owner) {
+                self.ended = true;
+                self.winner = self
+                    .players
+                    .iter()
+                    .map(|p| p.id)
+                    .find(|&id| id != tower.owner);
+                self.events.push(GameEvent::MatchEnded {
+                    winner: self.winner,
+                });
+            }
+        }
+
+        self.quantize();
+        std::mem::take(&mut self.events)
+    }
+
+    // Snaps every simulated float to the grid the snapshot format can carry, so
+    // a packed state unpacks bit-identical and checksums keep matching
+    pub fn quantize(&mut self) {
+        for unit in &mut self.units {
+            unit.quantize();
+        }
+        for tower in &mut self.towers {
+            tower.x = quantize(tower.x, POSITION_SCALE, POSITION_BITS);
+            tower.y = quantize(tower.y, POSITION_SCALE, POSITION_BITS);
+            tower.attack_cooldown = quantize(tower.attack_cooldown, TIMER_SCALE, TIMER_BITS);
+        }
+        for player in &mut self.players {
+            player.elixir_timer = quantize(player.elixir_timer, TIMER_SCALE, TIMER_BITS);
+        }
+    }
+}
+
+// Which way `player_id`'s units walk along y, towards the other side
+pub fn forward(player_id: u32) -> f32 {
+    if player_id % 2 == 1 { -1.0 } else { 1.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick::{TICK_DT, TICK_RATE};
+
+    // Two players and no towers, so only the units under test act
+    fn arena() -> GameState {
+        let mut state = GameState::with_seed(1);
+        state.cards = cards::catalog();
+        state.add_player(2);
+        state.towers.clear();
+        state
+    }
+
+    // A unit standing still with a catalog card's abilities
+    fn unit(state: &GameState, card_id: u32, owner: u32, x: f32, y: f32, health: u32) -> Unit {
+        let card = state.cards.iter().find(|c| c.id == card_id).unwrap();
+        Unit {
+            owner,
+            x,
+            y,
+            health,
+            abilities: card.abilities.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn died(events: &[GameEvent]) -> Vec<u32> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                GameEvent::UnitDied { unit_id, .. } => Some(*unit_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn death_damage_chains_through_units() {
+        let mut state = arena();
+        // Each goblin's blast reaches the next enemy along the line
+        state.deploy_unit(unit(&state, 1, 1, 100.0, 400.0, 0));
+        state.deploy_unit(unit(&state, 1, 2, 130.0, 400.0, 100));
+        state.deploy_unit(unit(&state, 1, 1, 160.0, 400.0, 100));
+        state.deploy_unit(unit(&state, 3, 2, 300.0, 400.0, 800));
+        state.take_events();
+
+        let events = state.update(TICK_DT);
+        assert_eq!(died(&events), vec![1, 2, 3]);
+        assert_eq!(state.units.len(), 1);
+        assert_eq!(state.units[0].health, 800);
+        assert!(events.contains(&GameEvent::DamageDealt {
+            target: DamageTarget::Unit(2),
+            amount: 100,
+        }));
+    }
+
+    #[test]
+    fn death_spawns_units_for_the_owner() {
+        let mut state = arena();
+        state.deploy_unit(unit(&state, 3, 2, 200.0, 300.0, 0));
+        state.take_events();
+
+        let events = state.update(TICK_DT);
+        assert_eq!(died(&events), vec![1]);
+        assert_eq!(state.units.len(), 1);
+        let squire = &state.units[0];
+        assert_eq!((squire.id, squire.owner, squire.health), (2, 2, 250));
+        assert!(matches!(
+            events.last(),
+            Some(GameEvent::UnitSpawned {
+                unit_id: 2,
+                owner: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn charge_fires_each_time_the_distance_is_covered() {
+        let mut state = arena();
+        let mut pekka = unit(&state, 0, 1, 100.0, 500.0, 1000);
+        pekka.velocity = -100.0;
+        state.deploy_unit(pekka);
+        // Right where the first slam lands, and one just short of the second
+        state.deploy_unit(unit(&state, 3, 2, 100.0, 380.0, 800));
+        state.deploy_unit(unit(&state, 3, 2, 100.0, 305.0, 800));
+
+        let mut slams = Vec::new();
+        for _ in 0..2 * TICK_RATE as usize {
+            let events = state.update(TICK_DT);
+            let damaged = events
+                .iter()
+                .filter(|e| matches!(e, GameEvent::DamageDealt { .. }))
+                .count();
+            if damaged > 0 {
+                slams.push((state.tick, damaged));
+            }
+        }
+        // 120 units at 100 per second is 1.2 seconds, give or take the rounding
+        // of positions to the snapshot grid
+        assert_eq!(slams.len(), 1);
+        assert!((36..=37).contains(&slams[0].0));
+        assert_eq!(state.units[1].health, 650);
+        assert_eq!(state.units[2].health, 800);
+    }
+
+    #[test]
+    fn deploy_brings_the_extra_units() {
+        let mut state = arena();
+        assert!(state.spawn_unit(1, 2, 240.0, 600.0));
+        let events = state.update(TICK_DT);
+
+        assert_eq!(state.units.len(), 3);
+        assert!(state.units.iter().all(|u| u.owner == 1));
+        assert_eq!(state.units[1].health, 120);
+        let spawned = events
+            .iter()
+            .filter(|e| matches!(e, GameEvent::UnitSpawned { .. }))
+            .count();
+        assert_eq!(spawned, 3);
+        // Only the card itself carries the ability, the extras do not spawn more
+        assert!(state.units[1].abilities.is_empty());
+    }
+
+    #[test]
+    fn units_that_walk_off_the_arena_are_removed() {
+        let mut state = arena();
+        let mut pekka = unit(&state, 0, 1, 100.0, 20.0, 1000);
+        pekka.velocity = -UNIT_SPEED;
+        state.deploy_unit(pekka);
+        state.take_events();
+
+        let mut events = Vec::new();
+        for _ in 0..TICK_RATE {
+            events.extend(state.update(TICK_DT));
+        }
+        assert!(state.units.is_empty());
+        // It left, it did not die, and its charge stopped with it
+        assert!(died(&events).is_empty());
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn knights_take_down_every_tower_and_win() {
+        let mut state = GameState::for_match(3);
+        state.add_player(1);
+        state.add_player(2);
+        assert_eq!(state.towers.len(), 6);
+        // Each side defends its own edge
+        assert!(
+            state
+                .towers
+                .iter()
+                .all(|t| (t.owner == 1) == (t.y > ARENA_HEIGHT / 2.0))
+        );
+
+        let mut events = Vec::new();
+        for _ in 0..180 * TICK_RATE as usize {
+            if state.ended {
+                break;
+            }
+            // Walk a knight at whichever enemy tower is still standing
+            let target = state.towers.iter().find(|t| t.owner == 2).unwrap();
+            let (x, y) = (target.x, target.y + 300.0);
+            state.spawn_unit(1, 3, x, y);
+            events.extend(state.update(TICK_DT));
+        }
+
+        assert_eq!(state.winner, Some(1));
+        assert!(state.towers.iter().all(|t| t.owner == 1));
+        let seen = |f: fn(&GameEvent) -> bool| events.iter().filter(|e| f(e)).count();
+        assert!(seen(|e| matches!(e, GameEvent::CardPlayed { player_id: 1, .. })) > 1);
+        assert!(seen(|e| matches!(e, GameEvent::ElixirChanged { .. })) > 1);
+        assert!(seen(|e| matches!(e, GameEvent::UnitSpawned { .. })) > 1);
+        assert!(seen(|e| matches!(e, GameEvent::UnitDied { .. })) > 1);
+        assert_eq!(
+            seen(|e| matches!(e, GameEvent::TowerDestroyed { owner: 2, .. })),
+            3
+        );
+        assert!(
+            seen(|e| {
+                matches!(
+                    e,
+                    GameEvent::DamageDealt {
+                        target: DamageTarget::Unit(_),
+                        ..
+                    }
+                )
+            }) > 0
+        );
+        assert!(
+            seen(|e| {
+                matches!(
+                    e,
+                    GameEvent::DamageDealt {
+                        target: DamageTarget::Tower(_),
+                        ..
+                    }
+                )
+            }) > 0
+        );
+        assert_eq!(
+            events.last(),
+            Some(&GameEvent::MatchEnded { winner: Some(1) })
+        );
     }
 }
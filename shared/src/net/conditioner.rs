@@ -0,0 +1,223 @@
+use std::net::SocketAddr;
+
+use crate::rng::Rng;
+
+// Shortest time a reordered datagram is held back, so it is overtaken even on
+// a link without latency
+pub const MIN_REORDER_HOLD: f64 = 0.02;
+
+// What a simulated link does to datagrams. Delays are one way, in seconds, the
+// rest are probabilities per datagram.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Conditions {
+    pub latency: f64,
+    // Up to this much extra delay, picked per datagram
+    pub jitter: f64,
+    pub loss: f64,
+    pub duplicate: f64,
+    // Held back so datagrams sent after it arrive first
+    pub reorder: f64,
+}
+
+impl Conditions {
+    pub fn is_ideal(&self) -> bool {
+        *self == Conditions::default()
+    }
+
+    // From `latency=80,jitter=20,loss=5,duplicate=1,reorder=2`, delays in
+    // milliseconds and the rest in percent. Missing keys stay at zero.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut conditions = Conditions::default();
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=')?;
+            let value: f64 = value.trim().parse().ok().filter(|v: &f64| *v >= 0.0)?;
+            match key.trim() {
+                "latency" => conditions.latency = value / 1000.0,
+                "jitter" => conditions.jitter = value / 1000.0,
+                "loss" => conditions.loss = (value / 100.0).min(1.0),
+                "duplicate" => conditions.duplicate = (value / 100.0).min(1.0),
+                "reorder" => conditions.reorder = (value / 100.0).min(1.0),
+                _ => return None,
+            }
+        }
+        Some(conditions)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConditionerStats {
+    pub passed: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+struct Delayed {
+    due: f64,
+    to: SocketAddr,
+    datagram: Vec<u8>,
+}
+
+// One direction of a simulated link. Datagrams go in with `push` and come out
+// of `pop` once their delay is up, minus the ones lost on the way. Like the
+// rest of the networking code it keeps no clock of its own, and its dice are
+// seeded, so the same pushes at the same times always give the same result.
+pub struct LinkConditioner {
+    conditions: Conditions,
+    rng: Rng,
+    // By due time, ties in push order
+    queue: Vec<Delayed>,
+    // Datagrams that are not reordered never arrive before this
+    last_due: f64,
+    stats: ConditionerStats,
+}
+
+impl LinkConditioner {
+    pub fn new(conditions: Conditions, seed: u64) -> Self {
+        LinkConditioner {
+            conditions,
+            rng: Rng::new(seed),
+            queue: Vec::new(),
+            last_due: 0.0,
+            stats: ConditionerStats::default(),
+        }
+    }
+
+    pub fn conditions(&self) -> Conditions {
+        self.conditions
+    }
+
+    // Applies to datagrams pushed from now on, queued ones keep their delay
+    pub fn set_conditions(&mut self, conditions: Conditions) {
+        self.conditions = conditions;
+    }
+
+    pub fn push(&mut self, to: SocketAddr, datagram: Vec<u8>, now: f64) {
+        if self.conditions.is_ideal() {
+            self.insert(now.max(self.last_due), to, datagram);
+            return;
+        }
+        if self.roll() < self.conditions.loss {
+            self.stats.dropped += 1;
+            return;
+        }
+        if self.roll() < self.conditions.duplicate {
+            self.stats.duplicated += 1;
+            self.schedule(to, datagram.clone(), now);
+        }
+        self.schedule(to, datagram, now);
+    }
+
+    // The next datagram whose delay is up
+    pub fn pop(&mut self, now: f64) -> Option<(SocketAddr, Vec<u8>)> {
+        if self.queue.first()?.due > now {
+            return None;
+        }
+        let delayed = self.queue.remove(0);
+        self.stats.passed += 1;
+        Some((delayed.to, delayed.datagram))
+    }
+
+    // When the next datagram comes out, for callers that sleep until then
+    pub fn next_due(&self) -> Option<f64> {
+        self.queue.first().map(|d| d.due)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn stats(&self) -> ConditionerStats {
+        self.stats
+    }
+
+    fn schedule(&mut self, to: SocketAddr, datagram: Vec<u8>, now: f64) {
+        let delay = self.conditions.latency + self.conditions.jitter * self.roll();
+        if self.roll() < self.conditions.reorder {
+            self.stats.reordered += 1;
+            let hold = (self.conditions.latency + self.conditions.jitter).max(MIN_REORDER_HOLD);
+            // Leaves `last_due` alone, so what follows may overtake it
+            let due = now + delay + hold;
+            let index = self.queue.partition_point(|d| d.due <= due);
+            self.queue.insert(index, Delayed { due, to, datagram });
+        } else {
+            // Jitter alone does not reorder, like on a real path
+            self.insert((now + delay).max(self.last_due), to, datagram);
+        }
+    }
+
+    fn insert(&mut self, due: f64, to: SocketAddr, datagram: Vec<u8>) {
+        self.last_due = due;
+        let index = self.queue.partition_point(|d| d.due <= due);
+        self.queue.insert(index, Delayed { due, to, datagram });
+    }
+
+    fn roll(&mut self) -> f64 {
+        self.rng.next_f32() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 1))
+    }
+
+    // Pushes `count` numbered datagrams a millisecond apart and collects what
+    // comes out over the next second, with arrival times
+    fn run(conditions: Conditions, seed: u64, count: u8) -> Vec<(u8, f64)> {
+        let mut link = LinkConditioner::new(conditions, seed);
+        let mut out = Vec::new();
+        for ms in 0..1000 {
+            let now = ms as f64 / 1000.0;
+            if ms < count as u32 {
+                link.push(addr(), vec![ms as u8], now);
+            }
+            while let Some((_, datagram)) = link.pop(now) {
+                out.push((datagram[0], now));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn ideal_link_passes_everything_at_once_in_order() {
+        let out = run(Conditions::default(), 1, 50);
+        let expected: Vec<(u8, f64)> = (0..50).map(|i| (i, i as f64 / 1000.0)).collect();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_run() {
+        let conditions =
+            Conditions::parse("latency=50,jitter=30,loss=10,duplicate=5,reorder=5").unwrap();
+        assert_eq!(conditions.latency, 0.05);
+        let out = run(conditions, 7, 200);
+        assert_eq!(out, run(conditions, 7, 200));
+        assert_ne!(out, run(conditions, 8, 200));
+
+        // Everything is late by at least the latency
+        assert!(
+            out.iter()
+                .all(|(i, t)| t - *i as f64 / 1000.0 >= 0.05 - 1e-9)
+        );
+        let delivered: std::collections::HashSet<u8> = out.iter().map(|(i, _)| *i).collect();
+        assert!(delivered.len() < 200);
+        assert!(out.len() > delivered.len());
+        assert!(out.windows(2).any(|w| w[1].0 < w[0].0));
+    }
+
+    #[test]
+    fn rejects_bad_specs() {
+        assert_eq!(Conditions::parse(""), Some(Conditions::default()));
+        assert_eq!(Conditions::parse("latency=-5"), None);
+        assert_eq!(Conditions::parse("lag=5"), None);
+        assert_eq!(Conditions::parse("loss"), None);
+    }
+}
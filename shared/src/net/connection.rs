@@ -0,0 +1,172 @@
+use super::handshake::{Datagram, DenyReason};
+use super::{Channel, Endpoint, EndpointStats, random_u64};
+
+// Handshake datagrams are repeated this often until answered
+pub const HANDSHAKE_RESEND_INTERVAL: f64 = 0.1;
+// Giving up on a server that never answers the handshake
+pub const CONNECT_TIMEOUT: f64 = 5.0;
+// Silence after which either side considers the other gone
+pub const CONNECTION_TIMEOUT: f64 = 5.0;
+// Disconnects are sent unreliably, a few copies make it very likely one arrives
+pub const DISCONNECT_REDUNDANCY: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    // Nothing arrived for CONNECTION_TIMEOUT, or the handshake never completed
+    TimedOut,
+    Denied(DenyReason),
+    // The other side said goodbye
+    Closed,
+    // We called `disconnect`
+    Local,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Requesting,
+    Responding { cookie: u64 },
+    Connected { session: u64 },
+    Disconnected(DisconnectReason),
+}
+
+// Client side of the connection: runs the challenge handshake, then carries the
+// endpoint's packets for the session and watches for timeouts. Like `Endpoint`
+// it never touches a socket.
+pub struct ClientConnection {
+    state: ConnectionState,
+    salt: u64,
+    endpoint: Endpoint,
+    started: f64,
+    last_handshake: Option<f64>,
+    last_received: f64,
+    disconnecting: bool,
+}
+
+impl ClientConnection {
+    pub fn new(now: f64) -> Self {
+        ClientConnection {
+            state: ConnectionState::Requesting,
+            salt: random_u64(),
+            endpoint: Endpoint::new(),
+            started: now,
+            last_handshake: None,
+            last_received: now,
+            disconnecting: false,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, ConnectionState::Connected { .. })
+    }
+
+    // Queued until the session is established, then sent like any endpoint message
+    pub fn send(&mut self, channel: Channel, payload: Vec<u8>) -> bool {
+        self.endpoint.send(channel, payload)
+    }
+
+    pub fn receive(&mut self, bytes: &[u8], now: f64) {
+        let Some(datagram) = Datagram::read(bytes) else {
+            return;
+        };
+        match (self.state, datagram) {
+            (ConnectionState::Requesting, Datagram::Challenge { salt, cookie })
+                if salt == self.salt =>
+            {
+                self.state = ConnectionState::Responding { cookie };
+                self.last_handshake = None;
+            }
+            (
+                ConnectionState::Requesting | ConnectionState::Responding { .. },
+                Datagram::Accepted { salt, session },
+            ) if salt == self.salt => {
+                self.state = ConnectionState::Connected { session };
+            }
+            (
+                ConnectionState::Requesting | ConnectionState::Responding { .. },
+                Datagram::Denied { salt, reason },
+            ) if salt == self.salt => {
+                self.state = ConnectionState::Disconnected(DisconnectReason::Denied(reason));
+            }
+            (ConnectionState::Connected { session }, Datagram::Payload { session: s, packet })
+                if s == session =>
+            {
+                self.endpoint.receive(&packet, now);
+            }
+            (ConnectionState::Connected { session }, Datagram::Disconnect { session: s })
+                if s == session =>
+            {
+                self.state = ConnectionState::Disconnected(DisconnectReason::Closed);
+            }
+            _ => return,
+        }
+        self.last_received = now;
+    }
+
+    pub fn drain_received(&mut self) -> Vec<(Channel, Vec<u8>)> {
+        self.endpoint.drain_received()
+    }
+
+    // Datagrams to send now. While connected the endpoint's packets go out every
+    // call, which doubles as the keepalive.
+    pub fn packets(&mut self, now: f64) -> Vec<Vec<u8>> {
+        if let ConnectionState::Connected { session } = self.state
+            && self.disconnecting
+        {
+            self.state = ConnectionState::Disconnected(DisconnectReason::Local);
+            let goodbye = Datagram::Disconnect { session }.write();
+            return vec![goodbye; DISCONNECT_REDUNDANCY];
+        }
+
+        let timeout = match self.state {
+            ConnectionState::Connected { .. } => now - self.last_received > CONNECTION_TIMEOUT,
+            ConnectionState::Disconnected(_) => return Vec::new(),
+            _ => now - self.started > CONNECT_TIMEOUT,
+        };
+        if timeout {
+            self.state = ConnectionState::Disconnected(DisconnectReason::TimedOut);
+            return Vec::new();
+        }
+
+        let handshake = match self.state {
+            ConnectionState::Requesting => Datagram::Request { salt: self.salt },
+            ConnectionState::Responding { cookie } => Datagram::Response {
+                salt: self.salt,
+                cookie,
+            },
+            ConnectionState::Connected { session } => {
+                return self
+                    .endpoint
+                    .packets(now)
+                    .into_iter()
+                    .map(|packet| Datagram::Payload { session, packet }.write())
+                    .collect();
+            }
+            ConnectionState::Disconnected(_) => return Vec::new(),
+        };
+        if self
+            .last_handshake
+            .is_some_and(|sent| now - sent < HANDSHAKE_RESEND_INTERVAL)
+        {
+            return Vec::new();
+        }
+        self.last_handshake = Some(now);
+        vec![handshake.write()]
+    }
+
+    // Says goodbye with the next `packets` call, or just stops if not connected
+    pub fn disconnect(&mut self) {
+        if self.is_connected() {
+            self.disconnecting = true;
+        } else {
+            self.state = ConnectionState::Disconnected(DisconnectReason::Local);
+        }
+    }
+
+    pub fn stats(&self) -> EndpointStats {
+        self.endpoint.stats()
+    }
+}
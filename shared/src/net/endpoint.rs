@@ -0,0 +1,665 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::fragment::{self, MAX_MESSAGE_SIZE, Reassembler};
+use super::packet::{self, HEADER_SIZE, MAX_PACKET_SIZE, PacketHeader, WireMessage};
+use super::{Channel, sequence_greater_than};
+
+// Sent packets remembered for acks and RTT, older ones count as lost
+const SENT_WINDOW: usize = 256;
+// Reliable messages are never resent faster than this, whatever the RTT
+const MIN_RESEND_INTERVAL: f64 = 0.05;
+// RTT assumed before the first sample arrives
+const INITIAL_RTT: f64 = 0.2;
+// Smoothing factor for the RTT moving average
+const RTT_SMOOTHING: f64 = 0.125;
+// Out of order reliable messages held at most, and the window used to spot
+// duplicates on the unordered channel. Bounds what a peer can make us buffer.
+const RECEIVE_WINDOW: usize = 1024;
+// Packets produced by one flush at most, anything beyond waits for the next
+const MAX_PACKETS_PER_FLUSH: usize = 32;
+// Priority gained per flush by a channel that could not send, indexed by channel
+// id. Reliable traffic climbs faster but snapshots still get their turn.
+const CHANNEL_WEIGHTS: [f32; 3] = [1.0, 2.0, 1.5];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EndpointStats {
+    // Smoothed round trip time in seconds
+    pub rtt: f64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub packets_acked: u64,
+    // Sent packets that left the ack window without being acknowledged
+    pub packets_lost: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_resent: u64,
+    // Unreliable messages lost because a fragment never arrived
+    pub fragment_groups_expired: u64,
+    // Unreliable messages over a flush budget, dropped for newer ones
+    pub messages_superseded: u64,
+}
+
+struct SentPacket {
+    sequence: u16,
+    sent_at: f64,
+    // Reliable messages carried, see `message_key`
+    messages: Vec<(Channel, u16, u8)>,
+    acked: bool,
+}
+
+struct PendingReliable {
+    message: WireMessage,
+    last_sent: Option<f64>,
+}
+
+// One side of a connection: numbers packets, acknowledges the peer's packets,
+// estimates RTT, and runs the three channels on top. It never touches a socket,
+// callers feed it received datagrams and put the datagrams it produces on the wire.
+pub struct Endpoint {
+    local_sequence: u16,
+    remote_sequence: u16,
+    received_bits: u32,
+    received_any: bool,
+    sent: VecDeque<SentPacket>,
+
+    unreliable: Vec<WireMessage>,
+    // How many at the front of `unreliable` an earlier flush had no room for
+    unreliable_deferred: usize,
+    reliable: Vec<PendingReliable>,
+    next_message_id: [u16; 3],
+    priority: [f32; 3],
+
+    reassembler: Reassembler,
+    ordered_next: u16,
+    ordered_buffer: HashMap<u16, Vec<u8>>,
+    unordered_seen: HashSet<u16>,
+    unordered_order: VecDeque<u16>,
+    received: VecDeque<(Channel, Vec<u8>)>,
+
+    stats: EndpointStats,
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Endpoint {
+    pub fn new() -> Self {
+        Endpoint {
+            local_sequence: 0,
+            remote_sequence: 0,
+            received_bits: 0,
+            received_any: false,
+            sent: VecDeque::with_capacity(SENT_WINDOW),
+            unreliable: Vec::new(),
+            unreliable_deferred: 0,
+            reliable: Vec::new(),
+            next_message_id: [0; 3],
+            priority: CHANNEL_WEIGHTS,
+            reassembler: Reassembler::default(),
+            ordered_next: 0,
+            ordered_buffer: HashMap::new(),
+            unordered_seen: HashSet::new(),
+            unordered_order: VecDeque::new(),
+            received: VecDeque::new(),
+            stats: EndpointStats {
+                rtt: INITIAL_RTT,
+                ..Default::default()
+            },
+        }
+    }
+
+    // Queues a message, split into fragments if it does not fit one packet.
+    // Returns false if it is larger than MAX_MESSAGE_SIZE and was not queued.
+    pub fn send(&mut self, channel: Channel, payload: Vec<u8>) -> bool {
+        if payload.len() > MAX_MESSAGE_SIZE {
+            return false;
+        }
+        let id = &mut self.next_message_id[channel.id() as usize];
+        let message = WireMessage {
+            channel,
+            id: *id,
+            fragment: None,
+            payload,
+        };
+        *id = id.wrapping_add(1);
+
+        for message in fragment::split(message) {
+            if channel.is_reliable() {
+                self.reliable.push(PendingReliable {
+                    message,
+                    last_sent: None,
+                });
+            } else {
+                self.unreliable.push(message);
+            }
+        }
+        true
+    }
+
+    // Processes one datagram from the peer, returns false if it was not a valid
+    // packet, was a duplicate, or carried reliable fragments there is no room for
+    pub fn receive(&mut self, bytes: &[u8], now: f64) -> bool {
+        let Some((header, mut messages)) = packet::read_packet(bytes) else {
+            return false;
+        };
+        // A reliable fragment with no room is resent if the packet is left
+        // unacknowledged, but lost for good once it is acknowledged
+        messages.retain(|m| m.fragment.is_none() || !self.already_delivered(m.channel, m.id));
+        if !self.reassembler.accepts(&messages) {
+            return false;
+        }
+        if !self.record_received(header.sequence) {
+            return false;
+        }
+        self.stats.packets_received += 1;
+        self.stats.bytes_received += bytes.len() as u64;
+
+        self.process_acks(header.ack, header.ack_bits, now);
+        for message in messages {
+            if message.fragment.is_none() {
+                self.deliver(message);
+            } else if !self.already_delivered(message.channel, message.id)
+                && let Some(message) = self.reassembler.insert(message, now)
+            {
+                self.deliver(message);
+            }
+        }
+        true
+    }
+
+    // Messages delivered since the last call, in delivery order
+    pub fn drain_received(&mut self) -> Vec<(Channel, Vec<u8>)> {
+        self.received.drain(..).collect()
+    }
+
+    // Datagrams to put on the wire now. Queued unreliable messages and reliable
+    // messages that are new or due for a resend are coalesced into as few packets
+    // as fit, channels that waited longest first. Whatever is over the per flush
+    // budget stays queued, unreliable messages only until newer ones are. At
+    // least one packet goes out so the peer keeps getting acks.
+    pub fn packets(&mut self, now: f64) -> Vec<Vec<u8>> {
+        self.stats.fragment_groups_expired += self.reassembler.expire(now) as u64;
+        // A snapshot or ack that waited is stale next to a newer one
+        if self.unreliable.len() > self.unreliable_deferred {
+            self.unreliable.drain(..self.unreliable_deferred);
+            self.stats.messages_superseded += self.unreliable_deferred as u64;
+        }
+
+        let resend_after = self.resend_interval();
+        let mut channels = Channel::ALL;
+        channels.sort_by(|a, b| {
+            self.priority[b.id() as usize].total_cmp(&self.priority[a.id() as usize])
+        });
+
+        let mut batches: Vec<Batch> = Vec::new();
+        for channel in channels {
+            let mut deferred = false;
+            if channel.is_reliable() {
+                for pending in self
+                    .reliable
+                    .iter_mut()
+                    .filter(|p| p.message.channel == channel)
+                {
+                    let due = pending
+                        .last_sent
+                        .is_none_or(|sent| now - sent >= resend_after);
+                    if !due {
+                        continue;
+                    }
+                    if !Batch::place(&mut batches, &pending.message) {
+                        deferred = true;
+                        continue;
+                    }
+                    if pending.last_sent.is_some() {
+                        self.stats.messages_resent += 1;
+                    }
+                    pending.last_sent = Some(now);
+                }
+            } else {
+                for message in std::mem::take(&mut self.unreliable) {
+                    if !Batch::place(&mut batches, &message) {
+                        deferred = true;
+                        self.unreliable.push(message);
+                    }
+                }
+                self.unreliable_deferred = self.unreliable.len();
+            }
+
+            // A channel left with messages waiting moves up, one that got
+            // everything out starts over
+            let priority = &mut self.priority[channel.id() as usize];
+            if deferred {
+                *priority += CHANNEL_WEIGHTS[channel.id() as usize];
+            } else {
+                *priority = CHANNEL_WEIGHTS[channel.id() as usize];
+            }
+        }
+
+        if batches.is_empty() {
+            return vec![self.write(&[], now)];
+        }
+        batches
+            .iter()
+            .map(|batch| self.write(&batch.messages, now))
+            .collect()
+    }
+
+    pub fn stats(&self) -> EndpointStats {
+        self.stats
+    }
+
+    pub fn rtt(&self) -> f64 {
+        self.stats.rtt
+    }
+
+    // Reliable messages, or fragments of them, not acknowledged yet
+    pub fn unacked(&self) -> usize {
+        self.reliable.len()
+    }
+
+    fn resend_interval(&self) -> f64 {
+        (self.stats.rtt * 1.25).max(MIN_RESEND_INTERVAL)
+    }
+
+    fn write(&mut self, messages: &[WireMessage], now: f64) -> Vec<u8> {
+        let header = PacketHeader {
+            sequence: self.local_sequence,
+            ack: self.remote_sequence,
+            ack_bits: self.received_bits,
+        };
+        let bytes = packet::write_packet(&header, messages);
+
+        if self.sent.len() == SENT_WINDOW
+            && let Some(old) = self.sent.pop_front()
+            && !old.acked
+        {
+            self.stats.packets_lost += 1;
+        }
+        self.sent.push_back(SentPacket {
+            sequence: self.local_sequence,
+            sent_at: now,
+            messages: messages
+                .iter()
+                .filter(|m| m.channel.is_reliable())
+                .map(message_key)
+                .collect(),
+            acked: false,
+        });
+
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+        self.stats.packets_sent += 1;
+        self.stats.bytes_sent += bytes.len() as u64;
+        bytes
+    }
+
+    // Updates the received window, false if the packet was already seen or is
+    // too old to tell
+    fn record_received(&mut self, sequence: u16) -> bool {
+        if !self.received_any {
+            self.received_any = true;
+            self.remote_sequence = sequence;
+            self.received_bits = 0;
+            return true;
+        }
+
+        if sequence_greater_than(sequence, self.remote_sequence) {
+            let shift = sequence.wrapping_sub(self.remote_sequence) as u32;
+            // The previous newest packet becomes bit `shift - 1`
+            self.received_bits = if shift > 32 {
+                0
+            } else {
+                ((self.received_bits as u64) << shift | 1 << (shift - 1)) as u32
+            };
+            self.remote_sequence = sequence;
+            return true;
+        }
+
+        let behind = self.remote_sequence.wrapping_sub(sequence) as u32;
+        if behind == 0 || behind > 32 {
+            return false;
+        }
+        let bit = 1 << (behind - 1);
+        if self.received_bits & bit != 0 {
+            return false;
+        }
+        self.received_bits |= bit;
+        true
+    }
+
+    fn process_acks(&mut self, ack: u16, ack_bits: u32, now: f64) {
+        let mut acked_messages = Vec::new();
+        for sent in &mut self.sent {
+            if sent.acked {
+                continue;
+            }
+            let behind = ack.wrapping_sub(sent.sequence) as u32;
+            let is_acked = if sent.sequence == ack {
+                true
+            } else {
+                (1..=32).contains(&behind) && ack_bits & (1 << (behind - 1)) != 0
+            };
+            if !is_acked {
+                continue;
+            }
+
+            sent.acked = true;
+            self.stats.packets_acked += 1;
+            let sample = now - sent.sent_at;
+            self.stats.rtt += (sample - self.stats.rtt) * RTT_SMOOTHING;
+            acked_messages.append(&mut sent.messages);
+        }
+
+        if !acked_messages.is_empty() {
+            self.reliable
+                .retain(|pending| !acked_messages.contains(&message_key(&pending.message)));
+        }
+    }
+
+    // Resent fragments of a reliable message that was already put back together
+    // must not start a new group that would never complete
+    fn already_delivered(&self, channel: Channel, id: u16) -> bool {
+        match channel {
+            Channel::Unreliable => false,
+            Channel::ReliableUnordered => self.unordered_seen.contains(&id),
+            Channel::ReliableOrdered => {
+                id.wrapping_sub(self.ordered_next) as usize >= RECEIVE_WINDOW
+                    || self.ordered_buffer.contains_key(&id)
+            }
+        }
+    }
+
+    fn deliver(&mut self, message: WireMessage) {
+        match message.channel {
+            Channel::Unreliable => self.received.push_back((message.channel, message.payload)),
+            Channel::ReliableUnordered => {
+                if !self.unordered_seen.insert(message.id) {
+                    return;
+                }
+                self.unordered_order.push_back(message.id);
+                if self.unordered_order.len() > RECEIVE_WINDOW
+                    && let Some(oldest) = self.unordered_order.pop_front()
+                {
+                    self.unordered_seen.remove(&oldest);
+                }
+                self.received.push_back((message.channel, message.payload));
+            }
+            Channel::ReliableOrdered => {
+                let ahead = message.id.wrapping_sub(self.ordered_next) as usize;
+                // Already delivered, or further ahead than we are willing to buffer
+                if ahead >= RECEIVE_WINDOW {
+                    return;
+                }
+                self.ordered_buffer.insert(message.id, message.payload);
+                while let Some(payload) = self.ordered_buffer.remove(&self.ordered_next) {
+                    self.received.push_back((Channel::ReliableOrdered, payload));
+                    self.ordered_next = self.ordered_next.wrapping_add(1);
+                }
+            }
+        }
+    }
+}
+
+// Messages coalesced into one packet
+struct Batch {
+    size: usize,
+    messages: Vec<WireMessage>,
+}
+
+impl Batch {
+    // First fit: the first packet with room takes the message, a new packet is
+    // opened while under budget. False if the message has to wait.
+    fn place(batches: &mut Vec<Batch>, message: &WireMessage) -> bool {
+        let size = message.wire_size();
+        if let Some(batch) = batches
+            .iter_mut()
+            .find(|batch| batch.size + size <= MAX_PACKET_SIZE)
+        {
+            batch.size += size;
+            batch.messages.push(message.clone());
+            return true;
+        }
+        if batches.len() == MAX_PACKETS_PER_FLUSH {
+            return false;
+        }
+        batches.push(Batch {
+            size: HEADER_SIZE + size,
+            messages: vec![message.clone()],
+        });
+        true
+    }
+}
+
+// Identifies one reliable message, or one fragment of it, for acknowledgement
+fn message_key(message: &WireMessage) -> (Channel, u16, u8) {
+    let index = message.fragment.map_or(0, |f| f.index);
+    (message.channel, message.id, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use super::*;
+    use crate::net::{Conditions, LinkConditioner};
+
+    const ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+    const STEP: f64 = 0.01;
+
+    // Two endpoints with a simulated link each way
+    struct Pair {
+        a: Endpoint,
+        b: Endpoint,
+        to_b: LinkConditioner,
+        to_a: LinkConditioner,
+        now: f64,
+    }
+
+    impl Pair {
+        fn new(conditions: Conditions) -> Self {
+            Pair {
+                a: Endpoint::new(),
+                b: Endpoint::new(),
+                to_b: LinkConditioner::new(conditions, 1),
+                to_a: LinkConditioner::new(conditions, 2),
+                now: 0.0,
+            }
+        }
+
+        // Both sides flush and take in whatever arrived, once per step
+        fn run(&mut self, seconds: f64) {
+            let end = self.now + seconds;
+            while self.now < end {
+                self.now += STEP;
+                let now = self.now;
+                for packet in self.a.packets(now) {
+                    self.to_b.push(ADDR, packet, now);
+                }
+                for packet in self.b.packets(now) {
+                    self.to_a.push(ADDR, packet, now);
+                }
+                while let Some((_, datagram)) = self.to_b.pop(now) {
+                    self.b.receive(&datagram, now);
+                }
+                while let Some((_, datagram)) = self.to_a.pop(now) {
+                    self.a.receive(&datagram, now);
+                }
+            }
+        }
+    }
+
+    fn header(packet: &[u8]) -> PacketHeader {
+        packet::read_packet(packet).unwrap().0
+    }
+
+    #[test]
+    fn acks_cover_packets_behind_the_newest() {
+        let mut a = Endpoint::new();
+        let mut b = Endpoint::new();
+        let sent: Vec<Vec<u8>> = (0..3).flat_map(|_| a.packets(0.0)).collect();
+        // The middle one is lost, the last one arrives twice
+        assert!(b.receive(&sent[0], 0.05));
+        assert!(b.receive(&sent[2], 0.05));
+        assert!(!b.receive(&sent[2], 0.05));
+
+        let reply = b.packets(0.05).remove(0);
+        let header = header(&reply);
+        assert_eq!(header.ack, 2);
+        assert_eq!(header.ack_bits & 0b11, 0b10);
+
+        assert!(a.receive(&reply, 0.1));
+        let stats = a.stats();
+        assert_eq!((stats.packets_sent, stats.packets_acked), (3, 2));
+    }
+
+    #[test]
+    fn rtt_is_smoothed_towards_the_samples() {
+        let mut a = Endpoint::new();
+        let mut b = Endpoint::new();
+        assert_eq!(a.rtt(), INITIAL_RTT);
+
+        // Each sample only moves the estimate an eighth of the way
+        let mut expected = INITIAL_RTT;
+        for i in 0..3 {
+            let sent = i as f64;
+            b.receive(&a.packets(sent).remove(0), sent + 0.05);
+            a.receive(&b.packets(sent + 0.05).remove(0), sent + 0.1);
+            expected += (0.1 - expected) * RTT_SMOOTHING;
+            assert!((a.rtt() - expected).abs() < 1e-9);
+        }
+
+        // Over a real link it settles on the round trip, a step late each way
+        let mut pair = Pair::new(Conditions::parse("latency=50").unwrap());
+        pair.run(3.0);
+        assert!((pair.a.rtt() - 0.12).abs() < 0.015);
+        assert!((pair.b.rtt() - 0.12).abs() < 0.015);
+    }
+
+    #[test]
+    fn lost_reliable_message_is_resent_until_acked() {
+        let mut a = Endpoint::new();
+        let mut b = Endpoint::new();
+        a.send(Channel::ReliableOrdered, b"place".to_vec());
+        // The first copy is lost
+        a.packets(0.0);
+        assert_eq!(a.unacked(), 1);
+
+        // Not before the resend interval, an ack may still be on its way
+        let early = a.packets(0.1).remove(0);
+        assert_eq!(packet::read_packet(&early).unwrap().1.len(), 0);
+
+        let resent = a.packets(0.3).remove(0);
+        assert_eq!(a.stats().messages_resent, 1);
+        b.receive(&resent, 0.35);
+        assert_eq!(
+            b.drain_received(),
+            vec![(Channel::ReliableOrdered, b"place".to_vec())]
+        );
+        a.receive(&b.packets(0.35).remove(0), 0.4);
+        assert_eq!(a.unacked(), 0);
+        assert_eq!(a.packets(1.0).len(), 1);
+        assert_eq!(a.stats().messages_resent, 1);
+    }
+
+    #[test]
+    fn reliable_channels_survive_loss_and_reordering() {
+        let conditions =
+            Conditions::parse("latency=30,jitter=20,loss=20,duplicate=5,reorder=10").unwrap();
+        let mut pair = Pair::new(conditions);
+        for i in 0..200u16 {
+            pair.a
+                .send(Channel::ReliableOrdered, i.to_le_bytes().to_vec());
+            pair.a
+                .send(Channel::ReliableUnordered, i.to_le_bytes().to_vec());
+            pair.run(STEP);
+        }
+        pair.run(5.0);
+
+        let received = pair.b.drain_received();
+        let on = |channel: Channel| -> Vec<u16> {
+            received
+                .iter()
+                .filter(|(c, _)| *c == channel)
+                .map(|(_, p)| u16::from_le_bytes([p[0], p[1]]))
+                .collect()
+        };
+        let expected: Vec<u16> = (0..200).collect();
+        assert_eq!(on(Channel::ReliableOrdered), expected);
+        let mut unordered = on(Channel::ReliableUnordered);
+        assert_ne!(unordered, expected);
+        unordered.sort();
+        assert_eq!(unordered, expected);
+
+        assert_eq!(pair.a.unacked(), 0);
+        assert!(pair.a.stats().messages_resent > 0);
+        assert!(pair.to_b.stats().dropped > 0 && pair.to_b.stats().reordered > 0);
+    }
+
+    // Fills a packet on its own, unfragmented
+    fn full_packet(tag: u8) -> Vec<u8> {
+        vec![tag; MAX_PACKET_SIZE - HEADER_SIZE - packet::MESSAGE_HEADER_SIZE]
+    }
+
+    #[test]
+    fn small_messages_share_a_packet() {
+        let mut a = Endpoint::new();
+        for i in 0..3u8 {
+            a.send(Channel::Unreliable, vec![i; 10]);
+            a.send(Channel::ReliableOrdered, vec![i; 10]);
+        }
+        let packets = a.packets(0.0);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packet::read_packet(&packets[0]).unwrap().1.len(), 6);
+    }
+
+    #[test]
+    fn flush_budget_goes_to_reliable_messages_first() {
+        let mut a = Endpoint::new();
+        let mut b = Endpoint::new();
+        for i in 0..MAX_PACKETS_PER_FLUSH as u8 {
+            a.send(Channel::Unreliable, full_packet(i));
+        }
+        a.send(Channel::ReliableOrdered, full_packet(100));
+
+        let packets = a.packets(0.0);
+        assert_eq!(packets.len(), MAX_PACKETS_PER_FLUSH);
+        for packet in &packets {
+            b.receive(packet, 0.0);
+        }
+        let received = b.drain_received();
+        assert_eq!(received[0], (Channel::ReliableOrdered, full_packet(100)));
+        assert_eq!(received.len(), MAX_PACKETS_PER_FLUSH);
+
+        // What did not fit goes out with the next flush
+        let next = a.packets(0.01);
+        assert_eq!(next.len(), 1);
+        b.receive(&next[0], 0.01);
+        let last = MAX_PACKETS_PER_FLUSH as u8 - 1;
+        assert_eq!(
+            b.drain_received(),
+            vec![(Channel::Unreliable, full_packet(last))]
+        );
+    }
+
+    #[test]
+    fn deferred_unreliable_messages_give_way_to_newer_ones() {
+        let mut a = Endpoint::new();
+        let mut b = Endpoint::new();
+        for i in 0..MAX_PACKETS_PER_FLUSH as u8 + 3 {
+            a.send(Channel::Unreliable, full_packet(i));
+        }
+        assert_eq!(a.packets(0.0).len(), MAX_PACKETS_PER_FLUSH);
+
+        a.send(Channel::Unreliable, b"newer".to_vec());
+        let next = a.packets(0.01);
+        assert_eq!(a.stats().messages_superseded, 3);
+        assert_eq!(next.len(), 1);
+        b.receive(&next[0], 0.01);
+        assert_eq!(
+            b.drain_received(),
+            vec![(Channel::Unreliable, b"newer".to_vec())]
+        );
+    }
+}
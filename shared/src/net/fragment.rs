@@ -0,0 +1,261 @@
+use std::collections::{HashMap, HashSet};
+
+use super::Channel;
+use super::packet::{
+    FRAGMENT_HEADER_SIZE, Fragment, HEADER_SIZE, MAX_PACKET_SIZE, MESSAGE_HEADER_SIZE, WireMessage,
+};
+
+// Largest fragment payload that still fits a packet on its own
+pub const MAX_FRAGMENT_SIZE: usize =
+    MAX_PACKET_SIZE - HEADER_SIZE - MESSAGE_HEADER_SIZE - FRAGMENT_HEADER_SIZE;
+// Messages above this are refused on send and never reassembled on receive, so
+// a peer cannot make us buffer arbitrary amounts of data
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+pub const MAX_FRAGMENTS: usize = MAX_MESSAGE_SIZE.div_ceil(MAX_FRAGMENT_SIZE);
+// Unreliable groups still incomplete after this long lost a fragment for good
+pub const FRAGMENT_TIMEOUT: f64 = 1.0;
+// Messages being reassembled at once. A new unreliable group beyond this is
+// dropped, a new reliable one takes the place of the oldest unreliable group.
+pub const MAX_PENDING_GROUPS: usize = 32;
+
+// Splits a message into fragments if it does not fit a packet, every fragment
+// keeps the message id so the receiver can put them back together
+pub fn split(message: WireMessage) -> Vec<WireMessage> {
+    if message.payload.len() <= MAX_PACKET_SIZE - HEADER_SIZE - MESSAGE_HEADER_SIZE {
+        return vec![message];
+    }
+
+    let chunks = message.payload.chunks(MAX_FRAGMENT_SIZE);
+    let count = chunks.len() as u8;
+    chunks
+        .enumerate()
+        .map(|(index, chunk)| WireMessage {
+            channel: message.channel,
+            id: message.id,
+            fragment: Some(Fragment {
+                index: index as u8,
+                count,
+            }),
+            payload: chunk.to_vec(),
+        })
+        .collect()
+}
+
+struct Group {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    started: f64,
+}
+
+#[derive(Default)]
+pub struct Reassembler {
+    groups: HashMap<(Channel, u16), Group>,
+}
+
+impl Reassembler {
+    // Whether every reliable fragment in a packet can be stored. Those fragments
+    // are acknowledged with the packet, so a packet with one that would be
+    // refused must be dropped unacknowledged for the sender to resend it later.
+    pub fn accepts(&self, messages: &[WireMessage]) -> bool {
+        let mut new_groups = HashSet::new();
+        for message in messages
+            .iter()
+            .filter(|m| m.fragment.is_some() && m.channel.is_reliable())
+        {
+            if !self.fits(message) {
+                return false;
+            }
+            if !self.groups.contains_key(&(message.channel, message.id)) {
+                new_groups.insert(message.id);
+            }
+        }
+        let unreliable = self.groups.keys().filter(|(c, _)| !c.is_reliable()).count();
+        self.groups.len() + new_groups.len() <= MAX_PENDING_GROUPS + unreliable
+    }
+
+    // Stores one fragment, returns the whole message once its last fragment is in.
+    // Malformed fragments and groups over the limits are dropped.
+    pub fn insert(&mut self, message: WireMessage, now: f64) -> Option<WireMessage> {
+        if !self.fits(&message) {
+            return None;
+        }
+        let count = message.fragment?.count as usize;
+        let index = message.fragment?.index as usize;
+
+        let key = (message.channel, message.id);
+        if !self.groups.contains_key(&key) && self.groups.len() >= MAX_PENDING_GROUPS {
+            if !message.channel.is_reliable() {
+                return None;
+            }
+            let oldest = self
+                .groups
+                .iter()
+                .filter(|((channel, _), _)| !channel.is_reliable())
+                .min_by(|a, b| a.1.started.total_cmp(&b.1.started))
+                .map(|(key, _)| *key)?;
+            self.groups.remove(&oldest);
+        }
+        let group = self.groups.entry(key).or_insert_with(|| Group {
+            fragments: vec![None; count],
+            received: 0,
+            started: now,
+        });
+
+        let slot = &mut group.fragments[index];
+        if slot.is_some() {
+            return None;
+        }
+        *slot = Some(message.payload);
+        group.received += 1;
+        if group.received < count {
+            return None;
+        }
+
+        let group = self.groups.remove(&key)?;
+        let payload = group.fragments.into_iter().flatten().flatten().collect();
+        Some(WireMessage {
+            channel: message.channel,
+            id: message.id,
+            fragment: None,
+            payload,
+        })
+    }
+
+    // A well formed fragment that agrees with the rest of its group
+    fn fits(&self, message: &WireMessage) -> bool {
+        let Some(fragment) = message.fragment else {
+            return false;
+        };
+        let count = fragment.count as usize;
+        (2..=MAX_FRAGMENTS).contains(&count)
+            && fragment.index < fragment.count
+            && message.payload.len() <= MAX_FRAGMENT_SIZE
+            && self
+                .groups
+                .get(&(message.channel, message.id))
+                .is_none_or(|group| group.fragments.len() == count)
+    }
+
+    // Drops unreliable groups that waited too long, returns how many. Reliable
+    // groups are kept: every fragment of them that was acknowledged was stored,
+    // and the sender resends the rest until they are, so they always complete.
+    pub fn expire(&mut self, now: f64) -> usize {
+        let before = self.groups.len();
+        self.groups.retain(|(channel, _), group| {
+            channel.is_reliable() || now - group.started < FRAGMENT_TIMEOUT
+        });
+        before - self.groups.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(channel: Channel, len: usize) -> WireMessage {
+        WireMessage {
+            channel,
+            id: 9,
+            fragment: None,
+            payload: (0..len).map(|i| i as u8).collect(),
+        }
+    }
+
+    #[test]
+    fn small_messages_are_not_split() {
+        let fragments = split(message(Channel::Unreliable, 100));
+        assert_eq!(fragments.len(), 1);
+        assert!(fragments[0].fragment.is_none());
+    }
+
+    #[test]
+    fn reassembles_out_of_order_and_ignores_duplicates() {
+        let original = message(Channel::ReliableOrdered, MAX_FRAGMENT_SIZE * 3 + 10);
+        let mut fragments = split(original.clone());
+        assert_eq!(fragments.len(), 4);
+        fragments.reverse();
+
+        let mut reassembler = Reassembler::default();
+        let duplicate = fragments[0].clone();
+        let mut complete = None;
+        for fragment in fragments {
+            assert!(complete.is_none());
+            if reassembler.len() == 1 {
+                assert!(reassembler.insert(duplicate.clone(), 0.0).is_none());
+            }
+            complete = reassembler.insert(fragment, 0.0);
+        }
+        assert_eq!(complete, Some(original));
+        assert!(reassembler.is_empty());
+    }
+
+    #[test]
+    fn incomplete_unreliable_groups_expire() {
+        let mut reassembler = Reassembler::default();
+        for channel in [Channel::Unreliable, Channel::ReliableOrdered] {
+            let first = split(message(channel, MAX_FRAGMENT_SIZE * 2))[0].clone();
+            reassembler.insert(first, 0.0);
+        }
+        assert_eq!(reassembler.expire(FRAGMENT_TIMEOUT / 2.0), 0);
+        assert_eq!(reassembler.expire(FRAGMENT_TIMEOUT), 1);
+        assert_eq!(reassembler.len(), 1);
+    }
+
+    #[test]
+    fn rejects_fragments_outside_the_limits() {
+        let mut reassembler = Reassembler::default();
+        let mut fragment = split(message(Channel::Unreliable, MAX_FRAGMENT_SIZE * 2))[0].clone();
+        fragment.fragment = Some(Fragment {
+            index: 0,
+            count: MAX_FRAGMENTS as u8 + 1,
+        });
+        assert!(reassembler.insert(fragment, 0.0).is_none());
+        assert!(reassembler.is_empty());
+    }
+
+    #[test]
+    fn reliable_groups_over_the_limit_are_refused_before_the_ack() {
+        let fragment = |channel, id| {
+            let mut message = message(channel, MAX_FRAGMENT_SIZE * 2);
+            message.id = id;
+            split(message).remove(0)
+        };
+        let mut reassembler = Reassembler::default();
+        for id in 0..MAX_PENDING_GROUPS as u16 - 1 {
+            assert!(reassembler.accepts(&[fragment(Channel::ReliableOrdered, id)]));
+            reassembler.insert(fragment(Channel::ReliableOrdered, id), 0.0);
+        }
+        reassembler.insert(fragment(Channel::Unreliable, 0), 0.0);
+        assert_eq!(reassembler.len(), MAX_PENDING_GROUPS);
+
+        // Unreliable groups give way to reliable ones, but only one is there
+        let next = MAX_PENDING_GROUPS as u16;
+        let two = [
+            fragment(Channel::ReliableOrdered, next),
+            fragment(Channel::ReliableOrdered, next + 1),
+        ];
+        assert!(!reassembler.accepts(&two));
+        assert!(reassembler.accepts(&two[..1]));
+        reassembler.insert(two[0].clone(), 0.0);
+        assert_eq!(reassembler.len(), MAX_PENDING_GROUPS);
+        assert!(!reassembler.accepts(&two[1..]));
+        assert!(
+            reassembler
+                .insert(fragment(Channel::Unreliable, 1), 0.0)
+                .is_none()
+        );
+
+        // Fragments of groups already in progress still go in
+        assert!(reassembler.accepts(&[
+            split(message(Channel::ReliableOrdered, MAX_FRAGMENT_SIZE * 2))[1].clone()
+        ]));
+    }
+}
@@ -0,0 +1,124 @@
+use super::packet::Reader;
+
+// First bytes of every datagram, anything else is dropped unread
+pub const PROTOCOL_ID: u32 = 0x5444_0002;
+// Datagrams are kept under a conservative path MTU so routers never split them
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+// Protocol id, kind and session id in front of every payload datagram
+pub const PAYLOAD_HEADER_SIZE: usize = 13;
+// Connection requests are padded to this size. The challenge sent back is
+// smaller, so a spoofed source address gets out less than was sent in.
+pub const REQUEST_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenyReason {
+    ServerFull,
+}
+
+// Everything that goes over the wire. Only `Request` is answered for an address
+// the server has not verified, and only with a `Challenge`, so forged sources can
+// neither be flooded through us nor make us allocate a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Datagram {
+    // `salt` is picked by the client per attempt and echoed in every answer, so
+    // stale or forged answers are told apart from ours
+    Request { salt: u64 },
+    // `cookie` proves on the way back that the client received this at its address
+    Challenge { salt: u64, cookie: u64 },
+    Response { salt: u64, cookie: u64 },
+    Accepted { salt: u64, session: u64 },
+    Denied { salt: u64, reason: DenyReason },
+    // An endpoint packet for an established session
+    Payload { session: u64, packet: Vec<u8> },
+    Disconnect { session: u64 },
+}
+
+impl Datagram {
+    fn kind(&self) -> u8 {
+        match self {
+            Datagram::Request { .. } => 0,
+            Datagram::Challenge { .. } => 1,
+            Datagram::Response { .. } => 2,
+            Datagram::Accepted { .. } => 3,
+            Datagram::Denied { .. } => 4,
+            Datagram::Payload { .. } => 5,
+            Datagram::Disconnect { .. } => 6,
+        }
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PAYLOAD_HEADER_SIZE);
+        bytes.extend_from_slice(&PROTOCOL_ID.to_le_bytes());
+        bytes.push(self.kind());
+        match self {
+            Datagram::Request { salt } => {
+                bytes.extend_from_slice(&salt.to_le_bytes());
+                bytes.resize(REQUEST_SIZE, 0);
+            }
+            Datagram::Challenge { salt, cookie } | Datagram::Response { salt, cookie } => {
+                bytes.extend_from_slice(&salt.to_le_bytes());
+                bytes.extend_from_slice(&cookie.to_le_bytes());
+            }
+            Datagram::Accepted { salt, session } => {
+                bytes.extend_from_slice(&salt.to_le_bytes());
+                bytes.extend_from_slice(&session.to_le_bytes());
+            }
+            Datagram::Denied { salt, reason } => {
+                bytes.extend_from_slice(&salt.to_le_bytes());
+                bytes.push(match reason {
+                    DenyReason::ServerFull => 0,
+                });
+            }
+            Datagram::Payload { session, packet } => {
+                bytes.extend_from_slice(&session.to_le_bytes());
+                bytes.extend_from_slice(packet);
+            }
+            Datagram::Disconnect { session } => bytes.extend_from_slice(&session.to_le_bytes()),
+        }
+        bytes
+    }
+
+    pub fn read(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        if u32::from_le_bytes(reader.take()?) != PROTOCOL_ID {
+            return None;
+        }
+        let [kind] = reader.take()?;
+        let r = &mut reader;
+        let datagram = match kind {
+            0 if bytes.len() >= REQUEST_SIZE => Datagram::Request { salt: read_u64(r)? },
+            1 => Datagram::Challenge {
+                salt: read_u64(r)?,
+                cookie: read_u64(r)?,
+            },
+            2 => Datagram::Response {
+                salt: read_u64(r)?,
+                cookie: read_u64(r)?,
+            },
+            3 => Datagram::Accepted {
+                salt: read_u64(r)?,
+                session: read_u64(r)?,
+            },
+            4 => Datagram::Denied {
+                salt: read_u64(r)?,
+                reason: match r.take()? {
+                    [0] => DenyReason::ServerFull,
+                    _ => return None,
+                },
+            },
+            5 => Datagram::Payload {
+                session: read_u64(r)?,
+                packet: r.rest().to_vec(),
+            },
+            6 => Datagram::Disconnect {
+                session: read_u64(r)?,
+            },
+            _ => return None,
+        };
+        Some(datagram)
+    }
+}
+
+fn read_u64(reader: &mut Reader) -> Option<u64> {
+    reader.take().map(u64::from_le_bytes)
+}
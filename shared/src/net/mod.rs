@@ -0,0 +1,59 @@
+use std::hash::{BuildHasher, RandomState};
+
+pub mod conditioner;
+pub mod connection;
+pub mod endpoint;
+pub mod fragment;
+pub mod handshake;
+pub mod packet;
+pub mod transport;
+
+pub use conditioner::{Conditions, LinkConditioner};
+pub use connection::{ClientConnection, ConnectionState, DisconnectReason};
+pub use endpoint::{Endpoint, EndpointStats};
+pub use transport::{Conditioned, MemoryNetwork, MemoryTransport, Transport};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    // Fire and forget, newer data supersedes lost data (snapshots)
+    Unreliable,
+    // Resent until acknowledged and delivered in send order (card plays, match events)
+    ReliableOrdered,
+    // Resent until acknowledged, delivered as soon as it arrives
+    ReliableUnordered,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 3] = [
+        Channel::Unreliable,
+        Channel::ReliableOrdered,
+        Channel::ReliableUnordered,
+    ];
+
+    pub fn id(self) -> u8 {
+        match self {
+            Channel::Unreliable => 0,
+            Channel::ReliableOrdered => 1,
+            Channel::ReliableUnordered => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Channel::ALL.get(id as usize).copied()
+    }
+
+    pub fn is_reliable(self) -> bool {
+        self != Channel::Unreliable
+    }
+}
+
+// Whether sequence `a` is newer than `b`, treating the u16 space as a circle
+pub fn sequence_greater_than(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+// Unpredictable to anyone else but not cryptographically strong, which is enough
+// for handshake salts and session ids
+pub fn random_u64() -> u64 {
+    RandomState::new().hash_one(std::time::SystemTime::now())
+}
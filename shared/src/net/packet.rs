@@ -0,0 +1,129 @@
+use super::Channel;
+use super::handshake::{MAX_DATAGRAM_SIZE, PAYLOAD_HEADER_SIZE};
+
+pub const HEADER_SIZE: usize = 8;
+pub const MESSAGE_HEADER_SIZE: usize = 5;
+// Fragment index and count, only present on fragments
+pub const FRAGMENT_HEADER_SIZE: usize = 2;
+// Largest endpoint packet, what is left of a datagram once wrapped for its session
+pub const MAX_PACKET_SIZE: usize = MAX_DATAGRAM_SIZE - PAYLOAD_HEADER_SIZE;
+
+// Set on the channel byte of a message that is one fragment of a larger one
+const FRAGMENT_FLAG: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub sequence: u16,
+    // Newest packet sequence received from the other side
+    pub ack: u16,
+    // Bit n set means packet `ack - n - 1` was received as well
+    pub ack_bits: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment {
+    pub index: u8,
+    pub count: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WireMessage {
+    pub channel: Channel,
+    // Per-channel message id, shared by every fragment of a message
+    pub id: u16,
+    pub fragment: Option<Fragment>,
+    pub payload: Vec<u8>,
+}
+
+impl WireMessage {
+    pub fn wire_size(&self) -> usize {
+        let fragment = if self.fragment.is_some() {
+            FRAGMENT_HEADER_SIZE
+        } else {
+            0
+        };
+        MESSAGE_HEADER_SIZE + fragment + self.payload.len()
+    }
+}
+
+pub fn write_packet(header: &PacketHeader, messages: &[WireMessage]) -> Vec<u8> {
+    let size = HEADER_SIZE + messages.iter().map(|m| m.wire_size()).sum::<usize>();
+    let mut bytes = Vec::with_capacity(size);
+    bytes.extend_from_slice(&header.sequence.to_le_bytes());
+    bytes.extend_from_slice(&header.ack.to_le_bytes());
+    bytes.extend_from_slice(&header.ack_bits.to_le_bytes());
+    for message in messages {
+        let flag = if message.fragment.is_some() {
+            FRAGMENT_FLAG
+        } else {
+            0
+        };
+        bytes.push(message.channel.id() | flag);
+        bytes.extend_from_slice(&message.id.to_le_bytes());
+        bytes.extend_from_slice(&(message.payload.len() as u16).to_le_bytes());
+        if let Some(fragment) = message.fragment {
+            bytes.extend_from_slice(&[fragment.index, fragment.count]);
+        }
+        bytes.extend_from_slice(&message.payload);
+    }
+    bytes
+}
+
+pub fn read_packet(bytes: &[u8]) -> Option<(PacketHeader, Vec<WireMessage>)> {
+    let mut reader = Reader::new(bytes);
+    let header = PacketHeader {
+        sequence: u16::from_le_bytes(reader.take()?),
+        ack: u16::from_le_bytes(reader.take()?),
+        ack_bits: u32::from_le_bytes(reader.take()?),
+    };
+
+    let mut messages = Vec::new();
+    while reader.position < bytes.len() {
+        let [channel] = reader.take()?;
+        let fragmented = channel & FRAGMENT_FLAG != 0;
+        let channel = Channel::from_id(channel & !FRAGMENT_FLAG)?;
+        let id = u16::from_le_bytes(reader.take()?);
+        let len = u16::from_le_bytes(reader.take()?) as usize;
+        let fragment = if fragmented {
+            let [index, count] = reader.take()?;
+            Some(Fragment { index, count })
+        } else {
+            None
+        };
+        let payload = reader.slice(len)?.to_vec();
+        messages.push(WireMessage {
+            channel,
+            id,
+            fragment,
+            payload,
+        });
+    }
+    Some((header, messages))
+}
+
+pub(super) struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(super) fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 0 }
+    }
+
+    pub(super) fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.position..];
+        self.position = self.bytes.len();
+        rest
+    }
+
+    pub(super) fn slice(&mut self, len: usize) -> Option<&'a [u8]> {
+        let slice = self.bytes.get(self.position..self.position + len)?;
+        self.position += len;
+        Some(slice)
+    }
+
+    pub(super) fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.slice(N)?.try_into().ok()
+    }
+}
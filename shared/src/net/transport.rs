@@ -0,0 +1,150 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::rc::Rc;
+
+use super::conditioner::{ConditionerStats, Conditions, LinkConditioner};
+use super::handshake::MAX_DATAGRAM_SIZE;
+
+// Where datagrams go in and out. A real socket, or something standing in for
+// one in tests. `now` is only for transports that delay datagrams.
+pub trait Transport {
+    fn send(&mut self, to: SocketAddr, datagram: &[u8], now: f64) -> io::Result<()>;
+    // The next datagram that has arrived by `now`, None when there is none
+    fn receive(&mut self, now: f64) -> io::Result<Option<(SocketAddr, Vec<u8>)>>;
+}
+
+// Expects a non-blocking socket
+impl Transport for UdpSocket {
+    fn send(&mut self, to: SocketAddr, datagram: &[u8], _now: f64) -> io::Result<()> {
+        self.send_to(datagram, to).map(|_| ())
+    }
+
+    fn receive(&mut self, _now: f64) -> io::Result<Option<(SocketAddr, Vec<u8>)>> {
+        // Anything longer than we ever send is cut short and fails to parse
+        let mut buf = [0u8; 2 * MAX_DATAGRAM_SIZE];
+        match self.recv_from(&mut buf) {
+            Ok((len, from)) => Ok(Some((from, buf[..len].to_vec()))),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+type Inboxes = HashMap<SocketAddr, VecDeque<(SocketAddr, Vec<u8>)>>;
+
+// A network living in memory, for running clients and a server in one process.
+// Datagrams arrive the moment they are sent, in order, and ones sent to an
+// address nobody bound are lost. Wrap the ends in `Conditioned` for a worse one.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    inboxes: Rc<RefCell<Inboxes>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Panics if the address is taken, like binding a port twice would fail
+    pub fn bind(&self, addr: SocketAddr) -> MemoryTransport {
+        let previous = self.inboxes.borrow_mut().insert(addr, VecDeque::new());
+        assert!(previous.is_none(), "{} is already bound", addr);
+        MemoryTransport {
+            network: self.clone(),
+            addr,
+        }
+    }
+}
+
+// One address on a `MemoryNetwork`. Dropping it unbinds the address.
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    addr: SocketAddr,
+}
+
+impl MemoryTransport {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send(&mut self, to: SocketAddr, datagram: &[u8], _now: f64) -> io::Result<()> {
+        if let Some(inbox) = self.network.inboxes.borrow_mut().get_mut(&to) {
+            inbox.push_back((self.addr, datagram.to_vec()));
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, _now: f64) -> io::Result<Option<(SocketAddr, Vec<u8>)>> {
+        let mut inboxes = self.network.inboxes.borrow_mut();
+        Ok(inboxes.get_mut(&self.addr).and_then(VecDeque::pop_front))
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network.inboxes.borrow_mut().remove(&self.addr);
+    }
+}
+
+// Puts a bad network between a transport and its user, in both directions
+pub struct Conditioned<T> {
+    inner: T,
+    outgoing: LinkConditioner,
+    incoming: LinkConditioner,
+}
+
+impl<T: Transport> Conditioned<T> {
+    // Each direction rolls its own dice from `seed`, so runs are repeatable
+    pub fn new(inner: T, conditions: Conditions, seed: u64) -> Self {
+        Conditioned {
+            inner,
+            outgoing: LinkConditioner::new(conditions, seed),
+            incoming: LinkConditioner::new(conditions, !seed),
+        }
+    }
+
+    pub fn set_conditions(&mut self, conditions: Conditions) {
+        self.outgoing.set_conditions(conditions);
+        self.incoming.set_conditions(conditions);
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    // Sent and received, in that order
+    pub fn stats(&self) -> (ConditionerStats, ConditionerStats) {
+        (self.outgoing.stats(), self.incoming.stats())
+    }
+
+    // Hands outgoing datagrams whose delay is up to the inner transport
+    pub fn release(&mut self, now: f64) -> io::Result<()> {
+        while let Some((to, datagram)) = self.outgoing.pop(now) {
+            self.inner.send(to, &datagram, now)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for Conditioned<T> {
+    fn send(&mut self, to: SocketAddr, datagram: &[u8], now: f64) -> io::Result<()> {
+        self.outgoing.push(to, datagram.to_vec(), now);
+        self.release(now)
+    }
+
+    fn receive(&mut self, now: f64) -> io::Result<Option<(SocketAddr, Vec<u8>)>> {
+        self.release(now)?;
+        while let Some((from, datagram)) = self.inner.receive(now)? {
+            self.incoming.push(from, datagram, now);
+        }
+        Ok(self.incoming.pop(now))
+    }
+}
@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::delta::SnapshotPayload;
+use crate::net::Channel;
+
+pub const SERVER_PORT: u16 = 7878;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ClientMessage {
+    // Queues for a 1v1. `rating` is self-reported until there are accounts, None
+    // plays anyone.
+    FindMatch {
+        rating: Option<u32>,
+    },
+    // Opens a private room, answered with its code
+    CreateRoom,
+    JoinRoom {
+        code: String,
+    },
+    // Leaves the queue or closes the room
+    LeaveLobby,
+    // Takes back the slot of a player whose connection dropped, with the token
+    // from its `Welcome`
+    Rejoin {
+        token: u64,
+    },
+    // `tick` is the tick the client predicted the card on, the server applies it
+    // right before simulating that tick. `sequence` increases by one per placement
+    // and is echoed back as `ack` once it and every earlier one were applied or
+    // rejected.
+    PlaceCard {
+        sequence: u32,
+        tick: u64,
+        card_id: u32,
+        x: f32,
+        y: f32,
+    },
+    // The snapshot for `tick` was decoded, so it can serve as a delta baseline
+    AckSnapshot {
+        tick: u64,
+    },
+    // Clock sync request, `client_time` comes back untouched in the pong
+    Ping {
+        client_time: f64,
+    },
+}
+
+// Units a snapshot does not bring up to date. `stale` ones were left over the
+// unit budget and carry their values from an earlier snapshot, `fogged` ones
+// the client was shown before are now hidden and missing.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Outdated {
+    pub stale: Vec<u32>,
+    pub fogged: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ServerMessage {
+    // Waiting in the queue for an opponent
+    Queued,
+    // Share `code` with the other player, the match starts once they join
+    RoomCreated {
+        code: String,
+    },
+    // No open room has that code, or every room is taken when creating one
+    RoomUnavailable,
+    // A match started or was rejoined. `token` lets the client take its slot back
+    // after a dropped connection. `hand` is the card ids the player can play.
+    Welcome {
+        player_id: u32,
+        token: u64,
+        hand: Vec<u32>,
+    },
+    // The token is unknown or its grace period ran out
+    RejoinFailed,
+    // Authoritative state after `tick`, in full or as a delta against a snapshot
+    // the client acknowledged. `checksum` is of this client's view, with hidden
+    // fields and units left out, so it verifies the reconstruction and catches
+    // desyncs only in what the client is shown. Replays check the full state.
+    // `ack` is the highest placement sequence from this client with it and every
+    // earlier one applied or rejected. `outdated` lists the units a prediction
+    // cannot be compared against.
+    Snapshot {
+        tick: u64,
+        checksum: u64,
+        ack: u32,
+        payload: SnapshotPayload,
+        outdated: Outdated,
+    },
+    // Answer to a ping, sent as soon as it arrives. `server_time` is seconds since
+    // the server started and `server_tick` the tick it was simulating then.
+    Pong {
+        client_time: f64,
+        server_time: f64,
+        server_tick: u64,
+    },
+    // How this client's placements have been arriving, sent every second
+    InputHealth {
+        health: BufferHealth,
+    },
+    // The placement with `sequence` was not applied: the player could not afford
+    // it, or a rewind for an earlier placement left it unaffordable. Sent even if
+    // it was acked before the rewind, the snapshots already show it gone.
+    PlacementRejected {
+        sequence: u32,
+    },
+}
+
+// State of the server's input buffer for one client
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct BufferHealth {
+    // Smoothed ticks between arrival and the targeted tick, negative when late
+    pub average_lead: f32,
+    // Placements waiting for their tick right now
+    pub buffered: u32,
+    pub late: u32,
+    pub dropped: u32,
+    pub duplicates: u32,
+}
+
+impl ClientMessage {
+    pub fn channel(&self) -> Channel {
+        match self {
+            ClientMessage::FindMatch { .. }
+            | ClientMessage::CreateRoom
+            | ClientMessage::JoinRoom { .. }
+            | ClientMessage::LeaveLobby
+            | ClientMessage::Rejoin { .. }
+            | ClientMessage::PlaceCard { .. } => Channel::ReliableOrdered,
+            // A lost ack only delays the next delta baseline
+            ClientMessage::AckSnapshot { .. } => Channel::Unreliable,
+            // A resent ping would measure the resend, not the network
+            ClientMessage::Ping { .. } => Channel::Unreliable,
+        }
+    }
+}
+
+impl ServerMessage {
+    pub fn channel(&self) -> Channel {
+        match self {
+            ServerMessage::Queued
+            | ServerMessage::RoomCreated { .. }
+            | ServerMessage::RoomUnavailable
+            | ServerMessage::Welcome { .. }
+            | ServerMessage::RejoinFailed
+            | ServerMessage::PlacementRejected { .. } => Channel::ReliableOrdered,
+            // Superseded by the next snapshot a tick later, never worth resending
+            ServerMessage::Snapshot { .. }
+            | ServerMessage::Pong { .. }
+            | ServerMessage::InputHealth { .. } => Channel::Unreliable,
+        }
+    }
+}
+
+pub fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    bincode::serialize(message).expect("protocol messages are always serializable")
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Option<T> {
+    bincode::deserialize(bytes).ok()
+}
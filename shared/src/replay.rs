@@ -0,0 +1,269 @@
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use crate::GameState;
+use crate::net::handshake::PROTOCOL_ID;
+use crate::tick::{TICK_DT, TICK_RATE};
+
+// Starts every replay file, so anything else is turned away before decoding
+pub const REPLAY_MAGIC: [u8; 4] = *b"TDRP";
+// Bumped whenever the file layout changes, older files are refused
+pub const REPLAY_VERSION: u32 = 1;
+// Ticks between recorded checksums
+pub const CHECKSUM_INTERVAL_TICKS: u64 = TICK_RATE as u64;
+// Larger files are refused rather than decoded into memory
+pub const MAX_REPLAY_BYTES: u64 = 64 * 1024 * 1024;
+
+// Something a player did to the simulation. Applied to the state of the tick it
+// is recorded on, before that tick is simulated.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    PlaceCard {
+        player_id: u32,
+        card_id: u32,
+        x: f32,
+        y: f32,
+    },
+    Forfeit {
+        player_id: u32,
+    },
+}
+
+impl Action {
+    // Returns whether it changed anything, a card the player cannot afford or a
+    // forfeit after the end does not
+    pub fn apply(&self, state: &mut GameState) -> bool {
+        match *self {
+            Action::PlaceCard {
+                player_id,
+                card_id,
+                x,
+                y,
+            } => state.spawn_unit(player_id, card_id, x, y),
+            Action::Forfeit { player_id } => {
+                let ended = state.ended;
+                state.forfeit(player_id);
+                state.ended && !ended
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplayPlayer {
+    pub player_id: u32,
+    pub deck: Vec<u32>,
+}
+
+// What the match was started from. The versions tell a file from an older
+// build apart, the simulation is only trusted as far as the checksums agree.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplayHeader {
+    pub version: u32,
+    pub protocol: u32,
+    pub game_version: String,
+    pub seed: u64,
+    // In join order, all of them in the match from tick 0
+    pub players: Vec<ReplayPlayer>,
+}
+
+// A whole match: everything needed to simulate it again, and checksums of the
+// recorded run to compare with along the way
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    // By tick, in the order they were applied
+    pub actions: Vec<(u64, Action)>,
+    // The state of a tick before its actions, every CHECKSUM_INTERVAL_TICKS
+    pub checksums: Vec<(u64, u64)>,
+    // Last tick of the match and its state after all actions
+    pub end_tick: u64,
+    pub end_checksum: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayError {
+    // Not a replay, or cut short
+    Malformed,
+    // Written by a build with another file layout
+    Version(u32),
+    // The simulation came out differently on this tick
+    Desync { tick: u64 },
+}
+
+impl Replay {
+    pub fn new(seed: u64) -> Self {
+        Replay {
+            header: ReplayHeader {
+                version: REPLAY_VERSION,
+                protocol: PROTOCOL_ID,
+                game_version: env!("CARGO_PKG_VERSION").to_string(),
+                seed,
+                players: Vec::new(),
+            },
+            actions: Vec::new(),
+            checksums: Vec::new(),
+            end_tick: 0,
+            end_checksum: 0,
+        }
+    }
+
+    // Variable-length integers, so ticks and ids mostly take a byte or two
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = REPLAY_MAGIC.to_vec();
+        bytes.extend_from_slice(&REPLAY_VERSION.to_le_bytes());
+        // Writing into a Vec cannot fail
+        options()
+            .serialize_into(&mut bytes, self)
+            .expect("Replay is always serializable");
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ReplayError> {
+        let body = bytes
+            .strip_prefix(&REPLAY_MAGIC)
+            .ok_or(ReplayError::Malformed)?;
+        let (version, body) = body.split_first_chunk().ok_or(ReplayError::Malformed)?;
+        let version = u32::from_le_bytes(*version);
+        if version != REPLAY_VERSION {
+            return Err(ReplayError::Version(version));
+        }
+        options()
+            .deserialize(body)
+            .map_err(|_| ReplayError::Malformed)
+    }
+
+    // The state the match started from, with every player joined
+    pub fn initial_state(&self) -> GameState {
+        let mut state = GameState::for_match(self.header.seed);
+        for player in &self.header.players {
+            state.add_player(player.player_id);
+        }
+        state
+    }
+
+    // Simulates the match again from the header and actions, checking each
+    // recorded checksum on the way. Returns the final state.
+    pub fn play(&self) -> Result<GameState, ReplayError> {
+        let mut state = self.initial_state();
+        let mut actions = self.actions.iter().peekable();
+        let mut checksums = self.checksums.iter().peekable();
+        loop {
+            let tick = state.tick;
+            while let Some((_, checksum)) = checksums.next_if(|(t, _)| *t <= tick) {
+                if *checksum != state.checksum() {
+                    return Err(ReplayError::Desync { tick });
+                }
+            }
+            while let Some((_, action)) = actions.next_if(|(t, _)| *t <= tick) {
+                action.apply(&mut state);
+            }
+            if tick >= self.end_tick {
+                break;
+            }
+            state.update(TICK_DT);
+            // Ended early, the recorded match went on
+            if state.tick == tick {
+                return Err(ReplayError::Desync { tick });
+            }
+        }
+
+        if state.tick != self.end_tick || state.checksum() != self.end_checksum {
+            return Err(ReplayError::Desync { tick: state.tick });
+        }
+        Ok(state)
+    }
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(MAX_REPLAY_BYTES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A short match played straight on a state, recorded the way the server does
+    fn record() -> Replay {
+        let mut replay = Replay::new(42);
+        for player_id in [1, 2] {
+            replay.header.players.push(ReplayPlayer {
+                player_id,
+                deck: vec![1, 2, 3, 4],
+            });
+        }
+        let mut state = replay.initial_state();
+        let mut actions = vec![
+            (
+                5,
+                Action::PlaceCard {
+                    player_id: 1,
+                    card_id: 1,
+                    x: 240.0,
+                    y: 600.0,
+                },
+            ),
+            (
+                40,
+                Action::PlaceCard {
+                    player_id: 2,
+                    card_id: 2,
+                    x: 120.0,
+                    y: 250.0,
+                },
+            ),
+            (90, Action::Forfeit { player_id: 2 }),
+        ];
+        actions.reverse();
+        while !state.ended {
+            if state.tick.is_multiple_of(CHECKSUM_INTERVAL_TICKS) {
+                replay.checksums.push((state.tick, state.checksum()));
+            }
+            while let Some((tick, action)) = actions.pop_if(|(t, _)| *t == state.tick) {
+                assert!(action.apply(&mut state));
+                replay.actions.push((tick, action));
+            }
+            if !state.ended {
+                state.update(TICK_DT);
+            }
+        }
+        replay.end_tick = state.tick;
+        replay.end_checksum = state.checksum();
+        replay
+    }
+
+    #[test]
+    fn plays_back_what_was_recorded() {
+        let replay = record();
+        let decoded = Replay::decode(&replay.encode()).unwrap();
+        assert_eq!(decoded, replay);
+
+        let state = decoded.play().unwrap();
+        assert_eq!(state.tick, 90);
+        assert_eq!(state.winner, Some(1));
+        // The goblin, and the archer with the two it brings
+        assert_eq!(state.units.len(), 4);
+    }
+
+    #[test]
+    fn tampering_is_caught() {
+        let mut replay = record();
+        replay.actions[1].1 = Action::PlaceCard {
+            player_id: 2,
+            card_id: 2,
+            x: 121.0,
+            y: 250.0,
+        };
+        assert_eq!(replay.play().err(), Some(ReplayError::Desync { tick: 60 }));
+
+        let bytes = record().encode();
+        assert_eq!(Replay::decode(&bytes[1..]), Err(ReplayError::Malformed));
+        assert_eq!(
+            Replay::decode(&bytes[..bytes.len() - 1]),
+            Err(ReplayError::Malformed)
+        );
+        let mut newer = bytes.clone();
+        newer[4] += 1;
+        assert_eq!(Replay::decode(&newer), Err(ReplayError::Version(2)));
+    }
+}
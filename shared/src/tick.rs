@@ -0,0 +1,36 @@
+// Simulation rate shared by the server and predicting clients
+pub const TICK_RATE: u32 = 30;
+pub const TICK_DT: f32 = 1.0 / TICK_RATE as f32;
+
+// Never run more than this many steps in one frame, so a long stall (window
+// dragged, debugger break) does not turn into a spiral of catch-up work
+pub const MAX_STEPS_PER_FRAME: u32 = 8;
+
+// Turns variable frame times into a whole number of fixed simulation steps
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixedTimestep {
+    accumulator: f32,
+}
+
+impl FixedTimestep {
+    pub fn new() -> Self {
+        FixedTimestep { accumulator: 0.0 }
+    }
+
+    // Number of ticks to simulate for a frame that took `frame_dt` seconds
+    pub fn advance(&mut self, frame_dt: f32) -> u32 {
+        self.accumulator += frame_dt;
+        let steps = (self.accumulator / TICK_DT) as u32;
+        self.accumulator -= steps as f32 * TICK_DT;
+        if steps > MAX_STEPS_PER_FRAME {
+            self.accumulator = 0.0;
+            return MAX_STEPS_PER_FRAME;
+        }
+        steps
+    }
+
+    // How far between the last tick and the next one the frame is, for rendering
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / TICK_DT).clamp(0.0, 1.0)
+    }
+}